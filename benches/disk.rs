@@ -0,0 +1,174 @@
+//! How fast the storage backends take a download's worth of data, written block by block the
+//! way peers deliver it, both straight to the storage and through `DiskIo`, and how fast
+//! `DiskIo` hands it back.
+//!
+//! Run with `cargo bench --bench disk`.
+
+use std::{path::Path, sync::Arc};
+
+use bittorrent_rust::{
+    disk::DiskIo,
+    storage::{FileStorage, MmapStorage, Preallocation, Storage, StorageKind},
+    Info, TorrentBuilder,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const SIZE: usize = 32 << 20;
+const PLENGTH: usize = 1 << 18;
+const BLOCK: usize = 1 << 14;
+/// Connections filling different pieces at once.
+const CONNECTIONS: usize = 8;
+/// Fewer pieces than `DiskIo` keeps cached, read over and over as when seeding a popular
+/// piece range.
+const HOT_PIECES: usize = 32;
+
+struct Fixture {
+    _source: TempDir,
+    data: Arc<Vec<u8>>,
+    info: Info,
+}
+
+fn fixture() -> Fixture {
+    let source = tempfile::tempdir().unwrap();
+    let path = source.path().join("bench.bin");
+    let data: Vec<u8> = (0..SIZE).map(|_| rand::random::<u8>()).collect();
+    std::fs::write(&path, &data).unwrap();
+    let info = TorrentBuilder::new(&path)
+        .piece_length(PLENGTH)
+        .build()
+        .unwrap()
+        .info;
+    Fixture {
+        _source: source,
+        data: Arc::new(data),
+        info,
+    }
+}
+
+fn open(kind: StorageKind, info: &Info, dir: &Path) -> Arc<dyn Storage> {
+    match kind {
+        StorageKind::File => {
+            let storage = FileStorage::new(info, dir, Vec::new()).unwrap();
+            storage.create_files(Preallocation::Full).unwrap();
+            Arc::new(storage)
+        }
+        StorageKind::Mmap => {
+            Arc::new(MmapStorage::new(info, dir, &[], Preallocation::Full).unwrap())
+        }
+    }
+}
+
+fn write_direct(storage: &dyn Storage, info: &Info, data: &[u8]) {
+    for (piece_index, piece) in data.chunks(PLENGTH).enumerate() {
+        for (block, bytes) in piece.chunks(BLOCK).enumerate() {
+            storage
+                .write_block(piece_index, block * BLOCK, bytes)
+                .unwrap();
+        }
+        assert!(info.verify_piece(piece_index, piece));
+    }
+    storage.flush().unwrap();
+}
+
+async fn write_pooled(disk: &DiskIo, data: &Arc<Vec<u8>>) {
+    let npieces = data.len().div_ceil(PLENGTH);
+    let tasks: Vec<_> = (0..CONNECTIONS)
+        .map(|connection| {
+            let disk = disk.clone();
+            let data = data.clone();
+            tokio::spawn(async move {
+                for piece_index in (connection..npieces).step_by(CONNECTIONS) {
+                    let start = piece_index * PLENGTH;
+                    let piece = &data[start..(start + PLENGTH).min(data.len())];
+                    for (block, bytes) in piece.chunks(BLOCK).enumerate() {
+                        disk.write_block(piece_index, block * BLOCK, bytes)
+                            .await
+                            .unwrap();
+                    }
+                    assert!(disk
+                        .verify_piece(piece_index, piece.to_vec())
+                        .await
+                        .unwrap());
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    disk.flush().await.unwrap();
+}
+
+fn write(c: &mut Criterion) {
+    let fixture = fixture();
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("write + hash");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+    for kind in [StorageKind::File, StorageKind::Mmap] {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(kind, &fixture.info, dir.path());
+        group.bench_function(BenchmarkId::new("direct", format!("{kind:?}")), |b| {
+            b.iter(|| write_direct(storage.as_ref(), &fixture.info, &fixture.data))
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let disk = DiskIo::new(open(kind, &fixture.info, dir.path()), &fixture.info);
+        group.bench_function(BenchmarkId::new("DiskIo", format!("{kind:?}")), |b| {
+            b.to_async(&runtime)
+                .iter(|| write_pooled(&disk, &fixture.data))
+        });
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let fixture = fixture();
+    let info = &fixture.info;
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+    for kind in [StorageKind::File, StorageKind::Mmap] {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(kind, info, dir.path());
+        write_direct(storage.as_ref(), info, &fixture.data);
+
+        group.throughput(Throughput::Bytes(SIZE as u64));
+        group.bench_function(BenchmarkId::new("DiskIo", format!("{kind:?}")), |b| {
+            // A fresh DiskIo for every pass, so that nothing is cached yet.
+            b.to_async(&runtime).iter_batched(
+                || DiskIo::new(storage.clone(), info),
+                |disk| async move {
+                    for piece_index in 0..info.num_pieces() {
+                        assert!(disk.read_piece(piece_index).await.unwrap().is_some());
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+
+        let disk = DiskIo::new(storage.clone(), info);
+        let hot = 0..HOT_PIECES.min(info.num_pieces());
+        group.throughput(Throughput::Bytes((hot.len() * PLENGTH) as u64));
+        group.bench_function(
+            BenchmarkId::new("DiskIo cached", format!("{kind:?}")),
+            |b| {
+                b.to_async(&runtime).iter(|| async {
+                    for piece_index in hot.clone() {
+                        for begin in (0..info.piece_size(piece_index)).step_by(BLOCK) {
+                            let len = BLOCK.min(info.piece_size(piece_index) - begin);
+                            let block = disk.read_block(piece_index, begin, len).await.unwrap();
+                            assert!(block.is_some());
+                        }
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write, read);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! Feeds arbitrary bytes to the bencode decoder. Run with `cargo fuzz run decode`.
+
+#![no_main]
+
+use bittorrent_rust::{
+    decoder::{self, Mode, Reason},
+    value::Value,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    match decoder::decode(data, Mode::Strict) {
+        // Canonical bencode has exactly one encoding per value.
+        Ok(value) => assert_eq!(value.encode(), data),
+        Err(e) => {
+            assert!(e.offset <= data.len());
+            if e.reason == Reason::TooDeep {
+                assert!(matches!(data[e.offset], b'l' | b'd'));
+            }
+        }
+    }
+
+    // Lenient mode accepts more, but whatever it decodes encodes canonically.
+    if let Ok(value) = Value::decode(data, Mode::Lenient) {
+        assert_eq!(decoder::decode(&value.encode(), Mode::Strict), Ok(value));
+    }
+});
@@ -1,356 +1,805 @@
-pub mod activepeer {
-
-    use anyhow::{Context, Error, Result};
-    use futures_util::{lock::Mutex, SinkExt, StreamExt};
-    use sha1::{Digest, Sha1};
-    use std::{collections::VecDeque, io::SeekFrom, sync::Arc};
-    use tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        net::TcpStream,
-    };
-    use tokio_util::codec::Framed;
-
-    use crate::{
-        handshake::Handshake,
-        peers::peers::{Message, MessageFramer, MessageTag, Piece, Request, WorkQueue},
-        torrent::{Info, Torrent},
-    };
-
-    const BLOCK_MAX: usize = 1 << 14;
-    const PEER_ID: [u8; 20] = *b"00112233445566778899";
+use anyhow::{bail, Context, Error, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::{collections::VecDeque, net::SocketAddrV4, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+use crate::{
+    disk::DiskIo,
+    handshake::Handshake,
+    peers::{
+        HashRequest, Message, MessageFramer, MessageTag, PeerStream, Piece, Request, WorkQueue,
+    },
+    storage::DownloadBuffer,
+    torrent::{Info, Torrent, V2Piece},
+};
+
+pub const BLOCK_MAX: usize = 1 << 14;
+const PEER_ID: [u8; 20] = *b"00112233445566778899";
+/// Set in the last reserved handshake byte by clients that speak BitTorrent v2 (BEP 52).
+const RESERVED_V2: u8 = 0x10;
+/// Most hashes a peer will send for one `hash request`.
+const MAX_HASHES: usize = 512;
+/// How long to wait for a peer to answer a `hash request`.
+const HASHES_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
 
-    #[derive(Debug, Clone)]
-    pub struct PeerState {
-        pub am_choking: bool,
-        pub am_interested: bool,
-        pub peer_choking: bool,
-        pub peer_interested: bool,
+impl Default for PeerState {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    impl PeerState {
-        pub fn new() -> Self {
-            Self {
-                am_choking: true,
-                am_interested: false,
-                peer_choking: true,
-                peer_interested: false,
-            }
+impl PeerState {
+    pub fn new() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
         }
     }
+}
 
-    pub struct ActivePeer {
-        pub connection: Framed<TcpStream, MessageFramer>,
-        pub peer_state: PeerState,
-        pub bitfield: Vec<u8>,
+pub struct ActivePeer {
+    pub connection: Framed<PeerStream, MessageFramer>,
+    pub peer_state: PeerState,
+    pub bitfield: Vec<u8>,
+    pub addr: SocketAddrV4,
+}
+
+impl ActivePeer {
+    pub fn new(connection: Framed<PeerStream, MessageFramer>, addr: SocketAddrV4) -> Self {
+        Self {
+            connection,
+            peer_state: PeerState::new(),
+            bitfield: Vec::new(),
+            addr,
+        }
     }
 
-    impl ActivePeer {
-        pub fn new(connection: Framed<TcpStream, MessageFramer>) -> Self {
-            Self {
-                connection: connection,
-                peer_state: PeerState::new(),
-                bitfield: Vec::new(),
+    #[allow(dead_code)]
+    pub async fn download_piece(
+        &mut self,
+        piece_index: usize,
+        t: &Info,
+        work_queue: &WorkQueue,
+        buffer: Arc<tokio::sync::Mutex<DownloadBuffer>>,
+    ) -> Result<()> {
+        let piece_size = if piece_index == t.num_pieces() - 1 {
+            let md = t.calculate_length() % t.plength;
+            if md == 0 {
+                t.plength
+            } else {
+                md
             }
-        }
+        } else {
+            t.plength
+        };
 
-        pub async fn download_piece(
-            &mut self,
-            piece_index: usize,
-            t: &Info,
-            work_queue: &WorkQueue,
-            buffer: Arc<tokio::sync::Mutex<Vec<u8>>>,
-        ) -> Result<()> {
-            let piece_hash = &t.pieces.0[piece_index];
-            let piece_size = if piece_index == t.pieces.0.len() - 1 {
-                let md = t.calculate_length() % t.plength;
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+        let mut all_blocks = Vec::<u8>::with_capacity(piece_size);
+
+        for block in 0..nblocks {
+            let block_size = if block == nblocks - 1 {
+                let md = piece_size % BLOCK_MAX;
                 if md == 0 {
-                    t.plength
+                    BLOCK_MAX
                 } else {
                     md
                 }
             } else {
-                t.plength
+                BLOCK_MAX
             };
 
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-            let mut all_blocks = Vec::<u8>::with_capacity(piece_size);
+            let mut request = Request::new(
+                piece_index as u32,
+                (block * BLOCK_MAX) as u32,
+                block_size as u32,
+            );
 
-            for block in 0..nblocks {
-                let block_size = if block == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
-                    }
-                } else {
-                    BLOCK_MAX
-                };
-
-                let mut request = Request::new(
-                    piece_index as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
-
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                self.connection
-                    .send(Message {
-                        tag: MessageTag::Request,
-                        payload: request_bytes,
-                    })
-                    .await
-                    .with_context(|| format!("send request for block {block}"))?;
+            let request_bytes = Vec::from(request.as_bytes_mut());
+            self.connection
+                .send(Message {
+                    tag: MessageTag::Request,
+                    payload: request_bytes,
+                })
+                .await
+                .with_context(|| format!("send request for block {block}"))?;
 
-                let piece_msg = self
-                    .connection
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
+            let piece_msg = self
+                .connection
+                .next()
+                .await
+                .context("peer closed the connection")?
+                .context("peer message was invalid")?;
 
-                let piece = Piece::ref_from_bytes(&piece_msg.payload[..])
-                    .expect("always get all Piece response fields from peer");
+            let piece =
+                Piece::ref_from_bytes(&piece_msg.payload[..]).context("piece message too short")?;
 
-                all_blocks.extend(piece.block());
-            }
+            all_blocks.extend(piece.block());
+        }
 
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("GenericArray<_, 20> == [_; 20]");
-            if hash != *piece_hash {
-                println!("Piece {piece_index} failed hash check");
-                work_queue.return_piece(piece_index).await;
-                return Err(anyhow::anyhow!("Hash mismatch for piece {}", piece_index));
-            }
+        let disk = buffer.lock().await.disk.clone();
+        disk.write_block(piece_index, 0, &all_blocks).await?;
+        if !disk.verify_piece(piece_index, all_blocks).await? {
+            log::warn!("Piece {piece_index} failed hash check");
+            work_queue.return_piece(piece_index).await;
+            return Err(anyhow::anyhow!("Hash mismatch for piece {}", piece_index));
+        }
 
-            // If integrity check passes, write the piece to buffer
-            let mut buffer = buffer.lock().await;
-            buffer.extend(all_blocks);
+        // If integrity check passes, the piece is ours
+        buffer.lock().await.mark_have(piece_index);
 
-            println!("Successfully downloaded and verified piece {}", piece_index);
-            if piece_index == t.pieces.0.len() - 1 {
-                work_queue.sender.send(999999).await.unwrap();
+        log::info!("Successfully downloaded and verified piece {}", piece_index);
+        Ok(())
+    }
+
+    /// Downloads pieces from the work queue until it runs dry or the peer fails us. The piece
+    /// being downloaded when that happens goes back to the queue.
+    pub async fn start_exchanging_messages(
+        &mut self,
+        torrent: &Torrent,
+        work_queue: &WorkQueue,
+        buffer: Arc<tokio::sync::Mutex<DownloadBuffer>>,
+    ) -> crate::Result<()> {
+        self.exchange_handshakes(torrent).await?;
+        self.send_message(MessageTag::Interested, Vec::new())
+            .await?;
+        self.peer_state.am_interested = true;
+
+        let disk = buffer.lock().await.disk.clone();
+        while let Some(piece_index) = work_queue.get_piece().await {
+            match self.fetch_piece(piece_index, torrent, &disk, &buffer).await {
+                Ok(true) => {}
+                Ok(false) => work_queue.return_piece(piece_index).await,
+                Err(e) => {
+                    work_queue.return_piece(piece_index).await;
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads one piece and checks it: `true` once it is verified and stored, `false` if it
+    /// failed its hash check. Fails if the peer can't be used any more, including when it is
+    /// the one that sent bad blocks.
+    async fn fetch_piece(
+        &mut self,
+        piece_index: usize,
+        torrent: &Torrent,
+        disk: &DiskIo,
+        buffer: &tokio::sync::Mutex<DownloadBuffer>,
+    ) -> Result<bool> {
+        let info = &torrent.torrent_file.info;
+        let piece_size = ActivePeer::get_piece_size(piece_index, info);
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+        // Blocks that arrived before an interruption don't need to be asked for again.
+        let mut have_blocks = buffer.lock().await.partial_blocks(piece_index);
+        let mut all_blocks = vec![0; piece_size];
+        if !have_blocks.is_empty() {
+            match disk
+                .read_piece(piece_index)
+                .await
+                .with_context(|| format!("read piece {piece_index} back"))?
+            {
+                Some(partial) => all_blocks.copy_from_slice(&partial),
+                None => have_blocks.clear(),
             }
-            Ok(())
         }
 
-        pub async fn start_exchanging_messages(
-            &mut self,
-            torrent: &Torrent,
-            work_queue: &WorkQueue,
-            buffer: Arc<tokio::sync::Mutex<Vec<u8>>>,
-        ) {
-            //step 1. do handshake
-            let handshake = self.exchange_handshakes(torrent).await;
-
-            //step 2. get bitfield
-            // self.bitfield = self
-            //     .exchange_bitfields()
-            //     .await
-            //     .expect("should return bitfield")
-            //     .payload;
-
-            //step 3. send interested message
-            self.send_message(MessageTag::Interested, Vec::new())
+        let mut blocks_to_download: VecDeque<usize> = (0..nblocks)
+            .filter(|block| !have_blocks.contains(&(block * BLOCK_MAX)))
+            .collect();
+        while !blocks_to_download.is_empty() {
+            //send request for block
+            if !self.peer_state.peer_choking {
+                let block_index = blocks_to_download.pop_front().unwrap();
+                if self
+                    .send_block_request(piece_index, block_index, info)
+                    .await
+                    .is_err()
+                {
+                    blocks_to_download.push_back(block_index);
+                }
+            }
+            //wait for response
+            let message = self
+                .connection
+                .next()
                 .await
-                .expect("should send intersted message");
-
-            self.peer_state.am_interested = true;
-
-            //step 4. start trying to download
-
-            while let Some(piece_index) = work_queue.get_piece().await {
-                let piece_size =
-                    ActivePeer::get_piece_size(piece_index, &torrent.torrent_file.info);
-                let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-                let mut all_blocks = Vec::<u8>::with_capacity(piece_size);
-
-                let mut blocks_to_download: VecDeque<usize> = (0..nblocks).collect();
-                while !blocks_to_download.is_empty() {
-                    //send request for block
-                    if !self.peer_state.peer_choking {
-                        let block_index = blocks_to_download.pop_front().unwrap();
-                        match self
-                            .send_block_request(
-                                piece_index,
-                                block_index,
-                                &torrent.torrent_file.info,
-                            )
-                            .await
-                        {
-                            Ok(()) => {}
-                            Err(_) => {
-                                blocks_to_download.push_back(block_index);
+                .context("peer closed the connection")?
+                .context("invalid message from peer")?;
+
+            //process the message
+            match message.tag {
+                MessageTag::Choke => {
+                    self.peer_state.peer_choking = true;
+                    log::debug!("{} choked us", self.addr);
+                }
+                MessageTag::Unchoke => {
+                    self.peer_state.peer_choking = false;
+                    log::debug!("{} unchoked us", self.addr);
+                }
+                MessageTag::Interested => {}
+                MessageTag::NotInterested => {}
+                MessageTag::Have => {
+                    log::debug!("{} has a new piece", self.addr);
+                }
+                MessageTag::Bitfield => self.bitfield = message.payload,
+                MessageTag::Request => {}
+                MessageTag::Piece => {
+                    let piece = Piece::ref_from_bytes(&message.payload[..])
+                        .context("piece message too short")?;
+
+                    let begin = piece.begin() as usize;
+                    let block = piece.block();
+                    if piece.index() as usize == piece_index
+                        && begin + block.len() <= all_blocks.len()
+                    {
+                        all_blocks[begin..begin + block.len()].copy_from_slice(block);
+                        // Waits here while the disk catches up, so we stop reading.
+                        match disk.write_block(piece_index, begin, block).await {
+                            Ok(()) => {
+                                buffer
+                                    .lock()
+                                    .await
+                                    .add_block_from(piece_index, begin, self.addr)
+                            }
+                            Err(e) => {
+                                log::warn!("Could not store block of piece {piece_index}: {e:#}");
                             }
                         }
                     }
-                    //wait for response
-                    let message = self
-                        .connection
-                        .next()
-                        .await
-                        .expect("recieve message from peer")
-                        .context("invalid message from peer");
-
-                    let message = match message {
-                        Err(e) => {
-                            println!("{}", e);
-                            //blocks_to_download.push_back(block_index);
-                            break;
-                        }
-                        Ok(recv_message) => recv_message,
-                    };
+                }
+                MessageTag::Cancel => {}
+                MessageTag::HashRequest => {
+                    // Nothing is served to peers, so there are no hashes to give either.
+                    self.send_message(MessageTag::HashReject, message.payload)
+                        .await?;
+                }
+                MessageTag::Hashes | MessageTag::HashReject => {}
+            }
+        }
 
-                    //process the message
-                    match message.tag {
-                        MessageTag::Choke => {
-                            self.peer_state.peer_choking = true;
-                            println!("choked");
-                        }
-                        MessageTag::Unchoke => {
-                            self.peer_state.peer_choking = false;
-                            println!("unchocked");
-                        }
-                        MessageTag::Interested => {}
-                        MessageTag::NotInterested => {}
-                        MessageTag::Have => {
-                            println!("recieved a have message");
-                        }
-                        MessageTag::Bitfield => self.bitfield = message.payload,
-                        MessageTag::Request => {}
-                        MessageTag::Piece => {
-                            let piece = Piece::ref_from_bytes(&message.payload[..])
-                                .expect("always get all Piece response fields from peer");
+        // A v2 piece that fails can be narrowed down to its bad blocks, which needs the data
+        // once more.
+        let data = info.file_tree.is_some().then(|| all_blocks.clone());
+        let verified = disk
+            .verify_piece(piece_index, all_blocks)
+            .await
+            .with_context(|| format!("store piece {piece_index}"))?;
+        if verified {
+            buffer.lock().await.mark_have(piece_index);
+            log::info!(
+                "Successfully downloaded and verified piece {} : {}",
+                piece_index + 1,
+                info.num_pieces()
+            );
+            return Ok(true);
+        }
+        log::warn!("Piece {} failed hash check", piece_index + 1);
+        let blamed = match data {
+            Some(data) => {
+                self.blame_bad_blocks(piece_index, info, disk, buffer, data)
+                    .await
+            }
+            None => {
+                buffer.lock().await.discard_partial(piece_index);
+                false
+            }
+        };
+        if blamed {
+            bail!("{} sent bad data", self.addr);
+        }
+        Ok(false)
+    }
 
-                            all_blocks.extend(piece.block());
-                        }
-                        MessageTag::Cancel => {}
+    /// Uploads to a peer that connected to us: tells it which pieces we have, unchokes it
+    /// once it is interested and answers its requests, until it disconnects.
+    pub async fn serve(
+        &mut self,
+        torrent: &Torrent,
+        buffer: Arc<tokio::sync::Mutex<DownloadBuffer>>,
+    ) -> Result<()> {
+        self.exchange_handshakes(torrent).await?;
+        let (bitfield, disk) = {
+            let buffer = buffer.lock().await;
+            let mut bitfield = vec![0u8; buffer.have.len().div_ceil(8)];
+            for (i, _) in buffer.have.iter().enumerate().filter(|(_, have)| **have) {
+                bitfield[i / 8] |= 0x80 >> (i % 8);
+            }
+            (bitfield, buffer.disk.clone())
+        };
+        if bitfield.iter().any(|&byte| byte != 0) {
+            self.send_message(MessageTag::Bitfield, bitfield).await?;
+        }
+
+        while let Some(message) = self.connection.next().await {
+            let message = message.context("invalid message from peer")?;
+            match message.tag {
+                MessageTag::Interested => {
+                    self.peer_state.peer_interested = true;
+                    if self.peer_state.am_choking {
+                        self.send_message(MessageTag::Unchoke, Vec::new()).await?;
+                        self.peer_state.am_choking = false;
                     }
                 }
-
-                if blocks_to_download.is_empty() {
-                    let mut hasher = Sha1::new();
-                    hasher.update(&all_blocks);
-                    let hash: [u8; 20] = hasher
-                        .finalize()
-                        .try_into()
-                        .expect("GenericArray<_, 20> == [_; 20]");
-                    let piece_hash = &torrent.torrent_file.info.pieces.0[piece_index];
-                    if hash != *piece_hash {
-                        println!("Piece {} failed hash check", piece_index + 1);
-                        work_queue.return_piece(piece_index).await;
-                        all_blocks = Vec::<u8>::new();
+                MessageTag::NotInterested => self.peer_state.peer_interested = false,
+                MessageTag::Request if !self.peer_state.am_choking => {
+                    let field = |i: usize| -> Option<usize> {
+                        let bytes = message.payload.get(i * 4..i * 4 + 4)?;
+                        Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+                    };
+                    let (Some(index), Some(begin), Some(length)) = (field(0), field(1), field(2))
+                    else {
+                        bail!("peer sent a short request");
+                    };
+                    if length > BLOCK_MAX {
+                        bail!("peer asked for a {length} byte block");
                     }
-
-                    if !all_blocks.is_empty() {
-                        let mut buffer = buffer.lock().await;
-                        buffer.extend(all_blocks);
-
-                        println!(
-                            "Successfully downloaded and verified piece {} : {}",
-                            piece_index + 1,
-                            torrent.torrent_file.info.pieces.0.len()
-                        );
+                    // Only verified pieces are handed out.
+                    if !buffer
+                        .lock()
+                        .await
+                        .have
+                        .get(index)
+                        .copied()
+                        .unwrap_or(false)
+                    {
+                        continue;
                     }
+                    let Some(block) = disk.read_block(index, begin, length).await? else {
+                        continue;
+                    };
+                    let mut payload = Vec::with_capacity(8 + block.len());
+                    payload.extend((index as u32).to_be_bytes());
+                    payload.extend((begin as u32).to_be_bytes());
+                    payload.extend(block);
+                    self.send_message(MessageTag::Piece, payload).await?;
+                }
+                MessageTag::HashRequest => {
+                    self.send_message(MessageTag::HashReject, message.payload)
+                        .await?;
                 }
+                _ => {}
             }
         }
+        Ok(())
+    }
 
-        pub async fn exchange_handshakes(&mut self, torrent: &Torrent) -> Result<Handshake> {
-            let mut handshake = Handshake::new(torrent.info_hash, PEER_ID);
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                self.connection
-                    .get_mut()
-                    .write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                self.connection
-                    .get_mut()
-                    .read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+    /// Works out which blocks of a v2 piece that failed its hash check were bad, using the
+    /// block hashes from the peer, and who sent them. The good blocks are kept so only the
+    /// bad ones are downloaded again.
+    ///
+    /// Returns whether this peer is to blame, either for a bad block or for hashes that
+    /// don't add up.
+    async fn blame_bad_blocks(
+        &mut self,
+        piece_index: usize,
+        info: &Info,
+        disk: &DiskIo,
+        buffer: &tokio::sync::Mutex<DownloadBuffer>,
+        data: Vec<u8>,
+    ) -> bool {
+        let Some(piece) = info.v2_pieces.get(piece_index) else {
+            buffer.lock().await.discard_partial(piece_index);
+            return false;
+        };
+        let bad = if piece.leaves == 1 {
+            // The piece is a single block, so its hash is the block's hash.
+            Some(vec![0])
+        } else {
+            let leaves = match self.request_block_hashes(piece).await {
+                Ok(Some(leaves)) => leaves,
+                Ok(None) => {
+                    log::debug!("{} has no block hashes for piece {piece_index}", self.addr);
+                    buffer.lock().await.discard_partial(piece_index);
+                    return false;
+                }
+                Err(e) => {
+                    log::debug!("Could not get block hashes from {}: {e:#}", self.addr);
+                    buffer.lock().await.discard_partial(piece_index);
+                    return false;
+                }
+            };
+            match disk.bad_blocks(piece_index, data.clone(), leaves).await {
+                Ok(bad) => bad,
+                Err(e) => {
+                    log::warn!("Could not check blocks of piece {piece_index}: {e:#}");
+                    buffer.lock().await.discard_partial(piece_index);
+                    return false;
+                }
             }
+        };
+        let Some(bad) = bad else {
+            log::warn!(
+                "{} sent block hashes that don't match piece {piece_index}",
+                self.addr
+            );
+            buffer.lock().await.discard_partial(piece_index);
+            return true;
+        };
 
-            Ok(handshake)
+        let mut blamed = false;
+        let good: Vec<_> = {
+            let mut buffer = buffer.lock().await;
+            for &begin in &bad {
+                match buffer.block_sender(piece_index, begin) {
+                    Some(peer) => {
+                        log::warn!("Block at {begin} of piece {piece_index} from {peer} is bad");
+                        blamed |= peer == self.addr;
+                    }
+                    None => log::warn!("Block at {begin} of piece {piece_index} is bad"),
+                }
+            }
+            let good = buffer
+                .partial_blocks(piece_index)
+                .into_iter()
+                .filter(|begin| !bad.contains(begin))
+                .map(|begin| (begin, buffer.block_sender(piece_index, begin)))
+                .collect();
+            buffer.discard_partial(piece_index);
+            good
+        };
+        // The failed check dropped the queued blocks; put the good ones back.
+        for (begin, sender) in good {
+            let end = (begin + BLOCK_MAX).min(data.len());
+            if let Err(e) = disk
+                .write_block(piece_index, begin, &data[begin..end])
+                .await
+            {
+                log::warn!("Could not store block of piece {piece_index}: {e:#}");
+                continue;
+            }
+            let mut buffer = buffer.lock().await;
+            match sender {
+                Some(peer) => buffer.add_block_from(piece_index, begin, peer),
+                None => buffer.add_block(piece_index, begin),
+            }
         }
+        blamed
+    }
 
-        pub async fn exchange_bitfields(&mut self) -> Result<Message> {
-            let bitfield = self
-                .connection
-                .next()
+    /// Asks for the leaf hashes of a v2 piece, one per 16 KiB block. Returns `None` if the
+    /// peer rejects the request.
+    async fn request_block_hashes(&mut self, piece: &V2Piece) -> Result<Option<Vec<[u8; 32]>>> {
+        let length = piece.leaves.next_power_of_two();
+        if length > MAX_HASHES {
+            anyhow::bail!("piece has {length} blocks, more than a peer will send hashes for");
+        }
+        let mut request = HashRequest::new(
+            piece.pieces_root,
+            0,
+            piece.first_leaf as u32,
+            length as u32,
+            0,
+        );
+        self.send_message(MessageTag::HashRequest, Vec::from(request.as_bytes_mut()))
+            .await?;
+        loop {
+            let message = tokio::time::timeout(HASHES_TIMEOUT, self.connection.next())
                 .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            Ok(bitfield)
+                .context("peer didn't answer the hash request")?
+                .context("peer closed the connection")?
+                .context("invalid message from peer")?;
+            match message.tag {
+                MessageTag::Hashes | MessageTag::HashReject => {
+                    let (answer, hashes) = HashRequest::from_bytes(&message.payload)
+                        .context("malformed hashes message")?;
+                    if answer.pieces_root() != piece.pieces_root
+                        || answer.base_layer() != 0
+                        || answer.index() as usize != piece.first_leaf
+                    {
+                        continue;
+                    }
+                    if message.tag == MessageTag::HashReject {
+                        return Ok(None);
+                    }
+                    return Ok(Some(hashes));
+                }
+                MessageTag::Choke => self.peer_state.peer_choking = true,
+                MessageTag::Unchoke => self.peer_state.peer_choking = false,
+                MessageTag::Bitfield => self.bitfield = message.payload,
+                _ => {}
+            }
         }
+    }
 
-        pub async fn send_message(
-            &mut self,
-            message_tag: MessageTag,
-            payload: Vec<u8>,
-        ) -> Result<()> {
+    pub async fn exchange_handshakes(&mut self, torrent: &Torrent) -> Result<Handshake> {
+        let mut handshake = Handshake::new(torrent.info_hash, PEER_ID);
+        if torrent.torrent_file.info.file_tree.is_some() {
+            handshake.reserved[7] |= RESERVED_V2;
+        }
+        {
+            let handshake_bytes = handshake.as_bytes_mut();
             self.connection
-                .send(Message {
-                    tag: message_tag,
-                    payload: payload,
-                })
+                .get_mut()
+                .write_all(handshake_bytes)
+                .await
+                .context("write handshake")?;
+            self.connection
+                .get_mut()
+                .read_exact(handshake_bytes)
                 .await
-                .context("send interested message")
+                .context("read handshake")?;
+        }
+        if handshake.info_hash != torrent.info_hash {
+            bail!("{} answered for another torrent", self.addr);
         }
 
-        pub async fn send_block_request(
-            &mut self,
-            piece_index: usize,
-            block: usize,
-            info: &Info,
-        ) -> Result<(), Error> {
-            let piece_size = ActivePeer::get_piece_size(piece_index, info);
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
+        Ok(handshake)
+    }
 
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
-                } else {
-                    md
-                }
-            } else {
+    #[allow(dead_code)]
+    pub async fn exchange_bitfields(&mut self) -> Result<Message> {
+        let bitfield = self
+            .connection
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+        Ok(bitfield)
+    }
+
+    pub async fn send_message(&mut self, message_tag: MessageTag, payload: Vec<u8>) -> Result<()> {
+        self.connection
+            .send(Message {
+                tag: message_tag,
+                payload,
+            })
+            .await
+            .context("send interested message")
+    }
+
+    pub async fn send_block_request(
+        &mut self,
+        piece_index: usize,
+        block: usize,
+        info: &Info,
+    ) -> Result<(), Error> {
+        let piece_size = ActivePeer::get_piece_size(piece_index, info);
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+
+        let block_size = if block == nblocks - 1 {
+            let md = piece_size % BLOCK_MAX;
+            if md == 0 {
                 BLOCK_MAX
-            };
+            } else {
+                md
+            }
+        } else {
+            BLOCK_MAX
+        };
+
+        let mut request = Request::new(
+            piece_index as u32,
+            (block * BLOCK_MAX) as u32,
+            block_size as u32,
+        );
+
+        let request_bytes = Vec::from(request.as_bytes_mut());
+        self.connection
+            .send(Message {
+                tag: MessageTag::Request,
+                payload: request_bytes,
+            })
+            .await
+            .with_context(|| format!("send request for block {block}"))
+    }
+    pub fn get_piece_size(piece_index: usize, t: &Info) -> usize {
+        t.piece_size(piece_index)
+    }
+}
 
-            let mut request = Request::new(
-                piece_index as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
 
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.connection
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))
-        }
-        pub fn get_piece_size(piece_index: usize, t: &Info) -> usize {
-            let piece_size = if piece_index == t.pieces.0.len() - 1 {
-                let md = t.calculate_length() % t.plength;
-                if md == 0 {
-                    t.plength
-                } else {
-                    md
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::{
+        merkle,
+        storage::MemoryStorage,
+        torrent::{tests::hybrid_torrent, TorrentFile},
+        TorrentBuilder,
+    };
+
+    const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+
+    type Setup = (
+        Torrent,
+        Arc<tokio::sync::Mutex<DownloadBuffer>>,
+        ActivePeer,
+        DuplexStream,
+    );
+
+    /// A torrent of `data` in 16 KiB pieces, a download of it and a peer connected to the
+    /// other end of `remote`.
+    fn setup(data: &[u8]) -> Setup {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, data).unwrap();
+        let torrent_file = TorrentBuilder::new(&path)
+            .piece_length(1 << 14)
+            .build()
+            .unwrap();
+        download(torrent_file)
+    }
+
+    /// A download of `torrent_file` into memory, and a peer connected to the other end of
+    /// `remote`.
+    fn download(torrent_file: TorrentFile) -> Setup {
+        let info = &torrent_file.info;
+        let disk = DiskIo::new(Arc::new(MemoryStorage::new(info)), info);
+        let buffer = DownloadBuffer::new(disk, vec![false; info.num_pieces()]);
+        let (local, remote) = duplex(1 << 16);
+        let peer = ActivePeer::new(Framed::new(Box::new(local), MessageFramer), REMOTE);
+        (
+            Torrent::new(torrent_file),
+            Arc::new(tokio::sync::Mutex::new(buffer)),
+            peer,
+            remote,
+        )
+    }
+
+    /// Answers our handshake as a peer on `info_hash`, and carries on with framed messages.
+    async fn answer_handshake(
+        mut remote: DuplexStream,
+        info_hash: [u8; 20],
+    ) -> Framed<DuplexStream, MessageFramer> {
+        let mut ours = [0u8; 68];
+        remote.read_exact(&mut ours).await.unwrap();
+        let mut theirs = Handshake::new(info_hash, [b'R'; 20]);
+        remote.write_all(theirs.as_bytes_mut()).await.unwrap();
+        Framed::new(remote, MessageFramer)
+    }
+
+    #[tokio::test]
+    async fn handshake_for_another_torrent_is_refused() {
+        let (torrent, buffer, mut peer, remote) = setup(&[1; 40_000]);
+        let work_queue = WorkQueue::new(vec![0]);
+        tokio::spawn(answer_handshake(remote, [9; 20]));
+        let result = peer
+            .start_exchanging_messages(&torrent, &work_queue, buffer)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(work_queue.get_piece().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn short_piece_message_gives_the_piece_back() {
+        let (torrent, buffer, mut peer, remote) = setup(&[1; 40_000]);
+        let work_queue = WorkQueue::new(vec![1]);
+        let info_hash = torrent.info_hash;
+        let remote = tokio::spawn(async move {
+            let mut remote = answer_handshake(remote, info_hash).await;
+            let interested = remote.next().await.unwrap().unwrap();
+            assert_eq!(interested.tag, MessageTag::Interested);
+            for (tag, payload) in [
+                (MessageTag::Unchoke, vec![]),
+                (MessageTag::Piece, vec![0; 3]),
+            ] {
+                remote.send(Message { tag, payload }).await.unwrap();
+            }
+            remote
+        });
+        let result = peer
+            .start_exchanging_messages(&torrent, &work_queue, buffer)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(work_queue.get_piece().await, Some(1));
+        drop(remote.await.unwrap());
+    }
+
+    /// Plays a peer that sends `data` for whatever piece of it is requested, and the leaf
+    /// hashes of `good` when asked for them.
+    async fn send_blocks(
+        mut remote: Framed<DuplexStream, MessageFramer>,
+        plength: usize,
+        data: Vec<u8>,
+        good: Vec<u8>,
+    ) {
+        remote
+            .send(Message {
+                tag: MessageTag::Unchoke,
+                payload: Vec::new(),
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(message)) = remote.next().await {
+            match message.tag {
+                MessageTag::Request => {
+                    let field = |i: usize| {
+                        u32::from_be_bytes(message.payload[i * 4..i * 4 + 4].try_into().unwrap())
+                            as usize
+                    };
+                    let start = field(0) * plength + field(1);
+                    let mut payload = message.payload[..8].to_vec();
+                    payload.extend(&data[start..start + field(2)]);
+                    let tag = MessageTag::Piece;
+                    remote.send(Message { tag, payload }).await.unwrap();
                 }
-            } else {
-                t.plength
-            };
+                MessageTag::HashRequest => {
+                    let (request, _) = HashRequest::from_bytes(&message.payload).unwrap();
+                    let first = request.index() as usize * merkle::BLOCK_SIZE;
+                    let mut leaves = merkle::leaves(&good[first..first + plength]);
+                    leaves.resize(request.length() as usize, [0; 32]);
+                    let mut payload = message.payload;
+                    payload.extend(leaves.concat());
+                    let tag = MessageTag::Hashes;
+                    remote.send(Message { tag, payload }).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
 
-            piece_size
+    #[tokio::test]
+    async fn bad_block_is_blamed_on_the_peer_that_sent_it() {
+        const OTHER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6882);
+        let plength = 2 * merkle::BLOCK_SIZE;
+        let good: Vec<u8> = (0..2 * plength).map(|i| (i % 251) as u8).collect();
+        let torrent_file = TorrentFile::from_bytes(&hybrid_torrent(plength, &good, &good)).unwrap();
+
+        // Another peer sent a bad first block before; this one sends a good second block.
+        let (torrent, buffer, mut peer, remote) = download(torrent_file.clone());
+        let mut bad_first = good[..merkle::BLOCK_SIZE].to_vec();
+        bad_first[0] ^= 1;
+        {
+            let mut buffer = buffer.lock().await;
+            buffer.disk.write_block(0, 0, &bad_first).await.unwrap();
+            buffer.add_block_from(0, 0, OTHER);
         }
+        let (disk, info_hash) = (buffer.lock().await.disk.clone(), torrent.info_hash);
+        let remote = tokio::spawn(async move {
+            let remote = answer_handshake(remote, info_hash).await;
+            send_blocks(remote, plength, good.clone(), good).await;
+        });
+        peer.exchange_handshakes(&torrent).await.unwrap();
+        let fetched = peer.fetch_piece(0, &torrent, &disk, &buffer).await.unwrap();
+        assert!(!fetched);
+        // Only the good block is kept, still credited to the peer that sent it.
+        let buffer = buffer.lock().await;
+        assert_eq!(
+            buffer.partial_blocks(0).into_iter().collect::<Vec<_>>(),
+            [merkle::BLOCK_SIZE]
+        );
+        assert_eq!(buffer.block_sender(0, merkle::BLOCK_SIZE), Some(REMOTE));
+        drop(buffer);
+        drop(peer);
+        remote.await.unwrap();
+
+        // This time the peer itself sends the bad block, and is dropped for it.
+        let good: Vec<u8> = (0..2 * plength).map(|i| (i % 251) as u8).collect();
+        let mut bad = good.clone();
+        bad[plength + merkle::BLOCK_SIZE] ^= 1;
+        let (torrent, buffer, mut peer, remote) = download(torrent_file);
+        let disk = buffer.lock().await.disk.clone();
+        tokio::spawn(async move {
+            let remote = answer_handshake(remote, info_hash).await;
+            send_blocks(remote, plength, bad, good).await;
+        });
+        peer.exchange_handshakes(&torrent).await.unwrap();
+        let blamed = peer
+            .fetch_piece(1, &torrent, &disk, &buffer)
+            .await
+            .unwrap_err();
+        assert!(blamed.to_string().contains("sent bad data"), "{blamed}");
+        let buffer = buffer.lock().await;
+        assert_eq!(
+            buffer.partial_blocks(1).into_iter().collect::<Vec<_>>(),
+            [0]
+        );
+        assert_eq!(buffer.block_sender(1, 0), Some(REMOTE));
     }
 }
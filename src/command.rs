@@ -1,16 +1,355 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{net::SocketAddr, path::PathBuf};
+
+use bittorrent_rust::{
+    mse::EncryptionPolicy,
+    storage::{Preallocation, StorageKind},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+    /// How `info` and `peers` print their results.
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+    /// Also log every peer connection and what peers tell us, not just progress and failures.
+    #[arg(long, short, global = true)]
+    pub verbose: bool,
 }
 #[derive(Subcommand, Debug)]
 pub enum Command {
-    Decode { value: String },
+    /// Decode a bencoded value and print it as JSON.
+    Decode {
+        value: String,
+        /// Reject anything that isn't canonical bencode: leading zeros, negative zero, and
+        /// unsorted or repeated dictionary keys.
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Encode a JSON value as canonical bencode and write it to stdout.
+    ///
+    /// Strings starting with `hex:` hold hex-encoded bytes, the way `decode` and `convert`
+    /// write byte strings that aren't UTF-8.
+    Encode { json: String },
+    /// Convert a file between bencode and JSON, e.g. to edit a .torrent in a text editor.
+    ///
+    /// Converting a canonical file to JSON and back gives the same bytes.
+    Convert {
+        input: PathBuf,
+        /// Where to write the result; stdout if not given.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// What to convert to. Defaults to bencode for a `.json` input and JSON otherwise.
+        #[arg(long, value_enum)]
+        to: Option<ConvertTo>,
+    },
+    /// Print a torrent's tracker, size, info hashes, piece hashes and files.
     Info { torrent: PathBuf },
-    Peers { torrent: PathBuf },
-    Download { torrent: PathBuf },
+    /// List the peers the tracker, and optionally the DHT and the local network, know about.
+    Peers {
+        torrent: PathBuf,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+    },
+    /// Download a torrent into the current directory, resuming where an earlier run stopped.
+    Download {
+        torrent: PathBuf,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Whether peer connections use Message Stream Encryption.
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        /// Only connect over TCP instead of racing it against uTP.
+        #[arg(long)]
+        no_utp: bool,
+        /// File the download's progress is kept in so it can be resumed; defaults to the
+        /// torrent's name with `.resume` appended.
+        #[arg(long)]
+        resume_file: Option<PathBuf>,
+        /// Download pieces in order, so files can be read from the front while they download.
+        #[arg(long)]
+        sequential: bool,
+        /// How the downloaded data is written to disk.
+        #[arg(long, value_enum, default_value_t = Storage::File)]
+        storage: Storage,
+        /// How files are sized when they are created.
+        #[arg(long, value_enum, default_value_t = Preallocate::Sparse)]
+        preallocate: Preallocate,
+    },
+    /// Download one file in order and play it out while it downloads, to stdout or over HTTP.
+    Stream {
+        torrent: PathBuf,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+        /// Index of the file to stream, in the order `info` lists them; defaults to the largest.
+        #[arg(long)]
+        file: Option<usize>,
+        /// Serve the file over HTTP on this address (with Range support) instead of writing it
+        /// to stdout.
+        #[arg(long, value_name = "ADDR")]
+        http: Option<SocketAddr>,
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        #[arg(long)]
+        no_utp: bool,
+    },
+    /// Check files already on disk against a torrent's piece hashes.
+    Verify {
+        torrent: PathBuf,
+        /// Directory the torrent's content was downloaded into.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Create a .torrent from a file or directory.
+    Create {
+        /// File or directory to share.
+        path: PathBuf,
+        /// Where to write the .torrent; defaults to the content's name with `.torrent` appended.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Tracker URL. Each use adds a tier; separate backup trackers in a tier with commas.
+        #[arg(long, value_name = "URL[,URL...]")]
+        announce: Vec<String>,
+        /// Piece length in bytes; picked from the content size when left out.
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Overrides the program name recorded in `created by`.
+        #[arg(long)]
+        created_by: Option<String>,
+        /// Leave out the creation date so identical content yields an identical file.
+        #[arg(long)]
+        no_date: bool,
+        /// Restrict peers to the listed trackers (BEP 27).
+        #[arg(long)]
+        private: bool,
+        /// Source tag, e.g. the name of the site the torrent is made for.
+        #[arg(long)]
+        source: Option<String>,
+        /// GetRight-style web seed URL (BEP 19). Can be given several times.
+        #[arg(long = "web-seed", value_name = "URL")]
+        web_seeds: Vec<String>,
+    },
+    /// Change the trackers, comment, web seeds, private flag or source of a .torrent.
+    ///
+    /// Everything that isn't changed keeps its exact bytes. The private flag and the source are
+    /// part of the info dictionary, so changing them gives the torrent a new info hash.
+    Edit {
+        torrent: PathBuf,
+        /// Where to write the edited torrent; the input is overwritten if not given.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// New tracker URL; an empty one removes it.
+        #[arg(long)]
+        announce: Option<String>,
+        /// A tier of comma-separated tracker URLs. Replaces the whole announce-list; can be
+        /// given several times, and an empty tier removes the list.
+        #[arg(long = "announce-list", value_name = "URLS")]
+        announce_list: Vec<String>,
+        /// New comment; an empty one removes it.
+        #[arg(long)]
+        comment: Option<String>,
+        /// Web seed URL (BEP 19). Replaces all web seeds; can be given several times, and an
+        /// empty URL removes them.
+        #[arg(long = "web-seed", value_name = "URL")]
+        web_seeds: Vec<String>,
+        /// Whether peers may only come from the trackers (BEP 27).
+        #[arg(long)]
+        private: Option<bool>,
+        /// New source tag; an empty one removes it.
+        #[arg(long)]
+        source: Option<String>,
+    },
+    /// Keep downloading and seeding many torrents, controlled over a local HTTP API.
+    ///
+    /// `GET /torrents` lists them, `POST /torrents` with a .torrent as the body adds one,
+    /// `POST /torrents/<info hash>/pause` and `/resume` pause and resume one, and
+    /// `DELETE /torrents/<info hash>` removes one. Every request needs an
+    /// `Authorization: Bearer <token>` header, with the token from the file next to the state
+    /// file, e.g. daemon.token.
+    Daemon {
+        /// TCP port peers connect to, for every torrent.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Address the control API listens on.
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9091")]
+        rpc: SocketAddr,
+        /// Directory torrents are downloaded into.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+        /// File the torrents are kept in between runs.
+        #[arg(long, default_value = "daemon.state")]
+        state: PathBuf,
+        /// Most peer connections open at once, across all torrents.
+        #[arg(long, default_value_t = 200)]
+        max_connections: usize,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        /// Only use TCP: take no uTP connections on `--port` and open none.
+        #[arg(long)]
+        no_utp: bool,
+    },
+    /// Run a tracker.
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TrackerCommand {
+    /// Serve announce and scrape over HTTP and/or UDP, keeping swarms in memory. Both share
+    /// the same swarms.
+    Serve {
+        /// Address the HTTP tracker listens on. Defaults to 0.0.0.0:6969 when neither `--http`
+        /// nor `--udp` is given.
+        #[arg(long, value_name = "ADDR")]
+        http: Option<SocketAddr>,
+        /// Address the UDP tracker (BEP 15) listens on; use `[::]:PORT` to serve IPv4 and IPv6.
+        #[arg(long, value_name = "ADDR")]
+        udp: Option<SocketAddr>,
+        /// Requests per second a single IP may make to the UDP tracker; 0 for no limit.
+        #[arg(long, default_value_t = 10)]
+        udp_rate_limit: u32,
+        /// How often clients are told to announce, in seconds.
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+        /// Seconds after which a peer that stopped announcing is dropped.
+        #[arg(long, default_value_t = 3600)]
+        peer_ttl: u64,
+        /// Only track this torrent, given as a hex info hash or a .torrent file. Can be given
+        /// several times; every torrent is tracked if left out.
+        #[arg(long, value_name = "HASH|TORRENT")]
+        allow: Vec<String>,
+        /// Most peers handed out per announce.
+        #[arg(long, default_value_t = 200)]
+        max_numwant: usize,
+        /// File the swarms are saved in, so they survive a restart.
+        #[arg(long)]
+        state: Option<PathBuf>,
+    },
+}
+
+/// How `info` and `peers` print what they found.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Lines meant for people.
+    #[default]
+    Text,
+    /// One JSON document, meant for scripts.
+    Json,
+}
+
+/// `--encryption`, for `EncryptionPolicy`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// Try an encrypted connection first, fall back to plaintext.
+    Prefer,
+    /// Only ever use RC4-encrypted connections.
+    Require,
+    /// Never encrypt.
+    Disable,
+}
+
+impl From<Encryption> for EncryptionPolicy {
+    fn from(encryption: Encryption) -> Self {
+        match encryption {
+            Encryption::Prefer => EncryptionPolicy::Prefer,
+            Encryption::Require => EncryptionPolicy::Require,
+            Encryption::Disable => EncryptionPolicy::Disable,
+        }
+    }
+}
+
+/// `--storage`, for `StorageKind`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Plain reads and writes on the torrent's files.
+    File,
+    /// Memory-mapped files; every wanted file is created at its full size up front.
+    Mmap,
+}
+
+impl From<Storage> for StorageKind {
+    fn from(storage: Storage) -> Self {
+        match storage {
+            Storage::File => StorageKind::File,
+            Storage::Mmap => StorageKind::Mmap,
+        }
+    }
+}
+
+/// `--preallocate`, for `Preallocation`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preallocate {
+    /// Reserve every file's blocks up front (`fallocate`), so a full volume shows right away.
+    Full,
+    /// Set every file to its full length without reserving any blocks.
+    Sparse,
+    /// Create empty files that grow as pieces are written. Memory-mapped storage can't grow
+    /// files, so it treats this like `sparse`.
+    None,
+}
+
+impl From<Preallocate> for Preallocation {
+    fn from(preallocate: Preallocate) -> Self {
+        match preallocate {
+            Preallocate::Full => Preallocation::Full,
+            Preallocate::Sparse => Preallocation::Sparse,
+            Preallocate::None => Preallocation::None,
+        }
+    }
+}
+
+/// The format `Command::Convert` produces.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertTo {
+    Json,
+    Bencode,
+}
+
+// Which files of a multi-file torrent to download. Kept out of the doc comment for the same
+// reason as `DiscoveryArgs`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SelectionArgs {
+    /// Only download files whose path matches this glob; `*` and `?` stay within one
+    /// directory, `**` crosses them. Can be given several times.
+    #[arg(long, value_name = "GLOB")]
+    pub only: Vec<String>,
+    /// Only download the files with these indices, in the order `info` lists them.
+    #[arg(long, value_name = "INDEX,...", value_delimiter = ',')]
+    pub files: Vec<usize>,
+    /// Set the priority (skip, low, normal or high) of the files matching a glob, e.g.
+    /// `'*.iso=high'`. Can be given several times; later rules win.
+    #[arg(long, value_name = "GLOB=LEVEL")]
+    pub priority: Vec<String>,
+}
+
+// Peer sources used in addition to the tracker. A doc comment here would become the about
+// text of every subcommand that flattens this without one of its own.
+#[derive(clap::Args, Debug, Clone)]
+pub struct DiscoveryArgs {
+    /// Look for peers on the local network (BEP 14). LAN peers are tried before any others.
+    #[arg(long)]
+    pub lsd: bool,
+    /// Also look for peers on the mainline DHT.
+    #[arg(long)]
+    pub dht: bool,
+    /// UDP port the DHT node listens on.
+    #[arg(long, default_value_t = 6881)]
+    pub dht_port: u16,
+    /// DHT bootstrap node as host:port. Can be given several times; defaults to the well-known
+    /// public routers.
+    #[arg(long = "dht-bootstrap", value_name = "HOST:PORT")]
+    pub dht_bootstrap: Vec<String>,
+    /// File the DHT routing table is kept in between runs.
+    #[arg(long, default_value = "dht.dat")]
+    pub dht_state: PathBuf,
 }
@@ -0,0 +1,394 @@
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::IoContext,
+    hashes::Hashes,
+    torrent::{File, Info, Keys, TorrentFile, UrlList},
+    Error,
+};
+
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+/// Automatic piece lengths grow until the torrent has at most this many pieces.
+const TARGET_PIECES: usize = 2000;
+
+/// Picks a power-of-two piece length for `total_length` bytes of content.
+pub fn auto_piece_length(total_length: usize) -> usize {
+    let mut plength = MIN_PIECE_LENGTH;
+    while total_length.div_ceil(plength) > TARGET_PIECES && plength < MAX_PIECE_LENGTH {
+        plength *= 2;
+    }
+    plength
+}
+
+/// Builds a `TorrentFile` from a file or directory on disk.
+///
+/// ```ignore
+/// let torrent = TorrentBuilder::new("release/")
+///     .announce(vec!["http://tracker.example/announce".to_string()])
+///     .private(true)
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: bool,
+    private: bool,
+    source: Option<String>,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(concat!("bittorrent-rust/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date: true,
+            private: false,
+            source: None,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// Uses a fixed piece length instead of picking one from the content size.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers. The first tracker of the first tier becomes `announce`.
+    pub fn announce(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Whether to record the creation time; leaving it out makes builds reproducible.
+    pub fn creation_date(mut self, creation_date: bool) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Walks the content, hashes it and assembles the metainfo.
+    pub fn build(&self) -> crate::Result<TorrentFile> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable file name", self.path.display()))?
+            .to_string();
+        let metadata = fs::metadata(&self.path)
+            .io_context(|| format!("read metadata of {}", self.path.display()))?;
+
+        let (sources, keys) = if metadata.is_dir() {
+            let mut relative = Vec::new();
+            walk(&self.path, Path::new(""), &mut relative)?;
+            if relative.is_empty() {
+                return Err(Error::Other(format!(
+                    "{} contains no files",
+                    self.path.display()
+                )));
+            }
+            let mut sources = Vec::new();
+            let mut files = Vec::new();
+            for path in relative {
+                let full = self.path.join(&path);
+                let length = fs::metadata(&full)
+                    .io_context(|| format!("read metadata of {}", full.display()))?
+                    .len() as usize;
+                files.push(File {
+                    length,
+                    path: path
+                        .iter()
+                        .map(|part| {
+                            part.to_str()
+                                .map(str::to_string)
+                                .with_context(|| format!("{} is not UTF-8", full.display()))
+                        })
+                        .collect::<Result<_>>()?,
+                    attr: None,
+                    symlink_path: None,
+                    sha1: None,
+                });
+                sources.push((full, length));
+            }
+            (sources, Keys::MultiFile { files })
+        } else {
+            let length = metadata.len() as usize;
+            (
+                vec![(self.path.clone(), length)],
+                Keys::SingleFile { length },
+            )
+        };
+
+        let total_length: usize = sources.iter().map(|(_, length)| length).sum();
+        let plength = match self.piece_length {
+            Some(0) => return Err(Error::Other("piece length must not be zero".into())),
+            Some(plength) => plength,
+            None => auto_piece_length(total_length),
+        };
+        let pieces = hash_pieces(&sources, total_length, plength)?;
+
+        let mut trackers = self.trackers.iter().flatten();
+        let announce = trackers.next().cloned();
+        let has_backups = trackers.next().is_some();
+        let creation_date = self.creation_date.then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs() as i64)
+                .unwrap_or(0)
+        });
+
+        let mut torrent = TorrentFile {
+            announce,
+            announce_list: has_backups.then(|| self.trackers.clone()),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            creation_date,
+            nodes: None,
+            url_list: match self.web_seeds.as_slice() {
+                [] => None,
+                [url] => Some(UrlList::One(url.clone())),
+                urls => Some(UrlList::Many(urls.to_vec())),
+            },
+            httpseeds: None,
+            piece_layers: None,
+            info: Info {
+                name,
+                plength,
+                pieces,
+                private: self.private.then_some(1),
+                source: self.source.clone(),
+                meta_version: None,
+                file_tree: None,
+                keys: Some(keys),
+                v2_pieces: Vec::new(),
+            },
+            info_bytes: Vec::new(),
+        };
+        // Written out the same way, so these are the bytes the info hash is taken over.
+        torrent.info_bytes = serde_bencode::to_bytes(&torrent.info).context("encode info")?;
+        Ok(torrent)
+    }
+}
+
+/// Collects the files under `dir` as paths relative to the torrent root, in a stable order.
+/// Symlinks are skipped rather than followed, so a link can't pull in content from outside the
+/// directory or loop back into it.
+fn walk(dir: &Path, relative: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("list {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let file_type = fs::symlink_metadata(&path)
+            .with_context(|| format!("read metadata of {}", path.display()))?
+            .file_type();
+        if file_type.is_symlink() {
+            log::warn!("Skipping symlink {}", path.display());
+        } else if file_type.is_dir() {
+            walk(&path, &relative, out)?;
+        } else {
+            out.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the concatenation of `sources` in `plength` pieces, one worker thread per core.
+fn hash_pieces(
+    sources: &[(PathBuf, usize)],
+    total_length: usize,
+    plength: usize,
+) -> Result<Hashes> {
+    let npieces = total_length.div_ceil(plength);
+    let hashes = Mutex::new(vec![[0u8; 20]; npieces]);
+    let next_piece = AtomicUsize::new(0);
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(npieces.max(1));
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    let mut buf = vec![0u8; plength];
+                    loop {
+                        let index = next_piece.fetch_add(1, Ordering::Relaxed);
+                        if index >= npieces {
+                            return Ok(());
+                        }
+                        let start = index * plength;
+                        let len = plength.min(total_length - start);
+                        read_at(sources, start, &mut buf[..len])?;
+                        let mut hasher = Sha1::new();
+                        hasher.update(&buf[..len]);
+                        hashes.lock().expect("hash workers do not panic")[index] =
+                            hasher.finalize().into();
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("hash worker panicked"))
+    })?;
+
+    Ok(Hashes(
+        hashes.into_inner().expect("hash workers do not panic"),
+    ))
+}
+
+/// Fills `buf` from the concatenated files, starting `offset` bytes in.
+fn read_at(sources: &[(PathBuf, usize)], offset: usize, buf: &mut [u8]) -> Result<()> {
+    let mut file_start = 0;
+    let mut filled = 0;
+    for (path, length) in sources {
+        let file_end = file_start + length;
+        let want = offset + filled;
+        if filled < buf.len() && want < file_end {
+            let n = (file_end - want).min(buf.len() - filled);
+            let mut file =
+                fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start((want - file_start) as u64))?;
+            file.read_exact(&mut buf[filled..filled + n])
+                .with_context(|| format!("read {}", path.display()))?;
+            filled += n;
+        }
+        file_start = file_end;
+    }
+    if filled != buf.len() {
+        bail!("files changed size while hashing");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piece_length_grows_with_the_content() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(
+            auto_piece_length(TARGET_PIECES * MIN_PIECE_LENGTH),
+            MIN_PIECE_LENGTH
+        );
+        assert_eq!(
+            auto_piece_length(TARGET_PIECES * MIN_PIECE_LENGTH + 1),
+            2 * MIN_PIECE_LENGTH
+        );
+        assert_eq!(auto_piece_length(4 << 30), 1 << 22);
+        assert_eq!(auto_piece_length(usize::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn directories_become_sorted_multi_file_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("t");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.bin"), [b'b'; 5]).unwrap();
+        fs::write(root.join("sub/c.bin"), [b'c'; 7]).unwrap();
+        fs::write(root.join("a.bin"), [b'a'; 3]).unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("a.bin"), root.join("link.bin")).unwrap();
+            std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+        }
+
+        let torrent = TorrentBuilder::new(&root).piece_length(4).build().unwrap();
+        assert_eq!(torrent.info.name, "t");
+        let Some(Keys::MultiFile { files }) = &torrent.info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        let layout: Vec<_> = files
+            .iter()
+            .map(|file| (file.path.join("/"), file.length))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("a.bin".into(), 3),
+                ("b.bin".into(), 5),
+                ("sub/c.bin".into(), 7)
+            ]
+        );
+        // The files are hashed back to back: aaab bbbb cccc ccc.
+        let expected: Vec<[u8; 20]> = [&b"aaab"[..], b"bbbb", b"cccc", b"ccc"]
+            .iter()
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        assert_eq!(torrent.info.pieces.0, expected);
+    }
+
+    #[test]
+    fn builds_without_a_date_are_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        fs::write(&path, vec![7u8; 40000]).unwrap();
+        let build = || {
+            let torrent = TorrentBuilder::new(&path)
+                .announce(vec!["http://tracker/announce".into()])
+                .creation_date(false)
+                .build()
+                .unwrap();
+            assert_eq!(torrent.creation_date, None);
+            serde_bencode::to_bytes(&torrent).unwrap()
+        };
+        let first = build();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(build(), first);
+
+        let dated = TorrentBuilder::new(&path).build().unwrap();
+        assert!(dated.creation_date.is_some());
+    }
+}
@@ -0,0 +1,410 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    download::DownloadOptions,
+    error::IoContext,
+    output::TorrentStatusReport,
+    session::{AddOptions, Session, SessionConfig},
+    torrent::TorrentFile,
+};
+
+/// Longest request head the control API accepts.
+const MAX_REQUEST_HEAD: usize = 8 << 10;
+/// Largest .torrent the control API accepts; v2 piece layers make some big.
+const MAX_BODY: usize = 32 << 20;
+
+/// A session that outlives the process: the torrents in it, and whether they were paused, are
+/// kept in a state file and added again on the next start. It is controlled over HTTP:
+///
+/// - `GET /torrents` lists the torrents as JSON.
+/// - `POST /torrents` with a .torrent file as the body adds it.
+/// - `POST /torrents/<info hash>/pause` and `.../resume` pause and resume one.
+/// - `DELETE /torrents/<info hash>` removes one, leaving its files.
+///
+/// Every request needs `Authorization: Bearer <token>`, with the token kept next to the state
+/// file (see [`token_file`]). Requests with an `Origin`, which only browsers send, or with a
+/// `Host` other than the address they arrived on are turned away, so web pages can't drive it.
+#[derive(Clone)]
+pub struct Daemon {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    session: Session,
+    /// Where new torrents are downloaded to.
+    dir: PathBuf,
+    state_file: PathBuf,
+    token: String,
+    /// The .torrent files as they were added, by info hash, so they can be saved as they were.
+    torrents: Mutex<HashMap<[u8; 20], Vec<u8>>>,
+}
+
+/// What is written to the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DaemonState {
+    torrents: Vec<SavedTorrent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedTorrent {
+    #[serde(with = "serde_bytes")]
+    torrent: Vec<u8>,
+    /// 1 if the torrent was paused. Bencode has no booleans.
+    paused: u8,
+}
+
+impl Daemon {
+    /// Adds back the torrents from the state file, if there is one, and reads the control API's
+    /// token from its [`token_file`], making one up the first time.
+    pub fn new(session: Session, dir: PathBuf, state_file: PathBuf) -> crate::Result<Self> {
+        let token = load_token(&token_file(&state_file))?;
+        let daemon = Self {
+            inner: Arc::new(Inner {
+                session,
+                dir,
+                state_file,
+                token,
+                torrents: Mutex::new(HashMap::new()),
+            }),
+        };
+        let state = match std::fs::read(&daemon.inner.state_file) {
+            Ok(bytes) => serde_bencode::from_bytes::<DaemonState>(&bytes)
+                .context("parse daemon state file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => DaemonState::default(),
+            Err(e) => return Err(e).io_context(|| "read daemon state file"),
+        };
+        for saved in state.torrents {
+            if let Err(e) = daemon.add(saved.torrent, saved.paused == 1) {
+                log::warn!("Could not add a saved torrent back: {e:#}");
+            }
+        }
+        Ok(daemon)
+    }
+
+    /// Adds a .torrent file's torrent to the session and the state file.
+    pub fn add(&self, dot_torrent: Vec<u8>, paused: bool) -> crate::Result<[u8; 20]> {
+        let torrent = TorrentFile::from_bytes(&dot_torrent)?;
+        let options = AddOptions {
+            download: DownloadOptions {
+                dir: self.inner.dir.clone(),
+                ..Default::default()
+            },
+            paused,
+        };
+        let handle = self.inner.session.add(torrent, options)?;
+        log::info!("Added {}", handle.name());
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(handle.info_hash(), dot_torrent);
+        self.save()?;
+        Ok(handle.info_hash())
+    }
+
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        self.inner.session.remove(info_hash).await?;
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+        self.save()
+    }
+
+    /// Writes the torrents and whether they are paused to the state file.
+    pub fn save(&self) -> crate::Result<()> {
+        let state = DaemonState {
+            torrents: self
+                .inner
+                .torrents
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(info_hash, torrent)| SavedTorrent {
+                    torrent: torrent.clone(),
+                    paused: self
+                        .inner
+                        .session
+                        .torrent(info_hash)
+                        .is_some_and(|handle| handle.is_paused())
+                        .into(),
+                })
+                .collect(),
+        };
+        let bytes = serde_bencode::to_bytes(&state).context("encode daemon state")?;
+        std::fs::write(&self.inner.state_file, bytes)
+            .io_context(|| format!("write daemon state to {}", self.inner.state_file.display()))
+    }
+
+    /// Starts a session with `config`, adds back the torrents from `state_file` and serves the
+    /// control API on `listener` until `shutdown` resolves. The state file is written one last
+    /// time before the session is shut down.
+    pub async fn run(
+        config: SessionConfig,
+        dir: PathBuf,
+        state_file: PathBuf,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> crate::Result<()> {
+        let session = Session::start(config).await?;
+        let daemon = Self::new(session.clone(), dir, state_file)?;
+        tokio::select! {
+            result = daemon.serve(listener) => result?,
+            _ = shutdown => {}
+        }
+        // Saved first, as shutting down takes every torrent out of the session.
+        daemon.save()?;
+        session.shutdown().await
+    }
+
+    /// Serves the control API on `listener` until it fails.
+    pub async fn serve(&self, listener: TcpListener) -> crate::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await.io_context(|| "accept connection")?;
+            let daemon = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = daemon.handle_request(stream).await {
+                    log::warn!("Control request failed: {e:#}");
+                }
+            });
+        }
+    }
+
+    async fn handle_request(&self, mut stream: TcpStream) -> Result<()> {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_REQUEST_HEAD {
+                bail!("request head too long");
+            }
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.context("read request")? == 0 {
+                return Ok(());
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default().to_string();
+        let path = request_line.next().unwrap_or_default().to_string();
+        let header = |wanted: &str| {
+            lines.clone().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case(wanted)
+                    .then(|| value.trim())
+            })
+        };
+
+        let local_addr = stream.local_addr().context("get local address")?;
+        let refusal = if header("origin").is_some() {
+            Some(("403 Forbidden", "requests from web pages are not accepted"))
+        } else if !header("host").is_some_and(|host| is_own_host(host, local_addr)) {
+            Some(("403 Forbidden", "the Host header doesn't name this server"))
+        } else if !header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| same_token(token.trim(), &self.inner.token))
+        {
+            Some(("401 Unauthorized", "missing or wrong token"))
+        } else {
+            None
+        };
+        if let Some((status, reason)) = refusal {
+            return write_response(&mut stream, status, &serde_json::json!({ "error": reason }))
+                .await;
+        }
+
+        let content_length = header("content-length")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(0);
+        if content_length > MAX_BODY {
+            bail!("request body too long");
+        }
+        let mut body = vec![0; content_length];
+        stream
+            .read_exact(&mut body)
+            .await
+            .context("read request body")?;
+
+        let (status, json) = self.respond(&method, &path, body).await;
+        write_response(&mut stream, status, &json).await
+    }
+
+    async fn respond(
+        &self,
+        method: &str,
+        path: &str,
+        body: Vec<u8>,
+    ) -> (&'static str, serde_json::Value) {
+        let error = |e: &str| serde_json::json!({ "error": e });
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["torrents"]) => {
+                let mut torrents = Vec::new();
+                for handle in self.inner.session.torrents() {
+                    torrents.push(TorrentStatusReport::new(&handle.status().await));
+                }
+                torrents.sort_by(|a, b| a.name.cmp(&b.name));
+                ("200 OK", serde_json::json!(torrents))
+            }
+            ("POST", ["torrents"]) => match self.add(body, false) {
+                Ok(info_hash) => (
+                    "201 Created",
+                    serde_json::json!({ "info_hash": hex::encode(info_hash) }),
+                ),
+                Err(e) => ("400 Bad Request", error(&format!("{e:#}"))),
+            },
+            (_, ["torrents", info_hash, ..]) => {
+                let Some(handle) = <[u8; 20]>::try_from(hex::decode(info_hash).unwrap_or_default())
+                    .ok()
+                    .and_then(|info_hash| self.inner.session.torrent(&info_hash))
+                else {
+                    return ("404 Not Found", error("no such torrent"));
+                };
+                match (method, &segments[2..]) {
+                    ("POST", ["pause"]) => handle.pause(),
+                    ("POST", ["resume"]) => handle.resume(),
+                    ("DELETE", []) => {
+                        return match self.remove(&handle.info_hash()).await {
+                            Ok(()) => ("200 OK", serde_json::json!({})),
+                            Err(e) => ("500 Internal Server Error", error(&format!("{e:#}"))),
+                        };
+                    }
+                    _ => return ("404 Not Found", error("no such endpoint")),
+                }
+                if let Err(e) = self.save() {
+                    return ("500 Internal Server Error", error(&format!("{e:#}")));
+                }
+                (
+                    "200 OK",
+                    serde_json::json!(TorrentStatusReport::new(&handle.status().await)),
+                )
+            }
+            _ => ("404 Not Found", error("no such endpoint")),
+        }
+    }
+}
+
+/// Where the control API's token for a daemon with this state file is kept: next to it, with
+/// the extension `token`.
+pub fn token_file(state_file: &Path) -> PathBuf {
+    state_file.with_extension("token")
+}
+
+/// Reads the token at `path`, or makes one up and writes it there, readable only by us.
+fn load_token(path: &Path) -> crate::Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(token) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).io_context(|| format!("read {}", path.display())),
+    }
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .io_context(|| format!("write {}", path.display()))?;
+    Ok(token)
+}
+
+/// Whether a `Host` header names the address the request arrived on. A name that resolves
+/// elsewhere, as in DNS rebinding, doesn't.
+fn is_own_host(host: &str, local_addr: SocketAddr) -> bool {
+    host == local_addr.to_string()
+        || (local_addr.ip().is_loopback() && host == format!("localhost:{}", local_addr.port()))
+}
+
+/// Compares tokens in time that doesn't depend on where they first differ.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    json: &serde_json::Value,
+) -> Result<()> {
+    let body = json.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a request with the given extra header lines and returns the status line.
+    async fn status(addr: SocketAddr, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET /torrents HTTP/1.1\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn control_api_needs_the_token_and_its_own_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionConfig {
+            listen_port: 0,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let state_file = dir.path().join("daemon.state");
+        let daemon = Daemon::new(session, dir.path().to_path_buf(), state_file.clone()).unwrap();
+        let token = std::fs::read_to_string(token_file(&state_file)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { daemon.serve(listener).await });
+
+        let host = format!("Host: {addr}\r\n");
+        let auth = format!("Authorization: Bearer {token}\r\n");
+        assert_eq!(
+            status(addr, &format!("{host}{auth}")).await,
+            "HTTP/1.1 200 OK"
+        );
+        let localhost = format!("Host: localhost:{}\r\n", addr.port());
+        assert_eq!(
+            status(addr, &format!("{localhost}{auth}")).await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(status(addr, &host).await, "HTTP/1.1 401 Unauthorized");
+        let wrong = format!(
+            "{host}Authorization: Bearer {}\r\n",
+            "0".repeat(token.len())
+        );
+        assert_eq!(status(addr, &wrong).await, "HTTP/1.1 401 Unauthorized");
+        let origin = format!("{host}{auth}Origin: http://example.com\r\n");
+        assert_eq!(status(addr, &origin).await, "HTTP/1.1 403 Forbidden");
+        let rebound = format!("Host: evil.example:{}\r\n{auth}", addr.port());
+        assert_eq!(status(addr, &rebound).await, "HTTP/1.1 403 Forbidden");
+
+        // The token survives a restart.
+        assert_eq!(load_token(&token_file(&state_file)).unwrap(), token);
+    }
+}
@@ -1,78 +1,364 @@
-use serde_json::json;
-
-#[allow(dead_code)]
-pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-    let begin_delimiter = encoded_value.chars().next();
-
-    match begin_delimiter {
-        Some('0'..='9') => {
-            let colon_index = encoded_value.find(':').unwrap();
-            let number_string = &encoded_value[..colon_index];
-            let number = number_string.parse::<i64>().unwrap();
-            let string =
-                &encoded_value.as_bytes()[colon_index + 1..colon_index + 1 + number as usize];
-            let rest = &encoded_value[colon_index + 1 + number as usize..];
-            (
-                serde_json::Value::String(unsafe {
-                    std::str::from_utf8_unchecked(string).to_string()
-                }),
-                rest,
-            )
-        }
-        Some('i') => {
-            let (mut n, rest) = encoded_value.split_at(encoded_value.find('e').unwrap());
-            n = &n[1..];
-            let number = n.parse::<i64>().unwrap();
-            (json!(number), rest)
-        }
-        Some('l') => {
-            let mut values = Vec::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (v, remainder) = decode_bencoded_value(rest);
-                values.push(v);
-                rest = remainder;
+use std::{collections::BTreeMap, ops::Range};
+
+use thiserror::Error;
+
+use crate::value::Value;
+
+/// How deeply lists and dictionaries may nest before the input is rejected, so that hostile
+/// input can't overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Why a bencoded value couldn't be decoded, and where.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("{reason} at byte {offset}")]
+pub struct DecodeError {
+    /// Offset into the input of the byte that made decoding fail.
+    pub offset: usize,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Reason {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("integer has no digits")]
+    EmptyInteger,
+    #[error("integer doesn't fit in 64 bits")]
+    IntegerOverflow,
+    #[error("string length is longer than the input")]
+    StringTooLong,
+    #[error("dictionary key is not a string")]
+    NonStringKey,
+    #[error("nested too deeply")]
+    TooDeep,
+    #[error("trailing data after the value")]
+    TrailingData,
+    // Only strict mode reports the ones below.
+    #[error("number has a leading zero")]
+    LeadingZero,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("dictionary keys are not sorted")]
+    UnsortedKeys,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+}
+
+/// How picky the decoder is about input that is well-formed but not canonical.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Accepts leading zeros, `i-0e` and unsorted or repeated keys, like most clients do. A
+    /// repeated key keeps its last value.
+    #[default]
+    Lenient,
+    /// Only accepts canonical bencode, the one encoding every value has: integers without
+    /// leading zeros or negative zero, and dictionary keys sorted as raw bytes without
+    /// repeats. Anything hashed, like an info dictionary, should be canonical.
+    Strict,
+}
+
+/// Decodes a single bencoded value that makes up all of `input`.
+pub fn decode(input: &[u8], mode: Mode) -> Result<Value, DecodeError> {
+    let mut decoder = Decoder {
+        input,
+        pos: 0,
+        mode,
+    };
+    let value = decoder.value(0)?;
+    if decoder.pos != input.len() {
+        return Err(decoder.error(Reason::TrailingData));
+    }
+    Ok(value)
+}
+
+/// A dictionary key and where its still-encoded value sits in the input.
+pub type DictEntry = (Vec<u8>, Range<usize>);
+
+/// Splits a bencoded dictionary that makes up all of `input` into its entries. Editing a
+/// dictionary this way leaves every other entry exactly as it was, canonical or not.
+pub fn dict_entries(input: &[u8]) -> Result<Vec<DictEntry>, DecodeError> {
+    let mut decoder = Decoder {
+        input,
+        pos: 0,
+        mode: Mode::Lenient,
+    };
+    decoder.expect(b'd')?;
+    let mut entries = Vec::new();
+    while decoder.peek()? != b'e' {
+        if !decoder.peek()?.is_ascii_digit() {
+            return Err(decoder.error(Reason::NonStringKey));
+        }
+        let key = decoder.bytes()?.to_vec();
+        let start = decoder.pos;
+        decoder.value(1)?;
+        entries.push((key, start..decoder.pos));
+    }
+    decoder.pos += 1;
+    if decoder.pos != input.len() {
+        return Err(decoder.error(Reason::TrailingData));
+    }
+    Ok(entries)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    mode: Mode,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, reason: Reason) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(Reason::UnexpectedEnd))
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), DecodeError> {
+        match self.peek()? {
+            b if b == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            b => Err(self.error(Reason::UnexpectedByte(b))),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+        match self.peek()? {
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?.to_vec())),
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Int(self.integer(b'e')?))
+            }
+            b'l' | b'd' if depth >= MAX_DEPTH => Err(self.error(Reason::TooDeep)),
+            b'l' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                while self.peek()? != b'e' {
+                    values.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(values))
             }
-            return (values.into(), &rest[1..]);
-        }
-        Some('d') => {
-            let mut dict = serde_json::Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (k, remainder) = decode_bencoded_value(rest);
-                let k = match k {
-                    serde_json::Value::String(k) => k,
-                    k => {
-                        panic!("dict keys must be strings, not {k:?}");
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                let mut last_key: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_start = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(self.error(Reason::NonStringKey));
                     }
-                };
-                let (v, remainder) = decode_bencoded_value(remainder);
-                dict.insert(k, v);
-                rest = remainder;
+                    let key = self.bytes()?;
+                    if self.mode == Mode::Strict {
+                        if let Some(last) = last_key {
+                            let reason = match key.cmp(last) {
+                                std::cmp::Ordering::Greater => None,
+                                std::cmp::Ordering::Equal => Some(Reason::DuplicateKey),
+                                std::cmp::Ordering::Less => Some(Reason::UnsortedKeys),
+                            };
+                            if let Some(reason) = reason {
+                                return Err(DecodeError {
+                                    offset: key_start,
+                                    reason,
+                                });
+                            }
+                        }
+                    }
+                    last_key = Some(key);
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key.to_vec(), value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b => Err(self.error(Reason::UnexpectedByte(b))),
+        }
+    }
+
+    /// A byte string: its length, a colon and that many bytes.
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.integer(b':')?;
+        let start = self.pos;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| start.checked_add(length))
+            .filter(|&end| end <= self.input.len())
+            .ok_or_else(|| self.error(Reason::StringTooLong))?;
+        self.pos = end;
+        Ok(&self.input[start..end])
+    }
+
+    /// Decimal digits with an optional leading `-`, up to and including `terminator`.
+    fn integer(&mut self, terminator: u8) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        let mut n: i64 = 0;
+        loop {
+            match self.peek()? {
+                b @ b'0'..=b'9' => {
+                    let digit = i64::from(b - b'0');
+                    // Accumulate negatively so that i64::MIN fits.
+                    n = n.checked_mul(10).and_then(|n| n.checked_sub(digit)).ok_or(
+                        DecodeError {
+                            offset: start,
+                            reason: Reason::IntegerOverflow,
+                        },
+                    )?;
+                    self.pos += 1;
+                }
+                b if b == terminator => break,
+                b => return Err(self.error(Reason::UnexpectedByte(b))),
             }
-            return (dict.into(), &rest[1..]);
         }
-        _ => {
-            panic!("Unhandled encoded value: {}", encoded_value)
+        let digits = &self.input[digits_start..self.pos];
+        if digits.is_empty() {
+            return Err(self.error(Reason::EmptyInteger));
+        }
+        if self.mode == Mode::Strict {
+            if negative && digits == b"0" {
+                return Err(DecodeError {
+                    offset: start,
+                    reason: Reason::NegativeZero,
+                });
+            }
+            if digits.len() > 1 && digits[0] == b'0' {
+                return Err(DecodeError {
+                    offset: digits_start,
+                    reason: Reason::LeadingZero,
+                });
+            }
+        }
+        self.expect(terminator)?;
+        if negative {
+            Ok(n)
+        } else {
+            n.checked_neg().ok_or(DecodeError {
+                offset: start,
+                reason: Reason::IntegerOverflow,
+            })
         }
     }
 }
 
-#[allow(dead_code)]
-pub fn decode_torrent(encoded_value: &str) -> serde_json::Value {
-    let mut values = Vec::new();
-    let (val, mut rest) = decode_bencoded_value(&encoded_value);
-    values.push(val);
-    while !rest.is_empty() {
-        if rest.starts_with('e') {
-            rest = &rest[1..];
-        }
-        if rest.is_empty() {
-            break;
-        }
-        let val = decode_bencoded_value(&rest);
-        values.push(val.0);
-        rest = val.1;
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            any::<i64>().prop_map(Value::Int),
+            prop::collection::vec(any::<u8>(), 0..16).prop_map(Value::Bytes),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::List),
+                prop::collection::btree_map(prop::collection::vec(any::<u8>(), 0..8), inner, 0..8)
+                    .prop_map(Value::Dict),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn encoded_values_decode_to_themselves(value in value()) {
+            let encoded = value.encode();
+            prop_assert_eq!(decode(&encoded, Mode::Strict), Ok(value.clone()));
+            prop_assert_eq!(decode(&encoded, Mode::Lenient), Ok(value));
+        }
+
+        /// Strict mode only accepts the one canonical encoding of a value.
+        #[test]
+        fn strict_input_is_canonical(input in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok(value) = decode(&input, Mode::Strict) {
+                prop_assert_eq!(value.encode(), input);
+            }
+        }
+
+        #[test]
+        fn errors_point_into_the_input(input in prop::collection::vec(any::<u8>(), 0..64)) {
+            for mode in [Mode::Lenient, Mode::Strict] {
+                if let Err(e) = decode(&input, mode) {
+                    prop_assert!(e.offset <= input.len());
+                    if e.reason == Reason::UnexpectedEnd {
+                        prop_assert_eq!(e.offset, input.len());
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn truncated_input_fails_within_it(value in value(), cut in any::<prop::sample::Index>()) {
+            let encoded = value.encode();
+            let truncated = &encoded[..cut.index(encoded.len())];
+            let e = decode(truncated, Mode::Strict).unwrap_err();
+            prop_assert!(e.offset <= truncated.len());
+            prop_assert!(matches!(
+                e.reason,
+                Reason::UnexpectedEnd | Reason::StringTooLong | Reason::EmptyInteger
+            ));
+        }
+
+        #[test]
+        fn trailing_data_is_reported_where_it_starts(value in value(), extra in 1..8usize) {
+            let mut encoded = value.encode();
+            let end = encoded.len();
+            encoded.extend(std::iter::repeat_n(b'x', extra));
+            let e = decode(&encoded, Mode::Strict).unwrap_err();
+            prop_assert_eq!(e, DecodeError { offset: end, reason: Reason::TrailingData });
+        }
+
+        #[test]
+        fn values_round_trip_through_json(value in value()) {
+            let json = value.to_json();
+            let text = serde_json::to_string(&json).unwrap();
+            let back = Value::from_json(&serde_json::from_str(&text).unwrap()).unwrap();
+            prop_assert_eq!(back.encode(), value.encode());
+        }
+
+        #[test]
+        fn dict_entries_span_their_values(
+            dict in prop::collection::btree_map(prop::collection::vec(any::<u8>(), 0..8), value(), 0..8)
+        ) {
+            let encoded = Value::Dict(dict.clone()).encode();
+            let entries = dict_entries(&encoded).unwrap();
+            prop_assert_eq!(entries.len(), dict.len());
+            let mut last_end = 0;
+            for ((key, range), (expected_key, expected)) in entries.into_iter().zip(dict) {
+                prop_assert_eq!(key, expected_key);
+                prop_assert!(range.start > last_end && range.end < encoded.len());
+                last_end = range.end;
+                prop_assert_eq!(decode(&encoded[range], Mode::Strict), Ok(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH), Mode::Strict).is_ok());
+        assert_eq!(
+            decode(&nested(MAX_DEPTH + 1), Mode::Strict),
+            Err(DecodeError {
+                offset: MAX_DEPTH,
+                reason: Reason::TooDeep,
+            })
+        );
     }
-    values.into()
 }
@@ -0,0 +1,1030 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::join_all;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::{error::IoContext, peers::Peer, Error};
+
+pub type NodeId = [u8; 20];
+
+/// The number of nodes kept in each bucket, and the number of nodes a lookup converges on.
+pub const K: usize = 8;
+/// How many queries a lookup keeps in flight at once.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Tokens handed out in `get_peers` replies stay valid for one to two rotations.
+const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten if they don't re-announce within this window.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// How often peers past `PEER_TTL` are swept out of the store.
+const PEER_SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Announces for new info hashes are ignored once this many are stored.
+const MAX_STORED_TORRENTS: usize = 2000;
+/// Once a torrent has this many peers, a new announce replaces the least recent one.
+const MAX_STORED_PEERS: usize = 100;
+/// A node we haven't heard from in this long may be replaced by a new one.
+const NODE_STALE: Duration = Duration::from_secs(15 * 60);
+const COMPACT_NODE_LEN: usize = 26;
+const COMPACT_PEER_LEN: usize = 6;
+
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    pub last_seen: Instant,
+}
+
+/// Kademlia routing table: one k-bucket per bit of distance from our own id.
+///
+/// Bucket `i` holds the nodes whose distance from us has exactly `i` leading zero bits, so the
+/// buckets close to us are sparse and the ones far away fill up first.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own_id, id);
+        let mut zeros = 0;
+        for byte in d {
+            if byte == 0 {
+                zeros += 8;
+            } else {
+                return Some(zeros + byte.leading_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    /// Inserts or refreshes a node. Returns false if the bucket was full of live nodes.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) -> bool {
+        let Some(index) = self.bucket_index(&id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|node| node.id == id) {
+            // Move to the tail: buckets are kept ordered from least to most recently seen.
+            let mut node = bucket.remove(position);
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            bucket.push(node);
+            return true;
+        }
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        if bucket[0].last_seen.elapsed() > NODE_STALE {
+            bucket.remove(0);
+            bucket.push(node);
+            return true;
+        }
+        false
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|node| node.id != *id);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().cloned().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn encode_compact_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(COMPACT_PEER_LEN);
+    bytes.extend(addr.ip().octets());
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    if bytes.len() != COMPACT_PEER_LEN {
+        return None;
+    }
+    Some(SocketAddrV4::new(
+        [bytes[0], bytes[1], bytes[2], bytes[3]].into(),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+fn encode_compact_nodes<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for node in nodes {
+        bytes.extend(node.id);
+        bytes.extend(encode_compact_peer(&node.addr));
+    }
+    bytes
+}
+
+fn decode_compact_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            let id: NodeId = chunk[..20].try_into().expect("guaranteed to be length 20");
+            decode_compact_peer(&chunk[20..]).map(|addr| (id, addr))
+        })
+        .collect()
+}
+
+/// The four KRPC queries defined by BEP 5.
+#[derive(Debug, Clone)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The body of a KRPC response. Which fields are present depends on the query.
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<(NodeId, SocketAddrV4)>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+enum Krpc {
+    Query {
+        transaction: Vec<u8>,
+        id: NodeId,
+        query: Query,
+    },
+    Response {
+        transaction: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn get<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> Option<&'a Value> {
+    dict.get(key.as_bytes())
+}
+
+fn get_bytes<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> Option<&'a [u8]> {
+    match get(dict, key) {
+        Some(Value::Bytes(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn get_id(dict: &HashMap<Vec<u8>, Value>, key: &str) -> Result<[u8; 20]> {
+    get_bytes(dict, key)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("missing or malformed `{key}`"))
+}
+
+impl Krpc {
+    fn encode(&self) -> Vec<u8> {
+        let value = match self {
+            Krpc::Query {
+                transaction,
+                id,
+                query,
+            } => {
+                let mut args = vec![("id", Value::Bytes(id.to_vec()))];
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => {
+                        args.push(("target", Value::Bytes(target.to_vec())));
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.push(("info_hash", Value::Bytes(info_hash.to_vec())));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.push(("info_hash", Value::Bytes(info_hash.to_vec())));
+                        args.push(("port", Value::Int(*port as i64)));
+                        args.push(("implied_port", Value::Int(*implied_port as i64)));
+                        args.push(("token", Value::Bytes(token.clone())));
+                    }
+                }
+                dict(vec![
+                    ("t", Value::Bytes(transaction.clone())),
+                    ("y", Value::Bytes(b"q".to_vec())),
+                    ("q", Value::Bytes(query.method().as_bytes().to_vec())),
+                    ("a", dict(args)),
+                ])
+            }
+            Krpc::Response {
+                transaction,
+                response,
+            } => {
+                let mut body = vec![("id", Value::Bytes(response.id.to_vec()))];
+                if !response.nodes.is_empty() {
+                    let mut nodes = Vec::with_capacity(COMPACT_NODE_LEN * response.nodes.len());
+                    for (id, addr) in &response.nodes {
+                        nodes.extend(id);
+                        nodes.extend(encode_compact_peer(addr));
+                    }
+                    body.push(("nodes", Value::Bytes(nodes)));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|addr| Value::Bytes(encode_compact_peer(addr)))
+                        .collect();
+                    body.push(("values", Value::List(values)));
+                }
+                if let Some(token) = &response.token {
+                    body.push(("token", Value::Bytes(token.clone())));
+                }
+                dict(vec![
+                    ("t", Value::Bytes(transaction.clone())),
+                    ("y", Value::Bytes(b"r".to_vec())),
+                    ("r", dict(body)),
+                ])
+            }
+            Krpc::Error {
+                transaction,
+                code,
+                message,
+            } => dict(vec![
+                ("t", Value::Bytes(transaction.clone())),
+                ("y", Value::Bytes(b"e".to_vec())),
+                (
+                    "e",
+                    Value::List(vec![
+                        Value::Int(*code),
+                        Value::Bytes(message.as_bytes().to_vec()),
+                    ]),
+                ),
+            ]),
+        };
+        serde_bencode::to_bytes(&value).expect("encoding a bencode value should be fine")
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let value: Value = serde_bencode::from_bytes(bytes).context("parse KRPC message")?;
+        let Value::Dict(message) = value else {
+            bail!("KRPC message is not a dictionary");
+        };
+        let transaction = get_bytes(&message, "t")
+            .context("KRPC message has no transaction id")?
+            .to_vec();
+        match get_bytes(&message, "y") {
+            Some(b"q") => {
+                let Some(Value::Dict(args)) = get(&message, "a") else {
+                    bail!("query has no arguments");
+                };
+                let id = get_id(args, "id")?;
+                let query = match get_bytes(&message, "q") {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode {
+                        target: get_id(args, "target")?,
+                    },
+                    Some(b"get_peers") => Query::GetPeers {
+                        info_hash: get_id(args, "info_hash")?,
+                    },
+                    Some(b"announce_peer") => {
+                        let port = match get(args, "port") {
+                            Some(Value::Int(port)) => u16::try_from(*port).unwrap_or(0),
+                            _ => 0,
+                        };
+                        let implied_port =
+                            matches!(get(args, "implied_port"), Some(Value::Int(n)) if *n != 0);
+                        Query::AnnouncePeer {
+                            info_hash: get_id(args, "info_hash")?,
+                            port,
+                            implied_port,
+                            token: get_bytes(args, "token")
+                                .context("announce_peer has no token")?
+                                .to_vec(),
+                        }
+                    }
+                    other => bail!(
+                        "unknown query method {:?}",
+                        other.map(String::from_utf8_lossy)
+                    ),
+                };
+                Ok(Krpc::Query {
+                    transaction,
+                    id,
+                    query,
+                })
+            }
+            Some(b"r") => {
+                let Some(Value::Dict(body)) = get(&message, "r") else {
+                    bail!("response has no body");
+                };
+                let values = match get(body, "values") {
+                    Some(Value::List(values)) => values
+                        .iter()
+                        .filter_map(|value| match value {
+                            Value::Bytes(bytes) => decode_compact_peer(bytes),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok(Krpc::Response {
+                    transaction,
+                    response: Response {
+                        id: get_id(body, "id")?,
+                        nodes: get_bytes(body, "nodes")
+                            .map(decode_compact_nodes)
+                            .unwrap_or_default(),
+                        values,
+                        token: get_bytes(body, "token").map(<[u8]>::to_vec),
+                    },
+                })
+            }
+            Some(b"e") => {
+                let (code, message) = match get(&message, "e") {
+                    Some(Value::List(error)) => match error.as_slice() {
+                        [Value::Int(code), Value::Bytes(message), ..] => {
+                            (*code, String::from_utf8_lossy(message).into_owned())
+                        }
+                        _ => (0, String::from("malformed error")),
+                    },
+                    _ => (0, String::from("malformed error")),
+                };
+                Ok(Krpc::Error {
+                    transaction,
+                    code,
+                    message,
+                })
+            }
+            _ => bail!("unknown KRPC message type"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The UDP port the node listens on.
+    pub port: u16,
+    /// `host:port` pairs contacted when the routing table is empty.
+    pub bootstrap_nodes: Vec<String>,
+    /// Where the routing table is saved between runs, if anywhere.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            state_file: None,
+        }
+    }
+}
+
+/// What is written to `DhtConfig::state_file`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DhtState {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    /// Compact node info, 26 bytes per node.
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+}
+
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+/// Peers announced to us, by info hash, with the time of their last announce.
+///
+/// Anyone can announce, so the store is capped at `MAX_STORED_TORRENTS` info hashes of at most
+/// `MAX_STORED_PEERS` peers each.
+#[derive(Default)]
+struct PeerStore {
+    torrents: HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>,
+}
+
+impl PeerStore {
+    fn get(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        self.torrents
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|(_, seen)| seen.elapsed() < PEER_TTL)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddrV4) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_STORED_TORRENTS {
+            self.expire();
+            if self.torrents.len() >= MAX_STORED_TORRENTS {
+                return;
+            }
+        }
+        let entries = self.torrents.entry(info_hash).or_default();
+        // Entries are kept in announce order, so the first one is the least recent.
+        entries.retain(|(known, _)| *known != addr);
+        if entries.len() >= MAX_STORED_PEERS {
+            entries.remove(0);
+        }
+        entries.push((addr, Instant::now()));
+    }
+
+    fn expire(&mut self) {
+        self.torrents.retain(|_, entries| {
+            entries.retain(|(_, seen)| seen.elapsed() < PEER_TTL);
+            !entries.is_empty()
+        });
+    }
+}
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<Vec<u8>, oneshot::Sender<Result<Response>>>>,
+    peers: Mutex<PeerStore>,
+    secrets: Mutex<Secrets>,
+    next_transaction: AtomicU16,
+}
+
+/// A node in the mainline DHT (BEP 5).
+///
+/// The node answers queries from other nodes for as long as it is alive and can look up and
+/// announce peers for info hashes, which makes it an alternative to `Torrent::contact_tracker`.
+pub struct Dht {
+    inner: Arc<Inner>,
+    config: DhtConfig,
+    receiver: JoinHandle<()>,
+    sweeper: JoinHandle<()>,
+}
+
+/// The outcome of an iterative `get_peers` lookup.
+struct Lookup {
+    /// The closest nodes that answered, together with the token each one gave us.
+    closest: Vec<(SocketAddrV4, Vec<u8>)>,
+    peers: Vec<SocketAddrV4>,
+}
+
+impl Dht {
+    pub async fn bind(config: DhtConfig) -> crate::Result<Self> {
+        let state = config.state_file.as_ref().and_then(|path| {
+            let bytes = std::fs::read(path).ok()?;
+            serde_bencode::from_bytes::<DhtState>(&bytes).ok()
+        });
+        let id: NodeId = state
+            .as_ref()
+            .and_then(|state| state.id.as_slice().try_into().ok())
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        let mut table = RoutingTable::new(id);
+        if let Some(state) = &state {
+            for (node_id, addr) in decode_compact_nodes(&state.nodes) {
+                table.insert(node_id, addr);
+            }
+        }
+
+        let socket = UdpSocket::bind(("0.0.0.0", config.port))
+            .await
+            .io_context(|| format!("bind DHT socket on port {}", config.port))?;
+        let mut rng = rand::thread_rng();
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            peers: Mutex::new(PeerStore::default()),
+            secrets: Mutex::new(Secrets {
+                current: rng.gen(),
+                previous: rng.gen(),
+                rotated_at: Instant::now(),
+            }),
+            next_transaction: AtomicU16::new(rng.gen()),
+        });
+        let receiver = tokio::spawn(Inner::receive_loop(inner.clone()));
+        let sweeper = tokio::spawn(Inner::sweep_peers(inner.clone()));
+        Ok(Self {
+            inner,
+            config,
+            receiver,
+            sweeper,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> crate::Result<SocketAddr> {
+        self.inner
+            .socket
+            .local_addr()
+            .io_context(|| "DHT socket address")
+    }
+
+    /// Binds and bootstraps. A node that can't reach anyone yet still answers queries and
+    /// learns about the network from them, so a failed bootstrap is only logged.
+    pub async fn start(config: DhtConfig) -> crate::Result<Self> {
+        let dht = Self::bind(config).await?;
+        if let Err(e) = dht.bootstrap().await {
+            log::warn!("DHT bootstrap failed: {e}");
+        }
+        Ok(dht)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// Fills the routing table from the saved nodes and the configured bootstrap nodes, then
+    /// looks up our own id so that the buckets closest to us get populated.
+    pub async fn bootstrap(&self) -> crate::Result<()> {
+        let mut candidates: Vec<SocketAddrV4> = {
+            let table = self.inner.table.lock().unwrap();
+            table.nodes().map(|node| node.addr).collect()
+        };
+        let mut unresolved = Vec::new();
+        for host in &self.config.bootstrap_nodes {
+            match lookup_host(host.as_str()).await {
+                Ok(addrs) => candidates.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(_) => unresolved.push(host.as_str()),
+            }
+        }
+
+        let target = self.inner.id;
+        let pings = candidates
+            .iter()
+            .map(|addr| self.inner.query(*addr, Query::FindNode { target }));
+        for response in join_all(pings).await.into_iter().flatten() {
+            let mut table = self.inner.table.lock().unwrap();
+            for (id, addr) in response.nodes {
+                table.insert(id, addr);
+            }
+        }
+        if self.inner.table.lock().unwrap().is_empty() {
+            let mut message = String::from("no DHT node answered during bootstrap");
+            if !unresolved.is_empty() {
+                message += &format!(" (could not resolve {})", unresolved.join(", "));
+            }
+            return Err(Error::Other(message));
+        }
+
+        self.inner.lookup(target, false).await;
+        Ok(())
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> crate::Result<NodeId> {
+        Ok(self.inner.query(addr, Query::Ping).await?.id)
+    }
+
+    pub async fn find_node(
+        &self,
+        addr: SocketAddrV4,
+        target: NodeId,
+    ) -> crate::Result<Vec<(NodeId, SocketAddrV4)>> {
+        Ok(self
+            .inner
+            .query(addr, Query::FindNode { target })
+            .await?
+            .nodes)
+    }
+
+    /// Looks up peers for `info_hash` by walking the DHT towards it.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<Peer> {
+        let lookup = self.inner.lookup(info_hash, true).await;
+        lookup.peers.into_iter().map(Peer::new).collect()
+    }
+
+    /// Looks up peers for `info_hash` and announces that we are downloading it on `port` to the
+    /// closest nodes found along the way.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<Peer> {
+        let lookup = self.inner.lookup(info_hash, true).await;
+        let announces = lookup.closest.into_iter().map(|(addr, token)| {
+            self.inner.query(
+                addr,
+                Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                },
+            )
+        });
+        join_all(announces).await;
+        lookup.peers.into_iter().map(Peer::new).collect()
+    }
+
+    /// Writes our id and routing table to `DhtConfig::state_file`.
+    pub fn save(&self) -> crate::Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        let state = {
+            let table = self.inner.table.lock().unwrap();
+            DhtState {
+                id: self.inner.id.to_vec(),
+                nodes: encode_compact_nodes(table.nodes()),
+            }
+        };
+        let bytes = serde_bencode::to_bytes(&state).context("encode DHT state")?;
+        std::fs::write(path, bytes).io_context(|| format!("write DHT state to {path:?}"))
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.sweeper.abort();
+    }
+}
+
+impl Inner {
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> Result<Response> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), sender);
+
+        let message = Krpc::Query {
+            transaction: transaction.clone(),
+            id: self.id,
+            query,
+        };
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            self.pending.lock().unwrap().remove(&transaction);
+            return Err(e).context("send KRPC query");
+        }
+
+        match time::timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => {
+                let response = response?;
+                self.table.lock().unwrap().insert(response.id, addr);
+                Ok(response)
+            }
+            _ => {
+                self.pending.lock().unwrap().remove(&transaction);
+                Err(anyhow!("DHT node {addr} did not answer"))
+            }
+        }
+    }
+
+    /// Iterative Kademlia lookup: keep querying the closest nodes we know of that haven't been
+    /// asked yet until the `K` closest have all been asked.
+    async fn lookup(&self, target: NodeId, want_peers: bool) -> Lookup {
+        let mut shortlist: BTreeMap<NodeId, (NodeId, SocketAddrV4)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), (node.id, node.addr)))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered: BTreeMap<NodeId, (SocketAddrV4, Vec<u8>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+
+        loop {
+            let batch: Vec<(NodeId, SocketAddrV4)> = shortlist
+                .values()
+                .take(K)
+                .filter(|(id, _)| !queried.contains(id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            queried.extend(batch.iter().map(|(id, _)| *id));
+            let queries = batch.iter().map(|(_, addr)| {
+                let query = if want_peers {
+                    Query::GetPeers { info_hash: target }
+                } else {
+                    Query::FindNode { target }
+                };
+                self.query(*addr, query)
+            });
+            let responses = join_all(queries).await;
+
+            for ((id, addr), response) in batch.into_iter().zip(responses) {
+                let response = match response {
+                    Ok(response) => response,
+                    Err(_) => {
+                        shortlist.remove(&distance(&id, &target));
+                        self.table.lock().unwrap().remove(&id);
+                        continue;
+                    }
+                };
+                if let Some(token) = response.token {
+                    answered.insert(distance(&response.id, &target), (addr, token));
+                }
+                peers.extend(response.values);
+                for (node_id, node_addr) in response.nodes {
+                    if node_id != self.id {
+                        shortlist
+                            .entry(distance(&node_id, &target))
+                            .or_insert((node_id, node_addr));
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            closest: answered.into_values().take(K).collect(),
+            peers: peers.into_iter().collect(),
+        }
+    }
+
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buffer = vec![0u8; 1 << 16];
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                // ICMP port unreachable shows up as an error on some platforms; keep going.
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Ok(message) = Krpc::decode(&buffer[..length]) else {
+                continue;
+            };
+            match message {
+                Krpc::Query {
+                    transaction,
+                    id,
+                    query,
+                } => {
+                    let reply = match self.handle_query(from, query) {
+                        Ok(response) => Krpc::Response {
+                            transaction,
+                            response,
+                        },
+                        Err((code, message)) => Krpc::Error {
+                            transaction,
+                            code,
+                            message,
+                        },
+                    };
+                    self.table.lock().unwrap().insert(id, from);
+                    let _ = self.socket.send_to(&reply.encode(), from).await;
+                }
+                Krpc::Response {
+                    transaction,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending.lock().unwrap().remove(&transaction) {
+                        let _ = sender.send(Ok(response));
+                    }
+                }
+                Krpc::Error {
+                    transaction,
+                    code,
+                    message,
+                } => {
+                    if let Some(sender) = self.pending.lock().unwrap().remove(&transaction) {
+                        let _ = sender.send(Err(anyhow!("DHT error {code}: {message}")));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn sweep_peers(self: Arc<Self>) {
+        let mut interval = time::interval(PEER_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.peers.lock().unwrap().expire();
+        }
+    }
+
+    fn handle_query(
+        &self,
+        from: SocketAddrV4,
+        query: Query,
+    ) -> std::result::Result<Response, (i64, String)> {
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.closest_nodes(&target);
+            }
+            Query::GetPeers { info_hash } => {
+                response.values = self.peers.lock().unwrap().get(&info_hash);
+                if response.values.is_empty() {
+                    response.nodes = self.closest_nodes(&info_hash);
+                }
+                response.token = Some(self.token_for(from, false));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if token != self.token_for(from, false) && token != self.token_for(from, true) {
+                    return Err((203, String::from("bad token")));
+                }
+                let port = if implied_port { from.port() } else { port };
+                let addr = SocketAddrV4::new(*from.ip(), port);
+                self.peers.lock().unwrap().announce(info_hash, addr);
+            }
+        }
+        Ok(response)
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> Vec<(NodeId, SocketAddrV4)> {
+        self.table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect()
+    }
+
+    /// Tokens are a hash of the querying node's IP and a secret that is rotated periodically.
+    fn token_for(&self, from: SocketAddrV4, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() > SECRET_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated_at = Instant::now();
+        }
+        let secret = if previous {
+            secrets.previous
+        } else {
+            secrets.current
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(from.ip().octets());
+        hasher.finalize()[..8].to_vec()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// `count` nodes on localhost that know about each other.
+    pub(crate) async fn network(count: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            let config = DhtConfig {
+                port: 0,
+                bootstrap_nodes: Vec::new(),
+                state_file: None,
+            };
+            nodes.push(Dht::bind(config).await.unwrap());
+        }
+        let ports: Vec<u16> = nodes
+            .iter()
+            .map(|node| node.local_addr().unwrap().port())
+            .collect();
+        for (i, node) in nodes.iter_mut().enumerate() {
+            node.config.bootstrap_nodes = ports
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, port)| format!("127.0.0.1:{port}"))
+                .collect();
+        }
+        for node in &nodes {
+            node.bootstrap().await.unwrap();
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn announced_peer_is_found_by_another_node() {
+        let nodes = network(3).await;
+        let info_hash = [7; 20];
+        nodes[0].announce(info_hash, 51413).await;
+        let peers = nodes[2].get_peers(info_hash).await;
+        let announcer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 51413);
+        assert!(peers.iter().any(|peer| peer.ip4 == announcer));
+    }
+
+    #[test]
+    fn peer_store_is_capped() {
+        let mut store = PeerStore::default();
+        for port in 0..MAX_STORED_PEERS as u16 + 10 {
+            store.announce([1; 20], SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+        }
+        let peers = store.get(&[1; 20]);
+        assert_eq!(peers.len(), MAX_STORED_PEERS);
+        assert!(!peers.contains(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)));
+
+        for i in 0..MAX_STORED_TORRENTS + 10 {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            store.announce(info_hash, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+        }
+        assert_eq!(store.torrents.len(), MAX_STORED_TORRENTS);
+    }
+}
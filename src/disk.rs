@@ -0,0 +1,477 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::{storage::Storage, torrent::Info, Error};
+
+/// Most bytes that may be waiting to be written; the network waits once the disk is this far
+/// behind.
+const MAX_PENDING: usize = 64 << 20;
+/// Adjacent blocks are merged into a run until it is this long, then it is written out.
+const COALESCE_LIMIT: usize = 1 << 20;
+/// Bytes of recently read pieces kept around for serving them again.
+const READ_CACHE: usize = 16 << 20;
+
+/// Contiguous bytes of one piece that are waiting to be written, or being written.
+struct Run {
+    id: u64,
+    begin: usize,
+    /// Shared with the write job once the run is being written.
+    data: Arc<Vec<u8>>,
+    /// Holds the run's share of `MAX_PENDING` until it is on disk.
+    permit: Option<OwnedSemaphorePermit>,
+    writing: bool,
+}
+
+struct Inner {
+    storage: Arc<dyn Storage>,
+    piece_sizes: Vec<usize>,
+    info: Arc<Info>,
+    /// One permit per worker thread in the blocking pool.
+    workers: Arc<Semaphore>,
+    /// One permit per byte that may be pending.
+    pending_bytes: Arc<Semaphore>,
+    /// Runs by piece index.
+    pending: Mutex<HashMap<usize, Vec<Run>>>,
+    next_run: AtomicU64,
+    /// Signalled whenever a run has been written.
+    written: Notify,
+    /// The first background write that failed; every later call reports it.
+    error: Mutex<Option<String>>,
+    /// Recently read pieces, least recently used first.
+    cache: Mutex<VecDeque<(usize, Arc<Vec<u8>>)>>,
+    cache_pieces: usize,
+}
+
+/// Disk I/O for a download: writes, reads and hashing run on a bounded pool of blocking
+/// threads so they never hold up the async runtime.
+///
+/// Blocks are kept in memory and merged with their neighbours until a run is long enough or
+/// its piece is verified, so the storage sees few large writes instead of many 16 KiB ones.
+/// When too much is waiting, the oldest runs are written out early and `write_block` waits
+/// for them, which stops the caller from reading more off the network.
+#[derive(Clone)]
+pub struct DiskIo {
+    inner: Arc<Inner>,
+}
+
+impl DiskIo {
+    pub fn new(storage: Arc<dyn Storage>, info: &Info) -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let npieces = info.num_pieces();
+        Self {
+            inner: Arc::new(Inner {
+                storage,
+                piece_sizes: (0..npieces).map(|i| info.piece_size(i)).collect(),
+                info: Arc::new(info.clone()),
+                workers: Arc::new(Semaphore::new(threads)),
+                pending_bytes: Arc::new(Semaphore::new(MAX_PENDING)),
+                pending: Mutex::new(HashMap::new()),
+                next_run: AtomicU64::new(0),
+                written: Notify::new(),
+                error: Mutex::new(None),
+                cache: Mutex::new(VecDeque::new()),
+                cache_pieces: (READ_CACHE / info.plength.max(1)).max(1),
+            }),
+        }
+    }
+
+    /// The storage everything ends up in. Reading it directly skips blocks that haven't been
+    /// written yet; call `flush` first.
+    pub fn storage(&self) -> &dyn Storage {
+        self.inner.storage.as_ref()
+    }
+
+    /// Runs `job` on the blocking pool once a worker is free.
+    async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Storage) -> crate::Result<T> + Send + 'static,
+    {
+        let permit = self
+            .inner
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the worker semaphore is never closed");
+        let inner = self.inner.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job(inner.storage.as_ref())
+        })
+        .await
+        .context("disk worker panicked")?;
+        Ok(result?)
+    }
+
+    fn check_error(&self) -> crate::Result<()> {
+        match &*self.inner.error.lock().unwrap() {
+            Some(e) => Err(Error::Other(format!("an earlier disk write failed: {e}"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues a block for writing. Waits while too much is already queued.
+    pub async fn write_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        block: &[u8],
+    ) -> crate::Result<()> {
+        self.check_error()?;
+        let Some(&piece_size) = self.inner.piece_sizes.get(piece_index) else {
+            return Err(Error::Other(format!("piece {piece_index} is out of range")));
+        };
+        if begin + block.len() > piece_size {
+            return Err(Error::Other(format!(
+                "block at {begin} runs past the end of piece {piece_index}"
+            )));
+        }
+        let needed = block.len().clamp(1, MAX_PENDING) as u32;
+        let pending_bytes = self.inner.pending_bytes.clone();
+        let permit = match pending_bytes.clone().try_acquire_many_owned(needed) {
+            Ok(permit) => permit,
+            Err(_) => {
+                // Short runs wait for their piece to be verified, and the pieces may all be
+                // waiting on blocks that can't be queued until permits are released. Write the
+                // oldest runs out early so that waiting here always ends.
+                self.dispatch_oldest(needed as usize);
+                pending_bytes
+                    .acquire_many_owned(needed)
+                    .await
+                    .expect("the pending semaphore is never closed")
+            }
+        };
+        self.invalidate(piece_index);
+
+        let mut pending = self.inner.pending.lock().unwrap();
+        let runs = pending.entry(piece_index).or_default();
+        let end = begin + block.len();
+        let before = runs
+            .iter()
+            .position(|run| !run.writing && run.begin + run.data.len() == begin);
+        let index = match before {
+            Some(index) => {
+                let run = &mut runs[index];
+                Arc::make_mut(&mut run.data).extend_from_slice(block);
+                merge(&mut run.permit, permit);
+                index
+            }
+            None => {
+                runs.push(Run {
+                    id: self.inner.next_run.fetch_add(1, Ordering::Relaxed),
+                    begin,
+                    data: Arc::new(block.to_vec()),
+                    permit: Some(permit),
+                    writing: false,
+                });
+                runs.len() - 1
+            }
+        };
+        // The block may also close the gap to the run after it.
+        if let Some(after) = runs.iter().position(|run| !run.writing && run.begin == end) {
+            let mut next = runs.remove(after);
+            let index = if after < index { index - 1 } else { index };
+            let run = &mut runs[index];
+            Arc::make_mut(&mut run.data).extend_from_slice(&next.data);
+            if let Some(permit) = next.permit.take() {
+                merge(&mut run.permit, permit);
+            }
+        }
+        let long_runs: Vec<u64> = runs
+            .iter()
+            .filter(|run| !run.writing && run.data.len() >= COALESCE_LIMIT)
+            .map(|run| run.id)
+            .collect();
+        drop(pending);
+        for id in long_runs {
+            self.dispatch(piece_index, id);
+        }
+        Ok(())
+    }
+
+    /// Starts writing one run in the background.
+    fn dispatch(&self, piece_index: usize, id: u64) {
+        let (begin, data) = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            let Some(run) = pending
+                .get_mut(&piece_index)
+                .and_then(|runs| runs.iter_mut().find(|run| run.id == id))
+            else {
+                return;
+            };
+            run.writing = true;
+            // The run stays queued so reads see it until it's on disk.
+            (run.begin, run.data.clone())
+        };
+        let disk = self.clone();
+        tokio::spawn(async move {
+            let result = disk
+                .run(move |storage| storage.write_block(piece_index, begin, &data))
+                .await;
+            if let Err(e) = result {
+                disk.inner
+                    .error
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| format!("{e:#}"));
+            }
+            let mut pending = disk.inner.pending.lock().unwrap();
+            if let Some(runs) = pending.get_mut(&piece_index) {
+                runs.retain(|run| run.id != id);
+                if runs.is_empty() {
+                    pending.remove(&piece_index);
+                }
+            }
+            drop(pending);
+            disk.inner.written.notify_waiters();
+        });
+    }
+
+    /// Starts writing the runs that have been queued the longest, at least `bytes` of them.
+    fn dispatch_oldest(&self, bytes: usize) {
+        let mut idle: Vec<(u64, usize, usize)> = self
+            .inner
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(&piece_index, runs)| {
+                runs.iter()
+                    .filter(|run| !run.writing)
+                    .map(move |run| (run.id, piece_index, run.data.len()))
+            })
+            .collect();
+        idle.sort_unstable();
+        let mut dispatched = 0;
+        for (id, piece_index, len) in idle {
+            if dispatched >= bytes {
+                break;
+            }
+            self.dispatch(piece_index, id);
+            dispatched += len;
+        }
+    }
+
+    fn dispatch_piece(&self, piece_index: usize) {
+        let ids: Vec<u64> = self
+            .inner
+            .pending
+            .lock()
+            .unwrap()
+            .get(&piece_index)
+            .map(|runs| {
+                runs.iter()
+                    .filter(|run| !run.writing)
+                    .map(|run| run.id)
+                    .collect()
+            })
+            .unwrap_or_default();
+        for id in ids {
+            self.dispatch(piece_index, id);
+        }
+    }
+
+    /// Checks a downloaded piece against its hash on the blocking pool. A good piece's queued
+    /// blocks are written out right away; a bad piece's are dropped.
+    pub async fn verify_piece(&self, piece_index: usize, data: Vec<u8>) -> crate::Result<bool> {
+        if piece_index >= self.inner.piece_sizes.len() {
+            return Err(Error::Other(format!("piece {piece_index} is out of range")));
+        }
+        let info = self.inner.info.clone();
+        let good = self
+            .run(move |_| Ok(info.verify_piece(piece_index, &data)))
+            .await?;
+        if good {
+            self.dispatch_piece(piece_index);
+        } else {
+            let mut pending = self.inner.pending.lock().unwrap();
+            if let Some(runs) = pending.get_mut(&piece_index) {
+                runs.retain(|run| run.writing);
+            }
+        }
+        Ok(good)
+    }
+
+    /// Finds the blocks of a piece that failed its hash check, on the blocking pool; see
+    /// `Info::bad_blocks`.
+    pub async fn bad_blocks(
+        &self,
+        piece_index: usize,
+        data: Vec<u8>,
+        leaves: Vec<[u8; 32]>,
+    ) -> crate::Result<Option<Vec<usize>>> {
+        let info = self.inner.info.clone();
+        Ok(self
+            .run(move |_| Ok(info.bad_blocks(piece_index, &data, &leaves)))
+            .await?)
+    }
+
+    /// Reads a whole piece, including blocks that are still queued. Pieces are cached, so
+    /// handing the same piece to several readers only reads it once.
+    pub async fn read_piece(&self, piece_index: usize) -> crate::Result<Option<Arc<Vec<u8>>>> {
+        let Some(&piece_size) = self.inner.piece_sizes.get(piece_index) else {
+            return Err(Error::Other(format!("piece {piece_index} is out of range")));
+        };
+        {
+            let mut cache = self.inner.cache.lock().unwrap();
+            if let Some(position) = cache.iter().position(|(index, _)| *index == piece_index) {
+                let entry = cache.remove(position).expect("position is in range");
+                let piece = entry.1.clone();
+                cache.push_back(entry);
+                return Ok(Some(piece));
+            }
+        }
+        // Taken before reading, so a run that finishes writing meanwhile is still seen.
+        let queued: Vec<(usize, Arc<Vec<u8>>)> = self
+            .inner
+            .pending
+            .lock()
+            .unwrap()
+            .get(&piece_index)
+            .map(|runs| {
+                runs.iter()
+                    .map(|run| (run.begin, run.data.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let (stored, mut piece) = self
+            .run(move |storage| {
+                let mut piece = vec![0; piece_size];
+                Ok((storage.read_block(piece_index, 0, &mut piece)?, piece))
+            })
+            .await?;
+        // Anything still queued is newer than what's on disk.
+        let mut covered = vec![stored; piece_size];
+        for (begin, data) in queued {
+            piece[begin..begin + data.len()].copy_from_slice(&data);
+            covered[begin..begin + data.len()].fill(true);
+        }
+        if !covered.iter().all(|&covered| covered) {
+            return Ok(None);
+        }
+        let piece = Arc::new(piece);
+        let mut cache = self.inner.cache.lock().unwrap();
+        cache.push_back((piece_index, piece.clone()));
+        if cache.len() > self.inner.cache_pieces {
+            cache.pop_front();
+        }
+        Ok(Some(piece))
+    }
+
+    /// Reads `len` bytes of a piece, starting `begin` bytes in.
+    pub async fn read_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        len: usize,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        let Some(piece) = self.read_piece(piece_index).await? else {
+            return Ok(None);
+        };
+        Ok(piece.get(begin..begin + len).map(<[u8]>::to_vec))
+    }
+
+    fn invalidate(&self, piece_index: usize) {
+        self.inner
+            .cache
+            .lock()
+            .unwrap()
+            .retain(|(index, _)| *index != piece_index);
+    }
+
+    /// Writes everything that is queued and waits until the storage has it durably.
+    pub async fn flush(&self) -> crate::Result<()> {
+        loop {
+            let written = self.inner.written.notified();
+            let queued: Vec<(usize, u64)> = {
+                let pending = self.inner.pending.lock().unwrap();
+                if pending.is_empty() {
+                    break;
+                }
+                pending
+                    .iter()
+                    .flat_map(|(&piece_index, runs)| {
+                        runs.iter()
+                            .filter(|run| !run.writing)
+                            .map(move |run| (piece_index, run.id))
+                    })
+                    .collect()
+            };
+            for (piece_index, id) in queued {
+                self.dispatch(piece_index, id);
+            }
+            written.await;
+        }
+        self.check_error()?;
+        Ok(self.run(|storage| storage.flush()).await?)
+    }
+}
+
+fn merge(into: &mut Option<OwnedSemaphorePermit>, permit: OwnedSemaphorePermit) {
+    match into {
+        Some(existing) => existing.merge(permit),
+        None => *into = Some(permit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        hashes::Hashes,
+        storage::{FileStorage, Preallocation},
+        torrent::Keys,
+    };
+
+    /// Pieces that each have a run shorter than `COALESCE_LIMIT` queued, and that can't be
+    /// finished until more blocks are queued, must not hold up `write_block` for good.
+    #[tokio::test]
+    async fn short_runs_of_unfinished_pieces_do_not_block_writes() {
+        const PLENGTH: usize = 4 << 20;
+        const BLOCK: usize = 1 << 14;
+        let run = COALESCE_LIMIT - BLOCK;
+        let npieces = MAX_PENDING / run + 2;
+        let info = Info {
+            name: "stuck.bin".to_string(),
+            plength: PLENGTH,
+            pieces: Hashes(vec![[0; 20]; npieces]),
+            private: None,
+            source: None,
+            meta_version: None,
+            file_tree: None,
+            keys: Some(Keys::SingleFile {
+                length: npieces * PLENGTH,
+            }),
+            v2_pieces: Vec::new(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(&info, dir.path(), Vec::new()).unwrap();
+        storage.create_files(Preallocation::Sparse).unwrap();
+        let disk = DiskIo::new(Arc::new(storage), &info);
+
+        let block = vec![1; BLOCK];
+        let writes = async {
+            for piece_index in 0..npieces {
+                for begin in (0..run).step_by(BLOCK) {
+                    disk.write_block(piece_index, begin, &block).await.unwrap();
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), writes)
+            .await
+            .expect("write_block waited on permits that are never released");
+        disk.flush().await.unwrap();
+    }
+}
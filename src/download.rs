@@ -0,0 +1,497 @@
+use anyhow::{Context, Result};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    activepeer::ActivePeer,
+    dht::Dht,
+    disk::DiskIo,
+    lsd::Lsd,
+    mse::EncryptionPolicy,
+    peers::{connect_to_peer, ConnectOptions, Peers, WorkQueue},
+    priority::{self, FilePriority},
+    resume::ResumeData,
+    storage::{
+        self, DownloadBuffer, FileStorage, MmapStorage, Preallocation, Storage, StorageKind,
+    },
+    torrent::Torrent,
+    utp::UtpSocket,
+    webseed::{WebSeed, WebSeedKind},
+    Error,
+};
+
+/// How often a download flushes its storage and saves its resume data.
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+/// How often `Download::wait_for_piece` looks at the buffer.
+const PIECE_POLL: Duration = Duration::from_millis(50);
+/// How many pieces past the playback position have deadlines in sequential mode.
+const DEADLINE_WINDOW: usize = 8;
+/// The piece at the playback position should arrive within this long; each piece after it in
+/// the window gets the same again on top.
+const PIECE_DEADLINE: Duration = Duration::from_secs(2);
+/// Outgoing peer connections a download opens when `DownloadOptions::max_peers` isn't set.
+pub const DEFAULT_MAX_PEERS: usize = 30;
+
+/// Settings for `Download::start`.
+#[derive(Clone, Default)]
+pub struct DownloadOptions {
+    /// Directory the torrent's files are written to.
+    pub dir: PathBuf,
+    /// Where progress is kept between runs; defaults to the torrent's name with `.resume`
+    /// appended, inside `dir`.
+    pub resume_file: Option<PathBuf>,
+    /// Priority of each file, in the order of `Info::files`; everything is wanted when empty.
+    pub file_priorities: Vec<FilePriority>,
+    pub encryption: EncryptionPolicy,
+    /// Race uTP against TCP when connecting to peers.
+    pub utp: bool,
+    /// Hand pieces out in order instead of by priority, for playing a file while it downloads.
+    pub sequential: bool,
+    /// Which built-in backend keeps the data in `dir`.
+    pub storage: StorageKind,
+    /// How the built-in backends size files when they create them.
+    pub preallocation: Preallocation,
+    /// Keeps the data somewhere else entirely; `dir` then only holds the resume file.
+    pub custom_storage: Option<Arc<dyn Storage>>,
+    /// Caps peer connections across every download sharing it; each connection holds a
+    /// permit for as long as it lasts.
+    pub connection_limit: Option<Arc<Semaphore>>,
+    /// Most outgoing peer connections this download opens; `DEFAULT_MAX_PEERS` when unset.
+    pub max_peers: Option<usize>,
+}
+
+/// A running download: peers and web seeds filling a shared buffer from a shared work queue.
+pub struct Download {
+    pub torrent: Arc<Torrent>,
+    pub(crate) buffer: Arc<Mutex<DownloadBuffer>>,
+    pub(crate) work_queue: Arc<WorkQueue>,
+    disk: DiskIo,
+    dir: PathBuf,
+    resume_file: PathBuf,
+    wanted: Vec<usize>,
+    skip: Vec<bool>,
+    peers: Arc<Peers>,
+    workers: Vec<JoinHandle<()>>,
+    /// Peers that connected to us, being served pieces.
+    uploads: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    saver: Option<JoinHandle<()>>,
+}
+
+/// The wanted pieces that aren't in `have` yet, which is what a download queues.
+pub(crate) fn missing_pieces(piece_priorities: &[FilePriority], have: &[bool]) -> Vec<usize> {
+    (0..piece_priorities.len())
+        .filter(|&i| piece_priorities[i] != FilePriority::Skip && !have[i])
+        .collect()
+}
+
+impl Download {
+    /// Works out what is still missing, finds peers and sets workers loose on it.
+    pub async fn start(
+        torrent: Torrent,
+        options: DownloadOptions,
+        dht: Option<&Dht>,
+        lsd: Option<&Lsd>,
+    ) -> crate::Result<Self> {
+        Self::start_with_utp(torrent, options, dht, lsd, None).await
+    }
+
+    /// `start`, connecting over uTP from `utp` rather than a socket of its own, so peers see
+    /// the port a session listens on.
+    pub(crate) async fn start_with_utp(
+        torrent: Torrent,
+        options: DownloadOptions,
+        dht: Option<&Dht>,
+        lsd: Option<&Lsd>,
+        utp: Option<Arc<UtpSocket>>,
+    ) -> crate::Result<Self> {
+        let torrent = Arc::new(torrent);
+        let info = &torrent.torrent_file.info;
+        let dir = options.dir.clone();
+        let (dht, lsd) = if info.is_private() {
+            (None, None)
+        } else {
+            (dht, lsd)
+        };
+
+        let resume_file = options
+            .resume_file
+            .clone()
+            .unwrap_or_else(|| dir.join(format!("{}.resume", info.name)));
+        // Checked before the storage is opened, which may create or resize files.
+        let resume_data = ResumeData::load(&resume_file)
+            .filter(|data| data.is_valid(torrent.info_hash, info, &dir));
+        let file_priorities = if options.file_priorities.is_empty() {
+            vec![FilePriority::Normal; info.files().len()]
+        } else {
+            options.file_priorities.clone()
+        };
+        let skip: Vec<bool> = file_priorities
+            .iter()
+            .map(|&priority| priority == FilePriority::Skip)
+            .collect();
+        let piece_priorities = priority::piece_priorities(info, &file_priorities);
+        let wanted: Vec<usize> = (0..piece_priorities.len())
+            .filter(|&i| piece_priorities[i] != FilePriority::Skip)
+            .collect();
+
+        // Before anything is created, so a full volume is reported rather than hit.
+        if options.custom_storage.is_none() {
+            storage::check_disk_space(info, &dir, &skip)?;
+        }
+        let mut file_storage = None;
+        let storage: Arc<dyn Storage> = match (&options.custom_storage, options.storage) {
+            (Some(storage), _) => storage.clone(),
+            (None, StorageKind::File) => {
+                let storage = Arc::new(FileStorage::new(info, &dir, skip.clone())?);
+                file_storage = Some(storage.clone());
+                storage
+            }
+            (None, StorageKind::Mmap) => {
+                Arc::new(MmapStorage::new(info, &dir, &skip, options.preallocation)?)
+            }
+        };
+        let have = match &resume_data {
+            Some(data) => {
+                log::info!("Resuming from {}", resume_file.display());
+                data.have(info)
+            }
+            None => {
+                let torrent = torrent.clone();
+                let storage = storage.clone();
+                tokio::task::spawn_blocking(move || {
+                    storage::recheck(&torrent.torrent_file.info, storage.as_ref())
+                })
+                .await
+                .context("recheck")??
+                .have
+            }
+        };
+
+        let disk = DiskIo::new(storage, info);
+        let mut download_buffer = DownloadBuffer::new(disk.clone(), have);
+        if let Some(data) = &resume_data {
+            data.restore_partial(info, &mut download_buffer);
+        }
+        let missing = missing_pieces(&piece_priorities, &download_buffer.have);
+        let work_queue = WorkQueue::with_priorities(missing.clone(), &piece_priorities);
+        if options.sequential {
+            work_queue.set_position(Some(0));
+        }
+        let mut download = Self {
+            torrent: torrent.clone(),
+            buffer: Arc::new(Mutex::new(download_buffer)),
+            work_queue: Arc::new(work_queue),
+            disk,
+            dir: dir.clone(),
+            resume_file,
+            wanted,
+            skip,
+            peers: Arc::new(Peers(Vec::new())),
+            workers: Vec::new(),
+            uploads: std::sync::Mutex::new(Vec::new()),
+            saver: None,
+        };
+        if missing.is_empty() {
+            log::info!(
+                "All {} wanted pieces are already on disk",
+                download.wanted.len()
+            );
+            return Ok(download);
+        }
+        log::info!(
+            "{} of {} wanted pieces left to download",
+            missing.len(),
+            download.wanted.len()
+        );
+        if let Some(file_storage) = &file_storage {
+            file_storage.create_files(options.preallocation)?;
+        }
+        let known_peers = resume_data
+            .as_ref()
+            .map(|data| data.peers().clone())
+            .unwrap_or(Peers(Vec::new()));
+
+        let mut web_seeds: Vec<WebSeed> = torrent
+            .torrent_file
+            .url_list
+            .as_ref()
+            .map(|url_list| url_list.urls())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::GetRight))
+            .collect();
+        web_seeds.extend(
+            torrent
+                .torrent_file
+                .httpseeds
+                .iter()
+                .flatten()
+                .map(|url| WebSeed::new(url.clone(), WebSeedKind::Hoffman)),
+        );
+        let mut peers = match torrent.discover_peers(dht, lsd).await {
+            Ok(peers) => peers,
+            // Web seeds and peers from the last run can carry the download on their own.
+            Err(e) if !web_seeds.is_empty() || !known_peers.0.is_empty() => {
+                log::warn!("{e:#}");
+                Peers(Vec::new())
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(dht) = dht {
+            dht.save()?;
+        }
+        for peer in known_peers.0 {
+            if !peers.0.iter().any(|known| known.ip4 == peer.ip4) {
+                peers.0.push(peer);
+            }
+        }
+        download.peers = Arc::new(peers);
+
+        let utp = match utp {
+            _ if !options.utp => None,
+            Some(utp) => Some(utp),
+            None => Some(Arc::new(
+                UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?,
+            )),
+        };
+        let connect_options = ConnectOptions {
+            encryption: options.encryption,
+            utp,
+        };
+
+        // One worker per connection. Workers take turns picking the next peer to try, so
+        // they end up connected to different ones.
+        let num_workers = download
+            .peers
+            .0
+            .len()
+            .min(options.max_peers.unwrap_or(DEFAULT_MAX_PEERS));
+        let next_peer = Arc::new(AtomicUsize::new(0));
+        for _ in 0..num_workers {
+            let peers = download.peers.clone();
+            let next_peer = next_peer.clone();
+            let file_ref = torrent.clone();
+            let work_queue_ref = download.work_queue.clone();
+            let buffer_ref = download.buffer.clone();
+            let connect_options = connect_options.clone();
+            let connection_limit = options.connection_limit.clone();
+            download.workers.push(tokio::spawn(async move {
+                let _permit = match connection_limit {
+                    Some(limit) => Some(limit.acquire_owned().await.expect("never closed")),
+                    None => None,
+                };
+
+                // Move on to the next peer whenever one fails us.
+                while let Some(recieved_peer) =
+                    peers.0.get(next_peer.fetch_add(1, Ordering::Relaxed))
+                {
+                    let Some(mut peer) =
+                        connect_to_peer(recieved_peer, file_ref.info_hash, &connect_options).await
+                    else {
+                        continue;
+                    };
+                    match peer
+                        .start_exchanging_messages(&file_ref, &work_queue_ref, buffer_ref.clone())
+                        .await
+                    {
+                        Ok(()) => return,
+                        Err(e) => log::debug!("Dropped {}: {e:#}", peer.addr),
+                    }
+                }
+            }));
+        }
+        if num_workers == 0 && web_seeds.is_empty() {
+            log::warn!("Could not connect to any peer");
+        }
+
+        for mut web_seed in web_seeds {
+            let file_ref = torrent.clone();
+            let work_queue_ref = download.work_queue.clone();
+            let buffer_ref = download.buffer.clone();
+            download.workers.push(tokio::spawn(async move {
+                web_seed
+                    .start_downloading(&file_ref, &work_queue_ref, buffer_ref)
+                    .await;
+            }));
+        }
+
+        download.saver = Some({
+            let torrent = torrent.clone();
+            let buffer = download.buffer.clone();
+            let peers = download.peers.clone();
+            let resume_file = download.resume_file.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(RESUME_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = save_resume(&torrent, &dir, &buffer, &peers, &resume_file).await
+                    {
+                        log::warn!("Could not save resume data: {e:#}");
+                    }
+                }
+            })
+        });
+
+        Ok(download)
+    }
+
+    /// Uploads to a peer that connected to us, for as long as it stays connected. The permit,
+    /// if any, is given back when it disconnects.
+    pub(crate) fn serve_peer(&self, mut peer: ActivePeer, permit: Option<OwnedSemaphorePermit>) {
+        let torrent = self.torrent.clone();
+        let buffer = self.buffer.clone();
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|upload| !upload.is_finished());
+        uploads.push(tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = peer.serve(&torrent, buffer).await {
+                log::debug!("Stopped serving {}: {e:#}", peer.addr);
+            }
+        }));
+    }
+
+    /// Whether every peer and web seed the download started with has stopped.
+    pub fn workers_done(&self) -> bool {
+        self.workers.iter().all(|worker| worker.is_finished())
+    }
+
+    /// How many of the wanted pieces have been downloaded, and how many are wanted.
+    pub async fn progress(&self) -> (usize, usize) {
+        let buffer = self.buffer.lock().await;
+        let have = self.wanted.iter().filter(|&&i| buffer.have[i]).count();
+        (have, self.wanted.len())
+    }
+
+    /// Reads and writes the download's data.
+    pub(crate) fn disk(&self) -> &DiskIo {
+        &self.disk
+    }
+
+    /// Flushes the storage and saves the resume data.
+    pub(crate) async fn save(&self) -> Result<()> {
+        save_resume(
+            &self.torrent,
+            &self.dir,
+            &self.buffer,
+            &self.peers,
+            &self.resume_file,
+        )
+        .await
+    }
+
+    /// Moves the playback position: from now on pieces are handed out in order from
+    /// `piece_index`.
+    pub(crate) fn set_playhead(&self, piece_index: usize) {
+        self.work_queue.set_position(Some(piece_index));
+    }
+
+    /// Waits until a piece is verified, so it can be read back through `disk`.
+    ///
+    /// The pieces just after it get deadlines; one that misses its deadline is queued again so
+    /// the next free worker fetches it too, rather than waiting on a slow peer.
+    pub(crate) async fn wait_for_piece(&self, piece_index: usize) -> Result<()> {
+        let info = &self.torrent.torrent_file.info;
+        let window_end = (piece_index + DEADLINE_WINDOW).min(info.num_pieces());
+        let started = Instant::now();
+        let mut requeued = vec![false; window_end - piece_index];
+        loop {
+            {
+                let buffer = self.buffer.lock().await;
+                if buffer.have[piece_index] {
+                    return Ok(());
+                }
+                for (offset, index) in (piece_index..window_end).enumerate() {
+                    let deadline = PIECE_DEADLINE * (offset as u32 + 1);
+                    if !buffer.have[index] && !requeued[offset] && started.elapsed() > deadline {
+                        requeued[offset] = true;
+                        self.work_queue.return_piece(index).await;
+                    }
+                }
+            }
+            if self.workers_done() {
+                anyhow::bail!("every peer and web seed gave up before the download finished");
+            }
+            time::sleep(PIECE_POLL).await;
+        }
+    }
+
+    /// Waits for the workers to finish, or for `shutdown`, and leaves the download in a state
+    /// it can be resumed from.
+    pub async fn wait(mut self, shutdown: impl Future<Output = ()>) -> crate::Result<()> {
+        let workers = std::mem::take(&mut self.workers);
+        let all_workers = async {
+            for worker in workers {
+                worker.await?;
+            }
+            anyhow::Ok(())
+        };
+        tokio::select! {
+            result = all_workers => result?,
+            _ = shutdown => {
+                self.shutdown().await?;
+                log::info!("Interrupted, progress saved to {}", self.resume_file.display());
+                return Ok(());
+            }
+        }
+        self.shutdown().await?;
+        if !self.is_complete().await {
+            return Err(Error::Other(
+                "every peer and web seed gave up before the download finished".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Disconnects from every peer, stops saving in the background and writes everything out
+    /// one last time. Once the download is complete its resume data is no longer needed and
+    /// is removed.
+    pub async fn shutdown(&self) -> crate::Result<()> {
+        let uploads = std::mem::take(&mut *self.uploads.lock().unwrap());
+        for task in self.workers.iter().chain(&uploads).chain(&self.saver) {
+            task.abort();
+        }
+        self.save().await?;
+        if self.is_complete().await {
+            let _ = std::fs::remove_file(&self.resume_file);
+            if !self.skip.contains(&true) {
+                let info = &self.torrent.torrent_file.info;
+                let _ = std::fs::remove_file(storage::partfile_path(info, &self.dir));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether every wanted piece has been downloaded.
+    pub async fn is_complete(&self) -> bool {
+        let buffer = self.buffer.lock().await;
+        self.wanted.iter().all(|&i| buffer.have[i])
+    }
+}
+
+/// Flushes the storage and records the download's progress in `resume_file`.
+async fn save_resume(
+    torrent: &Torrent,
+    dir: &Path,
+    buffer: &Mutex<DownloadBuffer>,
+    peers: &Peers,
+    resume_file: &Path,
+) -> Result<()> {
+    let buffer = buffer.lock().await;
+    let info = &torrent.torrent_file.info;
+    buffer.disk.flush().await?;
+    ResumeData::new(torrent.info_hash, info, dir, &buffer, peers)?.save(resume_file)
+}
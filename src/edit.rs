@@ -0,0 +1,250 @@
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::{decoder, value::Value, Error, Result};
+
+/// Changes to make to an existing .torrent. `None` leaves a field alone; an empty value
+/// removes it.
+///
+/// The file is edited in place rather than re-encoded, so every entry that isn't changed
+/// keeps its exact bytes. In particular, as long as nothing inside `info` changes, the info
+/// hash stays the same and the edited torrent still joins the same swarm.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentEdit {
+    pub announce: Option<String>,
+    /// Tiers of tracker URLs for `announce-list`.
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub comment: Option<String>,
+    /// URLs for `url-list` (BEP 19).
+    pub web_seeds: Option<Vec<String>>,
+    /// Inside `info`, so it changes the info hash.
+    pub private: Option<bool>,
+    /// Inside `info`, so it changes the info hash.
+    pub source: Option<String>,
+}
+
+/// The info hashes of a torrent, computed over the bytes of its info dictionary as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoHashes {
+    /// SHA-1, for torrents with v1 piece hashes.
+    pub v1: Option<[u8; 20]>,
+    /// SHA-256, for torrents with a v2 file tree.
+    pub v2: Option<[u8; 32]>,
+}
+
+impl InfoHashes {
+    pub fn of(torrent: &[u8]) -> Result<Self> {
+        let info = info_bytes(torrent)?;
+        let keys: Vec<Vec<u8>> = decoder::dict_entries(info)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let has = |key: &[u8]| keys.iter().any(|k| k == key);
+        Ok(Self {
+            v1: has(b"pieces").then(|| Sha1::digest(info).into()),
+            v2: has(b"file tree").then(|| Sha256::digest(info).into()),
+        })
+    }
+}
+
+impl TorrentEdit {
+    /// Whether any field inside `info` is being changed.
+    pub fn changes_info(&self) -> bool {
+        self.private.is_some() || self.source.is_some()
+    }
+
+    /// Applies the changes to the bytes of a .torrent file.
+    pub fn apply(&self, torrent: &[u8]) -> Result<Vec<u8>> {
+        let mut changes = Vec::new();
+        if let Some(announce) = &self.announce {
+            changes.push((b"announce".to_vec(), string(announce)));
+        }
+        if let Some(tiers) = &self.announce_list {
+            let tiers: Vec<Value> = tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .map(|tier| Value::List(tier.iter().map(|url| bytes(url)).collect()))
+                .collect();
+            let list = (!tiers.is_empty()).then(|| Value::List(tiers).encode());
+            changes.push((b"announce-list".to_vec(), list));
+        }
+        if let Some(comment) = &self.comment {
+            changes.push((b"comment".to_vec(), string(comment)));
+        }
+        if let Some(urls) = &self.web_seeds {
+            let url_list = match urls.as_slice() {
+                [] => None,
+                [url] => Some(bytes(url).encode()),
+                urls => Some(Value::List(urls.iter().map(|url| bytes(url)).collect()).encode()),
+            };
+            changes.push((b"url-list".to_vec(), url_list));
+        }
+
+        if self.changes_info() {
+            let mut info_changes = Vec::new();
+            if let Some(private) = self.private {
+                // BEP 27 treats a missing key like 0.
+                info_changes.push((b"private".to_vec(), private.then(|| Value::Int(1).encode())));
+            }
+            if let Some(source) = &self.source {
+                info_changes.push((b"source".to_vec(), string(source)));
+            }
+            let info = edit_dict(info_bytes(torrent)?, &info_changes)?;
+            changes.push((b"info".to_vec(), Some(info)));
+        }
+        edit_dict(torrent, &changes)
+    }
+}
+
+fn bytes(s: &str) -> Value {
+    Value::Bytes(s.as_bytes().to_vec())
+}
+
+/// An encoded string, or `None` to remove the entry if it's empty.
+fn string(s: &str) -> Option<Vec<u8>> {
+    (!s.is_empty()).then(|| bytes(s).encode())
+}
+
+/// The raw bytes of the `info` entry of a .torrent file.
+pub(crate) fn info_bytes(torrent: &[u8]) -> Result<&[u8]> {
+    let entries = decoder::dict_entries(torrent)?;
+    let Some((_, span)) = entries.into_iter().find(|(key, _)| key == b"info") else {
+        return Err(Error::InvalidTorrent("no info dictionary".into()));
+    };
+    Ok(&torrent[span])
+}
+
+/// Rewrites a bencoded dictionary with some entries replaced, added or removed (`None`),
+/// copying all other entries byte for byte. New keys go where they sort among the existing
+/// ones.
+fn edit_dict(dict: &[u8], changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<Vec<u8>> {
+    let entries = decoder::dict_entries(dict)?;
+    let mut additions: Vec<_> = changes
+        .iter()
+        .filter_map(|(key, value)| Some((key, value.as_ref()?)))
+        .filter(|(key, _)| entries.iter().all(|(k, _)| k != *key))
+        .collect();
+    additions.sort();
+    let mut additions = additions.into_iter().peekable();
+
+    let mut out = vec![b'd'];
+    let write = |out: &mut Vec<u8>, key: &[u8], value: &[u8]| {
+        out.extend(key.len().to_string().as_bytes());
+        out.push(b':');
+        out.extend(key);
+        out.extend(value);
+    };
+    for (key, span) in &entries {
+        while let Some((new_key, value)) = additions.next_if(|(new_key, _)| *new_key < key) {
+            write(&mut out, new_key, value);
+        }
+        match changes.iter().find(|(k, _)| k == key) {
+            Some((_, Some(value))) => write(&mut out, key, value),
+            Some((_, None)) => {}
+            None => write(&mut out, key, &dict[span.clone()]),
+        }
+    }
+    for (key, value) in additions {
+        write(&mut out, key, value);
+    }
+    out.push(b'e');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::TorrentFile;
+
+    /// A torrent with entries this client doesn't model and numbers that aren't canonical, which
+    /// re-encoding would change.
+    const TORRENT: &[u8] = b"d8:announce14:http://a/annou7:comment3:old13:creation datei0042e4:infod6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:x-fieldi007ee7:x-extrali1ei-0eee";
+
+    /// The bytes of entry `key` in the dictionary `dict`.
+    fn entry<'a>(dict: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+        let entries = decoder::dict_entries(dict).unwrap();
+        let (_, span) = entries.into_iter().find(|(k, _)| k == key)?;
+        Some(&dict[span])
+    }
+
+    fn keys(dict: &[u8]) -> Vec<Vec<u8>> {
+        decoder::dict_entries(dict)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn edits_outside_info_keep_everything_else_byte_for_byte() {
+        let edit = TorrentEdit {
+            announce: Some("http://b/announce".into()),
+            announce_list: Some(vec![vec!["http://b/announce".into()], vec![]]),
+            comment: Some(String::new()),
+            web_seeds: Some(vec!["http://seed/a".into()]),
+            ..Default::default()
+        };
+        assert!(!edit.changes_info());
+        let edited = edit.apply(TORRENT).unwrap();
+
+        assert_eq!(info_bytes(&edited).unwrap(), info_bytes(TORRENT).unwrap());
+        assert_eq!(
+            InfoHashes::of(&edited).unwrap(),
+            InfoHashes::of(TORRENT).unwrap()
+        );
+        for key in [&b"creation date"[..], b"x-extra"] {
+            assert_eq!(entry(&edited, key), entry(TORRENT, key));
+        }
+        assert_eq!(
+            entry(&edited, b"announce"),
+            Some(&b"17:http://b/announce"[..])
+        );
+        assert_eq!(
+            entry(&edited, b"announce-list"),
+            Some(&b"ll17:http://b/announceee"[..])
+        );
+        assert_eq!(entry(&edited, b"url-list"), Some(&b"13:http://seed/a"[..]));
+        assert_eq!(entry(&edited, b"comment"), None);
+        // New keys go where they sort.
+        let keys = keys(&edited);
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+
+        // Nothing to change gives back the same bytes.
+        assert_eq!(TorrentEdit::default().apply(TORRENT).unwrap(), TORRENT);
+    }
+
+    #[test]
+    fn private_toggle_changes_only_that_entry_of_info() {
+        let private = TorrentEdit {
+            private: Some(true),
+            ..Default::default()
+        };
+        assert!(private.changes_info());
+        let edited = private.apply(TORRENT).unwrap();
+        let (info, original) = (info_bytes(&edited).unwrap(), info_bytes(TORRENT).unwrap());
+        assert_eq!(entry(info, b"private"), Some(&b"i1e"[..]));
+        for key in [&b"pieces"[..], b"x-field", b"piece length"] {
+            assert_eq!(entry(info, key), entry(original, key));
+        }
+        assert_eq!(
+            entry(&edited, b"creation date"),
+            entry(TORRENT, b"creation date")
+        );
+        let hashes = InfoHashes::of(&edited).unwrap();
+        assert_ne!(hashes, InfoHashes::of(TORRENT).unwrap());
+        assert_eq!(hashes.v1, Some(Sha1::digest(info).into()));
+        let parsed = TorrentFile::from_bytes(&edited).unwrap();
+        assert!(parsed.info.is_private());
+        assert_eq!(Some(parsed.info_hash()), hashes.v1);
+
+        // Turning it off again removes the key, and with it the change to the info hash.
+        let public = TorrentEdit {
+            private: Some(false),
+            ..Default::default()
+        };
+        let restored = public.apply(&edited).unwrap();
+        assert_eq!(restored, TORRENT);
+    }
+}
@@ -0,0 +1,62 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::decoder::DecodeError;
+
+/// Why a call into the library failed.
+///
+/// Failures that callers are expected to handle get their own variant. Everything else, like
+/// a tracker that can't be reached, ends up in [`Error::Other`] with a message describing
+/// what went wrong and why.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A .torrent file that can't be parsed or whose hashes don't add up.
+    #[error("invalid torrent: {0}")]
+    InvalidTorrent(String),
+    /// Bencode that isn't well-formed.
+    #[error("invalid bencode: {0}")]
+    Bencode(#[from] DecodeError),
+    /// A torrent with this info hash is already in the session.
+    #[error("torrent {} is already added", hex::encode(.0))]
+    AlreadyAdded([u8; 20]),
+    /// No torrent with this info hash is in the session.
+    #[error("no torrent {}", hex::encode(.0))]
+    UnknownTorrent([u8; 20]),
+    /// The tracker only tracks the torrents it was told to, and this isn't one of them.
+    #[error("torrent is not tracked here")]
+    NotTracked,
+    /// A file or socket operation failed; `context` says which.
+    #[error("{context}: {error}")]
+    Io { context: String, error: io::Error },
+    #[error("{0}")]
+    Other(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// `anyhow::Context::with_context` for I/O errors, keeping them an [`Error::Io`].
+pub(crate) trait IoContext<T> {
+    fn io_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|error| Error::Io {
+            context: context().into(),
+            error,
+        })
+    }
+}
+
+/// Internals use `anyhow` to add context as errors bubble up; at the boundary the whole chain
+/// becomes the message, unless it is one of ours that passed through untouched.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Self>() {
+            Ok(e) => e,
+            Err(e) => Self::Other(format!("{e:#}")),
+        }
+    }
+}
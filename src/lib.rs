@@ -0,0 +1,58 @@
+//! A BitTorrent client library: parse and create .torrent files, download and seed them, and
+//! run a tracker.
+//!
+//! Most programs want a [`Session`], which shares one listening port, one connection limit
+//! and one DHT node among many torrents:
+//!
+//! ```no_run
+//! # async fn run() -> bittorrent_rust::Result<()> {
+//! use bittorrent_rust::{AddOptions, Session, SessionConfig, TorrentFile};
+//!
+//! let session = Session::start(SessionConfig::default()).await?;
+//! let torrent = TorrentFile::from_bytes(&std::fs::read("sample.torrent").unwrap())?;
+//! let handle = session.add(torrent, AddOptions::default())?;
+//! println!("{}: {}", handle.name(), handle.state());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`download::Download`] downloads a single torrent without a session, and
+//! [`tracker_server::Swarms`] is the state behind the HTTP and UDP trackers.
+//!
+//! Fallible calls return [`Error`]. Nothing is printed: progress, and failures that don't stop
+//! a download, are reported through the [`log`] crate.
+
+pub mod create;
+pub mod daemon;
+pub mod decoder;
+pub mod dht;
+pub mod disk;
+pub mod download;
+pub mod edit;
+mod error;
+pub mod handshake;
+pub mod hashes;
+pub mod lsd;
+pub mod mse;
+pub mod output;
+pub mod peers;
+pub mod priority;
+pub mod session;
+pub mod storage;
+pub mod stream;
+pub mod torrent;
+pub mod tracker;
+pub mod tracker_server;
+pub mod udp_tracker;
+pub mod value;
+
+mod activepeer;
+mod merkle;
+mod resume;
+mod utp;
+mod webseed;
+
+pub use create::TorrentBuilder;
+pub use error::{Error, Result};
+pub use session::{AddOptions, Session, SessionConfig, TorrentHandle, TorrentState, TorrentStatus};
+pub use torrent::{Info, TorrentFile};
@@ -0,0 +1,367 @@
+use anyhow::{Context, Result};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::peers::Peer;
+
+pub const LSD_MULTICAST_ADDR: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// BEP 14 asks for a re-announce every five minutes...
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// ...and no more than one announce per torrent per minute.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A `BT-SEARCH` message as sent over the multicast group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognise (and ignore) its own announces coming back from the group.
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            LSD_MULTICAST_ADDR, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(bytes).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+struct Torrent {
+    last_announce: Option<Instant>,
+    peers: Vec<SocketAddrV4>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    /// The peer port we announce; nothing is announced without one.
+    port: Option<u16>,
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; 20], Torrent>>,
+}
+
+/// Local Service Discovery (BEP 14).
+///
+/// Announces the torrents we're active in to the LAN multicast group and remembers the peers
+/// that announce the same torrents, so they can be tried before anything the tracker returns.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Lsd {
+    /// Joins the multicast group. `port` is the peer port we advertise to other clients.
+    pub fn start(port: u16) -> crate::Result<Self> {
+        Self::join(Some(port))
+    }
+
+    /// Joins the multicast group to learn about LAN peers without announcing ourselves, for
+    /// when nothing listens for peer connections.
+    pub fn listen_only() -> crate::Result<Self> {
+        Self::join(None)
+    }
+
+    fn join(port: Option<u16>) -> crate::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+            .context("create LSD socket")?;
+        // Other clients on this machine listen on the same port.
+        socket
+            .set_reuse_address(true)
+            .context("set SO_REUSEADDR on LSD socket")?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_MULTICAST_ADDR.port())).into())
+            .context("bind LSD socket")?;
+        socket
+            .join_multicast_v4(LSD_MULTICAST_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)
+            .context("join LSD multicast group")?;
+        socket
+            .set_multicast_loop_v4(true)
+            .context("enable multicast loopback")?;
+        socket
+            .set_nonblocking(true)
+            .context("make LSD socket non-blocking")?;
+        let socket = UdpSocket::from_std(socket.into()).context("register LSD socket")?;
+
+        let inner = Arc::new(Inner {
+            socket,
+            port,
+            cookie: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            torrents: Mutex::new(HashMap::new()),
+        });
+        let tasks = vec![
+            tokio::spawn(Inner::receive_loop(inner.clone())),
+            tokio::spawn(Inner::announce_loop(inner.clone())),
+        ];
+        Ok(Self { inner, tasks })
+    }
+
+    /// Starts announcing `info_hash` on the LAN.
+    pub(crate) async fn add_torrent(&self, info_hash: [u8; 20]) -> Result<()> {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .entry(info_hash)
+            .or_insert(Torrent {
+                last_announce: None,
+                peers: Vec::new(),
+            });
+        self.inner.announce(&[info_hash]).await
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /// The LAN peers that have announced `info_hash` so far.
+    pub fn peers(&self, info_hash: &[u8; 20]) -> Vec<Peer> {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .map(|torrent| torrent.peers.iter().copied().map(Peer::new).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Inner {
+    /// Sends one announce for the given torrents, skipping any announced less than a minute ago.
+    async fn announce(&self, info_hashes: &[[u8; 20]]) -> Result<()> {
+        let Some(port) = self.port else {
+            return Ok(());
+        };
+        let due: Vec<[u8; 20]> = {
+            let mut torrents = self.torrents.lock().unwrap();
+            info_hashes
+                .iter()
+                .filter(|info_hash| {
+                    let Some(torrent) = torrents.get_mut(*info_hash) else {
+                        return false;
+                    };
+                    let due = torrent
+                        .last_announce
+                        .is_none_or(|at| at.elapsed() >= MIN_ANNOUNCE_INTERVAL);
+                    if due {
+                        torrent.last_announce = Some(Instant::now());
+                    }
+                    due
+                })
+                .copied()
+                .collect()
+        };
+        if due.is_empty() {
+            return Ok(());
+        }
+        let announce = Announce {
+            port,
+            info_hashes: due,
+            cookie: Some(self.cookie.clone()),
+        };
+        self.socket
+            .send_to(&announce.to_bytes(), LSD_MULTICAST_ADDR)
+            .await
+            .context("send LSD announce")?;
+        Ok(())
+    }
+
+    async fn announce_loop(self: Arc<Self>) {
+        let mut interval = time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let info_hashes: Vec<[u8; 20]> =
+                self.torrents.lock().unwrap().keys().copied().collect();
+            if let Err(e) = self.announce(&info_hashes).await {
+                log::warn!("{e:#}");
+            }
+        }
+    }
+
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buffer = [0u8; 1500];
+        loop {
+            let Ok((length, from)) = self.socket.recv_from(&mut buffer).await else {
+                continue;
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            // Answer a newcomer with our own announce so it doesn't have to wait five minutes
+            // to find us.
+            let newly_seen = self.handle(&buffer[..length], from);
+            if !newly_seen.is_empty() {
+                let _ = self.announce(&newly_seen).await;
+            }
+        }
+    }
+
+    /// Records the sender of an announce as a peer of the torrents we share with it, and
+    /// returns the torrents it wasn't known for yet.
+    fn handle(&self, message: &[u8], from: SocketAddrV4) -> Vec<[u8; 20]> {
+        let Some(announce) = Announce::from_bytes(message) else {
+            return Vec::new();
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return Vec::new();
+        }
+
+        let peer = SocketAddrV4::new(*from.ip(), announce.port);
+        let mut newly_seen = Vec::new();
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get_mut(info_hash) {
+                if !torrent.peers.contains(&peer) {
+                    torrent.peers.push(peer);
+                    newly_seen.push(*info_hash);
+                }
+            }
+        }
+        newly_seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_round_trip() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[1; 20], [2; 20]],
+            cookie: Some("c00k1e".into()),
+        };
+        let bytes = announce.to_bytes();
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert_eq!(Announce::from_bytes(&bytes), Some(announce));
+
+        // Header names are case-insensitive and the cookie is optional.
+        let bytes = format!(
+            "BT-SEARCH * HTTP/1.1\r\nhost: {LSD_MULTICAST_ADDR}\r\nPORT: 51413\r\ninfohash: {}\r\n\r\n\r\n",
+            hex::encode([3; 20])
+        );
+        assert_eq!(
+            Announce::from_bytes(bytes.as_bytes()),
+            Some(Announce {
+                port: 51413,
+                info_hashes: vec![[3; 20]],
+                cookie: None,
+            })
+        );
+    }
+
+    #[test]
+    fn announces_need_a_port_and_an_info_hash() {
+        let without_info_hash = Announce {
+            port: 6881,
+            info_hashes: Vec::new(),
+            cookie: None,
+        };
+        assert_eq!(Announce::from_bytes(&without_info_hash.to_bytes()), None);
+        let bad_info_hash = b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: 0102\r\n\r\n\r\n";
+        assert_eq!(Announce::from_bytes(bad_info_hash), None);
+        let without_port = format!(
+            "BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n\r\n",
+            hex::encode([1; 20])
+        );
+        assert_eq!(Announce::from_bytes(without_port.as_bytes()), None);
+        assert_eq!(Announce::from_bytes(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn own_announces_are_ignored() {
+        let inner = Inner {
+            socket: UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            port: Some(6881),
+            cookie: "ours".into(),
+            torrents: Mutex::new(HashMap::from([(
+                [1; 20],
+                Torrent {
+                    last_announce: None,
+                    peers: Vec::new(),
+                },
+            )])),
+        };
+        let from = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 5), 6771);
+        let announce = |cookie: &str| {
+            Announce {
+                port: 7000,
+                info_hashes: vec![[1; 20], [2; 20]],
+                cookie: Some(cookie.into()),
+            }
+            .to_bytes()
+        };
+
+        assert!(inner.handle(&announce("ours"), from).is_empty());
+        assert!(inner.torrents.lock().unwrap()[&[1; 20]].peers.is_empty());
+
+        // Only torrents we have are recorded, and only the first time.
+        assert_eq!(inner.handle(&announce("theirs"), from), vec![[1; 20]]);
+        assert!(inner.handle(&announce("theirs"), from).is_empty());
+        assert_eq!(
+            inner.torrents.lock().unwrap()[&[1; 20]].peers,
+            vec![SocketAddrV4::new(*from.ip(), 7000)]
+        );
+    }
+}
@@ -1,128 +1,548 @@
-pub mod activepeer;
 mod command;
-mod decoder;
-mod handshake;
-mod hashes;
-mod peers;
-mod torrent;
-mod tracker;
 
-use std::io::Write;
-use std::sync::Arc;
+use std::{io::Write, time::Duration};
 
-use activepeer::activepeer::ActivePeer;
 use anyhow::Context;
+use bittorrent_rust::{
+    daemon::{self, Daemon},
+    decoder::Mode,
+    dht::{Dht, DhtConfig, DEFAULT_BOOTSTRAP_NODES},
+    download::{Download, DownloadOptions},
+    edit::{InfoHashes, TorrentEdit},
+    lsd::Lsd,
+    output::{PeersReport, TorrentReport},
+    priority, storage,
+    storage::FileStorage,
+    stream::{self, StreamOutput},
+    torrent::{Keys, Torrent},
+    tracker_server,
+    tracker_server::{Swarms, TrackerConfig},
+    value::Value,
+    SessionConfig, TorrentBuilder, TorrentFile,
+};
 use clap::Parser;
-use command::{Args, Command};
-use decoder::decode_bencoded_value;
-use peers::peers::{connect_to_peer, WorkQueue};
-use sha1::{Digest, Sha1};
-use torrent::{Keys, Torrent, TorrentFile};
+use command::{Args, Command, ConvertTo, DiscoveryArgs, Format, TrackerCommand};
+use tokio::net::{TcpListener, UdpSocket};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    log::set_logger(&Logger).expect("no other logger is set");
+    log::set_max_level(if args.verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    });
     match args.command {
-        Command::Decode { value } => {
-            let v = decode_bencoded_value(&value).0;
-            println!("{v}");
+        Command::Decode { value, strict } => {
+            let mode = if strict { Mode::Strict } else { Mode::Lenient };
+            let v = Value::decode(value.as_bytes(), mode).context("decode bencoded value")?;
+            println!("{}", v.to_json());
+        }
+        Command::Encode { json } => {
+            let json: serde_json::Value = serde_json::from_str(&json).context("parse JSON")?;
+            let v = Value::from_json(&json).context("convert JSON to bencode")?;
+            std::io::stdout()
+                .write_all(&v.encode())
+                .context("write to stdout")?;
+        }
+        Command::Convert { input, output, to } => {
+            let contents =
+                std::fs::read(&input).with_context(|| format!("read {}", input.display()))?;
+            let to = to.unwrap_or(if input.extension().is_some_and(|ext| ext == "json") {
+                ConvertTo::Bencode
+            } else {
+                ConvertTo::Json
+            });
+            let converted = match to {
+                ConvertTo::Json => {
+                    let v = Value::decode(&contents, Mode::Lenient)
+                        .with_context(|| format!("decode {}", input.display()))?;
+                    let mut json = serde_json::to_vec_pretty(&v.to_json())?;
+                    json.push(b'\n');
+                    json
+                }
+                ConvertTo::Bencode => {
+                    let json: serde_json::Value = serde_json::from_slice(&contents)
+                        .with_context(|| format!("parse {}", input.display()))?;
+                    Value::from_json(&json)
+                        .context("convert JSON to bencode")?
+                        .encode()
+                }
+            };
+            match output {
+                Some(output) => std::fs::write(&output, converted)
+                    .with_context(|| format!("write {}", output.display()))?,
+                None => std::io::stdout()
+                    .write_all(&converted)
+                    .context("write to stdout")?,
+            }
         }
         Command::Info { torrent } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: TorrentFile =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            println!("Tracker URL: {}", t.announce);
-            if let Keys::SingleFile { length } = &t.info.keys {
-                println!("Length: {length}");
+            let t = TorrentFile::from_bytes(&dot_torrent)?;
+            if args.format == Format::Json {
+                println!("{}", serde_json::to_string(&TorrentReport::new(&t))?);
+                return Ok(());
             }
-
-            if let Keys::MultiFile { files } = &t.info.keys {
-                println!("Files: {:?}", files);
+            if let Some(announce) = &t.announce {
+                println!("Tracker URL: {announce}");
+            }
+            match &t.info.keys {
+                Some(Keys::SingleFile { length }) => println!("Length: {length}"),
+                Some(Keys::MultiFile { files }) => {
+                    let files: Vec<_> =
+                        files.iter().filter(|file| !file.attributes().pad).collect();
+                    println!("Files: {:?}", files);
+                }
+                None => {
+                    let files: Vec<_> = t
+                        .info
+                        .files()
+                        .into_iter()
+                        .filter(|file| !file.attr.pad)
+                        .map(|file| (file.path.join("/"), file.length))
+                        .collect();
+                    println!("Files: {:?}", files);
+                }
             }
 
-            let info_encoded =
-                serde_bencode::to_bytes(&t.info).context("re-encode info section")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&info_encoded);
-            let info_hash = hasher.finalize();
-            println!("Info Hash: {}", hex::encode(&info_hash));
+            if t.info.keys.is_some() {
+                println!("Info Hash: {}", hex::encode(t.info_hash()));
+            }
+            if let Some(info_hash) = t.info_hash_v2() {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
+            }
+            if let Some(version) = t.info.meta_version {
+                println!("Meta Version: {version}");
+            }
             println!("Piece Length: {}", t.info.plength);
-            println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(&hash));
+            if t.info.pieces.is_empty() {
+                println!("Pieces: {}", t.info.num_pieces());
+            } else {
+                println!("Piece Hashes:");
+                for hash in t.info.pieces.0 {
+                    println!("{}", hex::encode(hash));
+                }
             }
         }
-        Command::Peers { torrent } => {
+        Command::Peers { torrent, discovery } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: TorrentFile =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = TorrentFile::from_bytes(&dot_torrent)?;
+            let dht = start_dht(&discovery, &t).await?;
+            let lsd = start_lsd(&discovery)?;
             let torrent = Torrent::new(t);
 
-            let tracker_info = torrent
-                .contact_tracker()
-                .await
-                .context("getting info from tracker")?;
+            let peers = torrent.discover_peers(dht.as_ref(), lsd.as_ref()).await?;
+            if let Some(dht) = &dht {
+                dht.save()?;
+            }
 
-            println!("{:?}", tracker_info.peers.0);
+            match args.format {
+                Format::Text => println!("{:?}", peers.0),
+                Format::Json => println!("{}", serde_json::to_string(&PeersReport::new(&peers.0))?),
+            }
         }
-        Command::Download { torrent } => {
+        Command::Download {
+            torrent,
+            discovery,
+            selection,
+            encryption,
+            no_utp,
+            resume_file,
+            sequential,
+            storage,
+            preallocate,
+        } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: TorrentFile =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let torrent = Arc::new(Torrent::new(t));
+            let t = TorrentFile::from_bytes(&dot_torrent)?;
+            let dht = start_dht(&discovery, &t).await?;
+            let lsd = start_lsd(&discovery)?;
+            let file_priorities = priority::file_priorities(
+                &t.info,
+                &selection.only,
+                &selection.files,
+                &selection.priority,
+            )?;
+            let options = DownloadOptions {
+                dir: ".".into(),
+                resume_file,
+                file_priorities,
+                encryption: encryption.into(),
+                utp: !no_utp,
+                sequential,
+                storage: storage.into(),
+                preallocation: preallocate.into(),
+                custom_storage: None,
+                connection_limit: None,
+                max_peers: None,
+            };
+            let download =
+                Download::start(Torrent::new(t), options, dht.as_ref(), lsd.as_ref()).await?;
+            download.wait(ctrl_c()).await?;
+        }
+        Command::Stream {
+            torrent,
+            discovery,
+            file,
+            http,
+            encryption,
+            no_utp,
+        } => {
+            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
+            let t = TorrentFile::from_bytes(&dot_torrent)?;
+            let output = match http {
+                Some(addr) => {
+                    let listener = TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("listen on {addr}"))?;
+                    println!("Streaming on http://{}/", listener.local_addr()?);
+                    StreamOutput::Http(listener)
+                }
+                // Keep the stream itself clean of progress messages.
+                #[cfg(unix)]
+                None => StreamOutput::Writer(stream::take_stdout()?),
+                #[cfg(not(unix))]
+                None => StreamOutput::Writer(tokio::io::stdout()),
+            };
 
-            let tracker_info = torrent
-                .contact_tracker()
-                .await
-                .context("getting info from tracker")?;
-
-            let work_queue =
-                WorkQueue::new((0..torrent.torrent_file.info.pieces.0.len()).collect());
-            let work_queue = Arc::new(work_queue);
-            let buffer = Arc::new(tokio::sync::Mutex::new(Vec::<u8>::new()));
-
-            let mut workers = vec![];
-
-            let num_workers = 1;
-            let peers = Arc::new(tracker_info.peers.clone());
-
-            for _ in 0..num_workers {
-                let peer_info_ref = peers.clone();
-                let file_ref = torrent.clone();
-                let work_queue_ref = work_queue.clone();
-                let buffer_ref = buffer.clone();
-                workers.push(tokio::spawn(async move {
-                    let peers = peer_info_ref;
-
-                    //try connecting to a peer
-
-                    let mut peer: Option<ActivePeer> = None;
-                    for recieved_peer in peers.0.iter() {
-                        let result = connect_to_peer(recieved_peer).await;
-                        match result {
-                            Some(connection) => {
-                                peer = Some(connection);
-                                break;
-                            }
-                            None => {}
-                        }
-                    }
+            let dht = start_dht(&discovery, &t).await?;
+            let lsd = start_lsd(&discovery)?;
+            let options = DownloadOptions {
+                dir: ".".into(),
+                encryption: encryption.into(),
+                utp: !no_utp,
+                ..Default::default()
+            };
+            stream::stream(
+                Torrent::new(t),
+                file,
+                options,
+                dht.as_ref(),
+                lsd.as_ref(),
+                output,
+                ctrl_c(),
+            )
+            .await?;
+        }
+        Command::Verify { torrent, dir } => {
+            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
+            let t = TorrentFile::from_bytes(&dot_torrent)?;
+            let files = FileStorage::new(&t.info, &dir, Vec::new())?;
+            let recheck = storage::recheck(&t.info, &files)?;
 
-                    let mut peer = peer.expect("connect to a peer");
-                    peer.start_exchanging_messages(&file_ref, &work_queue_ref, buffer_ref)
-                        .await;
-                }));
+            for file in &recheck.files {
+                let percent = if file.length == 0 {
+                    100.0
+                } else {
+                    file.verified_bytes as f64 * 100.0 / file.length as f64
+                };
+                println!(
+                    "{} {:6.2}% {} ({} of {} bytes){}",
+                    if file.is_complete() { "ok  " } else { "FAIL" },
+                    percent,
+                    dir.join(&file.path).display(),
+                    file.verified_bytes,
+                    file.length,
+                    if file.sha1_matches == Some(false) {
+                        ", SHA-1 mismatch"
+                    } else {
+                        ""
+                    }
+                );
             }
-
-            for worker in workers {
-                worker.await?;
+            if !recheck.is_complete() {
+                let bad_pieces = recheck.bad_pieces();
+                println!("Bad pieces: {:?}", bad_pieces);
+                anyhow::bail!(
+                    "{} of {} pieces failed verification",
+                    bad_pieces.len(),
+                    recheck.have.len()
+                );
+            }
+            let mismatched = recheck
+                .files
+                .iter()
+                .filter(|file| file.sha1_matches == Some(false))
+                .count();
+            if mismatched > 0 {
+                anyhow::bail!("{mismatched} files don't match their SHA-1");
+            }
+            println!("All {} pieces verified", recheck.have.len());
+        }
+        Command::Create {
+            path,
+            output,
+            announce,
+            piece_length,
+            comment,
+            created_by,
+            no_date,
+            private,
+            source,
+            web_seeds,
+        } => {
+            let mut builder = TorrentBuilder::new(&path)
+                .private(private)
+                .creation_date(!no_date);
+            for tier in announce {
+                builder = builder.announce(
+                    tier.split(',')
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                );
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
             }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if created_by.is_some() {
+                builder = builder.created_by(created_by);
+            }
+            if let Some(source) = source {
+                builder = builder.source(source);
+            }
+            for url in web_seeds {
+                builder = builder.web_seed(url);
+            }
+
+            let t = builder.build()?;
+            let output = output.unwrap_or_else(|| format!("{}.torrent", t.info.name).into());
+            let encoded = serde_bencode::to_bytes(&t).context("encode torrent file")?;
+            std::fs::write(&output, encoded)
+                .with_context(|| format!("write {}", output.display()))?;
+            println!("Created {}", output.display());
+            println!("Info Hash: {}", hex::encode(t.info_hash()));
+            println!("Piece Length: {}", t.info.plength);
+            println!("Pieces: {}", t.info.num_pieces());
+        }
+        Command::Edit {
+            torrent,
+            output,
+            announce,
+            announce_list,
+            comment,
+            web_seeds,
+            private,
+            source,
+        } => {
+            let edit = TorrentEdit {
+                announce,
+                announce_list: (!announce_list.is_empty()).then(|| {
+                    announce_list
+                        .iter()
+                        .map(|tier| {
+                            tier.split(',')
+                                .map(str::trim)
+                                .filter(|url| !url.is_empty())
+                                .map(str::to_string)
+                                .collect()
+                        })
+                        .collect()
+                }),
+                comment,
+                web_seeds: (!web_seeds.is_empty()).then(|| {
+                    web_seeds
+                        .into_iter()
+                        .filter(|url| !url.is_empty())
+                        .collect()
+                }),
+                private,
+                source,
+            };
+            let dot_torrent = std::fs::read(&torrent).context("read torrent file")?;
+            let edited = edit.apply(&dot_torrent)?;
+            TorrentFile::from_bytes(&edited).context("check edited torrent")?;
 
-            let file_name = &torrent.torrent_file.info.name;
-            let mut f = std::fs::File::create(file_name)?;
-            let buffer_guard = buffer.lock().await;
-            f.write_all(&buffer_guard)?;
+            let before = InfoHashes::of(&dot_torrent)?;
+            let after = InfoHashes::of(&edited)?;
+            let output = output.unwrap_or(torrent);
+            std::fs::write(&output, &edited)
+                .with_context(|| format!("write {}", output.display()))?;
+            println!("Wrote {}", output.display());
+            let hashes = [
+                (
+                    "Info Hash",
+                    before.v1.map(hex::encode),
+                    after.v1.map(hex::encode),
+                ),
+                (
+                    "Info Hash v2",
+                    before.v2.map(hex::encode),
+                    after.v2.map(hex::encode),
+                ),
+            ];
+            for (label, before, after) in hashes {
+                match (before, after) {
+                    (Some(before), Some(after)) if before != after => {
+                        println!("{label} changed: {before} -> {after}")
+                    }
+                    (_, Some(after)) => println!("{label}: {after}"),
+                    _ => {}
+                }
+            }
+            if before != after {
+                println!("The info dictionary changed, so peers and trackers see a new torrent");
+            }
+        }
+        Command::Daemon {
+            port,
+            rpc,
+            dir,
+            state,
+            max_connections,
+            discovery,
+            encryption,
+            no_utp,
+        } => {
+            let listener = TcpListener::bind(rpc)
+                .await
+                .with_context(|| format!("listen on {rpc}"))?;
+            println!("Control API on http://{}/torrents", listener.local_addr()?);
+            println!("Token in {}", daemon::token_file(&state).display());
+            let config = SessionConfig {
+                listen_port: port,
+                max_connections,
+                dht: dht_config(&discovery),
+                lsd: discovery.lsd,
+                encryption: encryption.into(),
+                utp: !no_utp,
+            };
+            Daemon::run(config, dir, state, listener, ctrl_c()).await?;
+        }
+        Command::Tracker {
+            command:
+                TrackerCommand::Serve {
+                    http,
+                    udp,
+                    udp_rate_limit,
+                    interval,
+                    peer_ttl,
+                    allow,
+                    max_numwant,
+                    state,
+                },
+        } => {
+            let allowed = if allow.is_empty() {
+                None
+            } else {
+                Some(
+                    allow
+                        .iter()
+                        .map(|torrent| allowed_info_hash(torrent))
+                        .collect::<anyhow::Result<_>>()?,
+                )
+            };
+            let swarms = Swarms::new(TrackerConfig {
+                interval: Duration::from_secs(interval),
+                peer_ttl: Duration::from_secs(peer_ttl),
+                allowed,
+                max_numwant,
+                state_file: state,
+            });
+            let http = match (http, udp) {
+                (None, None) => Some(([0, 0, 0, 0], 6969).into()),
+                (http, _) => http,
+            };
+            let http = match http {
+                Some(addr) => {
+                    let listener = TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("listen on {addr}"))?;
+                    println!("HTTP tracker on http://{}/announce", listener.local_addr()?);
+                    Some(listener)
+                }
+                None => None,
+            };
+            let udp = match udp {
+                Some(addr) => {
+                    let socket = UdpSocket::bind(addr)
+                        .await
+                        .with_context(|| format!("listen on {addr}"))?;
+                    println!("UDP tracker on udp://{}/announce", socket.local_addr()?);
+                    Some(socket)
+                }
+                None => None,
+            };
+            tracker_server::run(swarms, http, udp, udp_rate_limit, ctrl_c()).await?;
         }
     }
     Ok(())
 }
+
+/// An info hash given to `tracker serve --allow`: 40 hex digits, or a .torrent file.
+fn allowed_info_hash(torrent: &str) -> anyhow::Result<[u8; 20]> {
+    if torrent.len() == 40 {
+        if let Ok(info_hash) = hex::decode(torrent) {
+            return Ok(info_hash.try_into().expect("40 hex digits are 20 bytes"));
+        }
+    }
+    let dot_torrent =
+        std::fs::read(torrent).with_context(|| format!("read torrent file {torrent}"))?;
+    Ok(TorrentFile::from_bytes(&dot_torrent)?.info_hash())
+}
+
+/// These commands don't take peer connections, so there is no port to announce on the LAN.
+fn start_lsd(args: &DiscoveryArgs) -> anyhow::Result<Option<Lsd>> {
+    if !args.lsd {
+        return Ok(None);
+    }
+    Ok(Some(Lsd::listen_only()?))
+}
+
+fn dht_config(args: &DiscoveryArgs) -> Option<DhtConfig> {
+    if !args.dht {
+        return None;
+    }
+    let bootstrap_nodes = if args.dht_bootstrap.is_empty() {
+        DEFAULT_BOOTSTRAP_NODES
+            .iter()
+            .map(|node| node.to_string())
+            .collect()
+    } else {
+        args.dht_bootstrap.clone()
+    };
+    Some(DhtConfig {
+        port: args.dht_port,
+        bootstrap_nodes,
+        state_file: Some(args.dht_state.clone()),
+    })
+}
+
+async fn start_dht(args: &DiscoveryArgs, t: &TorrentFile) -> anyhow::Result<Option<Dht>> {
+    let Some(mut config) = dht_config(args) else {
+        return Ok(None);
+    };
+    for (host, port) in t.nodes.iter().flatten() {
+        config.bootstrap_nodes.push(format!("{host}:{port}"));
+    }
+
+    Ok(Some(Dht::start(config).await?))
+}
+
+/// Resolves on the first Ctrl-C.
+async fn ctrl_c() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Prints what the library logs to stderr, where it stays out of the way of anything written
+/// to stdout. What the HTTP client and other dependencies log is left out.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with("bittorrent_rust")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
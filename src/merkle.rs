@@ -0,0 +1,67 @@
+use sha2::{Digest, Sha256};
+
+/// BitTorrent v2 hashes files in blocks of this size; they are the leaves of the merkle tree.
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The root of a merkle tree `width` leaves wide whose first leaves are `layer`.
+///
+/// `width` is rounded up to a power of two. The missing leaves are `pad`, and the missing
+/// nodes further up are hashes of missing nodes below them.
+pub fn root(mut layer: Vec<[u8; 32]>, width: usize, mut pad: [u8; 32]) -> [u8; 32] {
+    let mut width = width.max(1).next_power_of_two();
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// The leaf hashes of `data`, one per block; the last block may be short.
+pub fn leaves(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+/// The root of a subtree over `leaves` blocks that are all past the end of a file, which is
+/// what stands in for missing pieces in a piece layer.
+pub fn pad_root(leaves: usize) -> [u8; 32] {
+    root(Vec::new(), leaves, [0; 32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_pads_to_a_power_of_two() {
+        let data = vec![7u8; 2 * BLOCK_SIZE + 1];
+        let leaves = leaves(&data);
+        assert_eq!(leaves.len(), 3);
+        assert_eq!(leaves[2], <[u8; 32]>::from(Sha256::digest([7u8])));
+
+        let zero = [0; 32];
+        let expected = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &zero),
+        );
+        assert_eq!(root(leaves.clone(), 3, zero), expected);
+        assert_eq!(root(leaves.clone(), 4, zero), expected);
+        // Wider trees pad with whole subtrees of padding.
+        let pad = hash_pair(&hash_pair(&zero, &zero), &hash_pair(&zero, &zero));
+        assert_eq!(root(leaves, 5, zero), hash_pair(&expected, &pad));
+        assert_eq!(pad_root(4), pad);
+        assert_eq!(pad_root(1), zero);
+    }
+}
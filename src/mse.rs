@@ -0,0 +1,511 @@
+use anyhow::{bail, Context, Result};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time;
+
+use crate::peers::PeerStream;
+
+/// The 768-bit safe prime all MSE implementations share.
+const P: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const G: u32 = 2;
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+/// The verification constant: eight zero bytes, encrypted.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// RC4 output that is thrown away before use; the start of the keystream is weak.
+const RC4_DISCARD: usize = 1024;
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+/// How long an inbound peer gets to send the start of its handshake.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a peer connection should treat Message Stream Encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Try an encrypted connection first, fall back to plaintext.
+    #[default]
+    Prefer,
+    /// Only ever use RC4-encrypted connections.
+    Require,
+    /// Never encrypt.
+    Disable,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            EncryptionPolicy::Disable => CRYPTO_PLAINTEXT,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, byte) in s.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Self { s, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k =
+                self.s[(self.s[self.i as usize].wrapping_add(self.s[self.j as usize])) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+/// Our half of the Diffie-Hellman exchange.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let p = BigUint::parse_bytes(P.as_bytes(), 16).expect("P is valid hex");
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(G).modpow(&private, &p);
+        Self {
+            private,
+            public: pad_key(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; KEY_LEN] {
+        let p = BigUint::parse_bytes(P.as_bytes(), 16).expect("P is valid hex");
+        let remote = BigUint::from_bytes_be(remote_public);
+        pad_key(&remote.modpow(&self.private, &p))
+    }
+}
+
+fn pad_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Reads from `stream` until `pattern` has been seen, giving up after `limit` bytes.
+async fn sync_on<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8], limit: usize) -> Result<()> {
+    let mut window = Vec::with_capacity(limit);
+    while window.len() < limit {
+        window.push(stream.read_u8().await.context("read MSE padding")?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    bail!("no MSE synchronisation point within {limit} bytes")
+}
+
+/// A stream that went through the MSE handshake.
+///
+/// If RC4 was selected, everything read or written is run through the negotiated ciphers;
+/// otherwise the bytes pass through as they are.
+pub struct MseStream<S> {
+    inner: S,
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Decrypted bytes that arrived as part of the handshake (the initial payload).
+    read_prefix: Vec<u8>,
+    /// Encrypted bytes accepted by `poll_write` but not yet handed to `inner`.
+    write_pending: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_prefix.is_empty() {
+            let n = this.read_prefix.len().min(buf.remaining());
+            buf.put_slice(&this.read_prefix[..n]);
+            this.read_prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((decrypt, _)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // The keystream has to advance exactly once per byte, so encrypted bytes the inner
+        // stream doesn't take right away are buffered rather than re-encrypted on retry.
+        ready!(this.poll_drain(cx))?;
+        let (_, encrypt) = this.ciphers.as_mut().expect("checked above");
+        this.write_pending.extend_from_slice(buf);
+        encrypt.apply(&mut this.write_pending);
+        // Push the data out now if we can; flushing picks up whatever is left.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Runs the initiating side of the MSE handshake over `stream`.
+///
+/// `info_hash` is the shared secret (SKEY) both sides must already know.
+pub(crate) async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>> {
+    let keys = KeyPair::generate();
+    let mut hello = keys.public.to_vec();
+    hello.extend(random_pad());
+    stream.write_all(&hello).await.context("send Ya")?;
+
+    let mut remote_public = [0u8; KEY_LEN];
+    stream
+        .read_exact(&mut remote_public)
+        .await
+        .context("read Yb")?;
+    let secret = keys.shared_secret(&remote_public);
+
+    let mut encrypt = Rc4::new(&sha1(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&sha1(&[b"keyB", &secret, &info_hash]));
+
+    let mut message = sha1(&[b"req1", &secret]).to_vec();
+    message.extend(xor(sha1(&[b"req2", &info_hash]), sha1(&[b"req3", &secret])));
+    let mut negotiation = VC.to_vec();
+    negotiation.extend(policy.crypto_provide().to_be_bytes());
+    negotiation.extend(0u16.to_be_bytes()); // len(PadC)
+    negotiation.extend(0u16.to_be_bytes()); // len(IA)
+    encrypt.apply(&mut negotiation);
+    message.extend(negotiation);
+    stream
+        .write_all(&message)
+        .await
+        .context("send MSE request")?;
+
+    // The answer starts with VC encrypted under keyB, somewhere after PadB.
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    sync_on(&mut stream, &encrypted_vc, MAX_PAD + VC.len()).await?;
+
+    let mut reply = [0u8; 6];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .context("read crypto_select")?;
+    decrypt.apply(&mut reply);
+    let crypto_select = u32::from_be_bytes(reply[..4].try_into().expect("4 bytes"));
+    let pad_len = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if pad_len > MAX_PAD {
+        bail!("PadD of {pad_len} bytes is too long");
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad).await.context("read PadD")?;
+    decrypt.apply(&mut pad);
+
+    let ciphers = match crypto_select {
+        CRYPTO_RC4 if policy != EncryptionPolicy::Disable => Some((decrypt, encrypt)),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Require => None,
+        other => bail!("peer selected crypto method {other:#x}, which we did not offer"),
+    };
+    Ok(MseStream {
+        inner: stream,
+        ciphers,
+        read_prefix: Vec::new(),
+        write_pending: Vec::new(),
+    })
+}
+
+/// Runs the receiving side of the MSE handshake over `stream`.
+///
+/// Returns the negotiated stream and whichever of `info_hashes` the initiator asked for.
+pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, [u8; 20])> {
+    let mut remote_public = [0u8; KEY_LEN];
+    stream
+        .read_exact(&mut remote_public)
+        .await
+        .context("read Ya")?;
+    let keys = KeyPair::generate();
+    let mut hello = keys.public.to_vec();
+    hello.extend(random_pad());
+    stream.write_all(&hello).await.context("send Yb")?;
+    let secret = keys.shared_secret(&remote_public);
+
+    sync_on(&mut stream, &sha1(&[b"req1", &secret]), MAX_PAD + 20).await?;
+    let mut skey_hash = [0u8; 20];
+    stream
+        .read_exact(&mut skey_hash)
+        .await
+        .context("read SKEY hash")?;
+    let req3 = sha1(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(sha1(&[b"req2", *info_hash]), req3) == skey_hash)
+        .context("peer asked for a torrent we don't have")?;
+
+    let mut decrypt = Rc4::new(&sha1(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&sha1(&[b"keyB", &secret, &info_hash]));
+
+    let mut request = [0u8; 14];
+    stream
+        .read_exact(&mut request)
+        .await
+        .context("read crypto_provide")?;
+    decrypt.apply(&mut request);
+    if request[..8] != VC {
+        bail!("bad MSE verification constant");
+    }
+    let crypto_provide = u32::from_be_bytes(request[8..12].try_into().expect("4 bytes"));
+    let pad_len = u16::from_be_bytes([request[12], request[13]]) as usize;
+    if pad_len > MAX_PAD {
+        bail!("PadC of {pad_len} bytes is too long");
+    }
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad).await.context("read PadC")?;
+    decrypt.apply(&mut pad);
+    let ia_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut initial_payload = vec![0u8; ia_len];
+    stream
+        .read_exact(&mut initial_payload)
+        .await
+        .context("read IA")?;
+    decrypt.apply(&mut initial_payload);
+
+    let offered = crypto_provide & policy.crypto_provide();
+    let crypto_select = if offered & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if offered & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        bail!("no common crypto method (peer offered {crypto_provide:#x})");
+    };
+
+    let mut reply = VC.to_vec();
+    reply.extend(crypto_select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut reply);
+    stream
+        .write_all(&reply)
+        .await
+        .context("send crypto_select")?;
+
+    let ciphers = (crypto_select == CRYPTO_RC4).then_some((decrypt, encrypt));
+    Ok((
+        MseStream {
+            inner: stream,
+            ciphers,
+            read_prefix: initial_payload,
+            write_pending: Vec::new(),
+        },
+        info_hash,
+    ))
+}
+
+/// Works out whether an inbound connection starts with a plaintext BitTorrent handshake or an
+/// MSE key exchange, and negotiates accordingly.
+///
+/// Returns the stream to hand to the framer, with the peer's handshake still unread, and the
+/// info hash the peer asked for.
+pub(crate) async fn accept_inbound<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(PeerStream, [u8; 20])> {
+    // The protocol header, the reserved bytes and the info hash. An MSE initiator sends at
+    // least its 96-byte public key, so this much arrives either way.
+    let mut header = [0u8; PROTOCOL_HEADER.len() + 28];
+    time::timeout(HEADER_TIMEOUT, stream.read_exact(&mut header))
+        .await
+        .context("peer sent nothing to start the handshake")?
+        .context("read handshake")?;
+    // Whatever comes next reads the header again first.
+    let stream = MseStream {
+        inner: stream,
+        ciphers: None,
+        read_prefix: header.to_vec(),
+        write_pending: Vec::new(),
+    };
+
+    if header.starts_with(PROTOCOL_HEADER) {
+        if policy == EncryptionPolicy::Require {
+            bail!("peer sent a plaintext handshake but encryption is required");
+        }
+        let info_hash: [u8; 20] = header[28..].try_into().expect("20 bytes");
+        if !info_hashes.contains(&info_hash) {
+            bail!("peer asked for a torrent we don't have");
+        }
+        return Ok((Box::new(stream), info_hash));
+    }
+    if policy == EncryptionPolicy::Disable {
+        bail!("peer wants an encrypted connection but encryption is disabled");
+    }
+    let (stream, info_hash) = accept(stream, info_hashes, policy).await?;
+    Ok((Box::new(stream), info_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    fn handshake(info_hash: [u8; 20]) -> Vec<u8> {
+        [PROTOCOL_HEADER.as_slice(), &[0; 8], &info_hash, &[b'P'; 20]].concat()
+    }
+
+    #[tokio::test]
+    async fn plaintext_handshake_is_passed_through() {
+        let (mut client, server) = duplex(4096);
+        client.write_all(&handshake(INFO_HASH)).await.unwrap();
+        let (mut stream, info_hash) =
+            accept_inbound(server, &[INFO_HASH], EncryptionPolicy::Prefer)
+                .await
+                .unwrap();
+        assert_eq!(info_hash, INFO_HASH);
+        let mut received = vec![0; 68];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, handshake(INFO_HASH));
+
+        let (mut client, server) = duplex(4096);
+        client.write_all(&handshake(INFO_HASH)).await.unwrap();
+        let refused = accept_inbound(server, &[INFO_HASH], EncryptionPolicy::Require).await;
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn rc4_connection_carries_data_both_ways() {
+        let (client, server) = duplex(4096);
+        let (initiated, accepted) = tokio::join!(
+            initiate(client, INFO_HASH, EncryptionPolicy::Require),
+            accept_inbound(server, &[[1; 20], INFO_HASH], EncryptionPolicy::Prefer),
+        );
+        let mut client = initiated.unwrap();
+        let (mut server, info_hash) = accepted.unwrap();
+        assert!(client.is_encrypted());
+        assert_eq!(info_hash, INFO_HASH);
+
+        client.write_all(&handshake(INFO_HASH)).await.unwrap();
+        let mut received = vec![0; 68];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, handshake(INFO_HASH));
+
+        server.write_all(b"and back").await.unwrap();
+        let mut received = [0; 8];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"and back");
+    }
+
+    #[tokio::test]
+    async fn unknown_skey_is_refused() {
+        let (client, server) = duplex(4096);
+        let (initiated, accepted) =
+            tokio::join!(initiate(client, [9; 20], EncryptionPolicy::Prefer), async {
+                let accepted = accept_inbound(server, &[INFO_HASH], EncryptionPolicy::Prefer).await;
+                // Hang up, as the session does, so the initiator sees the refusal.
+                accepted.map(|(_, info_hash)| info_hash)
+            },);
+        let e = accepted.unwrap_err();
+        assert!(format!("{e:#}").contains("torrent we don't have"), "{e:#}");
+        assert!(initiated.is_err());
+    }
+}
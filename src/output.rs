@@ -0,0 +1,218 @@
+use serde::Serialize;
+
+use crate::{
+    peers::Peer,
+    session::{TorrentState, TorrentStatus},
+    torrent::{TorrentFile, UrlList},
+};
+
+/// What `info --format json` prints.
+#[derive(Debug, Serialize)]
+pub struct TorrentReport {
+    pub name: String,
+    /// Total size of the files, not counting pad files.
+    pub length: usize,
+    pub piece_length: usize,
+    pub pieces: usize,
+    pub info_hash: InfoHashReport,
+    pub meta_version: Option<u8>,
+    pub private: bool,
+    pub source: Option<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    /// Tiers of tracker URLs; `announce` on its own makes a single tier.
+    pub trackers: Vec<Vec<String>>,
+    /// BEP 19 web seeds.
+    pub web_seeds: Vec<String>,
+    /// BEP 17 HTTP seeds.
+    pub http_seeds: Vec<String>,
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InfoHashReport {
+    /// Hex SHA-1 of the info dictionary, for v1 and hybrid torrents.
+    pub v1: Option<String>,
+    /// Hex SHA-256 of the info dictionary, for v2 and hybrid torrents.
+    pub v2: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    /// Path components, starting with the torrent's name.
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Where the file starts in the torrent's byte stream.
+    pub offset: usize,
+    /// The first and last piece holding the file's data; `None` for empty files.
+    pub pieces: Option<[usize; 2]>,
+    pub executable: bool,
+    pub hidden: bool,
+    /// Where a symlink points, relative to the torrent's root.
+    pub symlink: Option<Vec<String>>,
+}
+
+impl TorrentReport {
+    pub fn new(t: &TorrentFile) -> Self {
+        let info = &t.info;
+        let files: Vec<FileReport> = info
+            .files()
+            .into_iter()
+            .filter(|file| !file.attr.pad)
+            .map(|file| FileReport {
+                pieces: (file.length > 0).then(|| {
+                    [
+                        file.offset / info.plength,
+                        (file.offset + file.length - 1) / info.plength,
+                    ]
+                }),
+                path: file.path,
+                length: file.length,
+                offset: file.offset,
+                executable: file.attr.executable,
+                hidden: file.attr.hidden,
+                symlink: file.symlink_path,
+            })
+            .collect();
+        let trackers = match (&t.announce_list, &t.announce) {
+            (Some(tiers), _) if !tiers.is_empty() => tiers.clone(),
+            (_, Some(announce)) => vec![vec![announce.clone()]],
+            _ => Vec::new(),
+        };
+        Self {
+            name: info.name.clone(),
+            length: files.iter().map(|file| file.length).sum(),
+            piece_length: info.plength,
+            pieces: info.num_pieces(),
+            info_hash: InfoHashReport {
+                v1: info.keys.is_some().then(|| hex::encode(t.info_hash())),
+                v2: t.info_hash_v2().map(hex::encode),
+            },
+            meta_version: info.meta_version,
+            private: info.is_private(),
+            source: info.source.clone(),
+            comment: t.comment.clone(),
+            created_by: t.created_by.clone(),
+            creation_date: t.creation_date,
+            trackers,
+            web_seeds: t.url_list.as_ref().map(UrlList::urls).unwrap_or_default(),
+            http_seeds: t.httpseeds.clone().unwrap_or_default(),
+            files,
+        }
+    }
+}
+
+/// What `peers --format json` prints.
+#[derive(Debug, Serialize)]
+pub struct PeersReport {
+    pub peers: Vec<PeerReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerReport {
+    pub address: String,
+    pub port: u16,
+    /// Hex peer id, when the tracker gave one.
+    pub peer_id: Option<String>,
+}
+
+impl PeersReport {
+    pub fn new(peers: &[Peer]) -> Self {
+        Self {
+            peers: peers
+                .iter()
+                .map(|peer| PeerReport {
+                    address: peer.ip4.ip().to_string(),
+                    port: peer.ip4.port(),
+                    peer_id: peer.peer_id.map(hex::encode),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// One torrent in the list the daemon's `GET /torrents` returns.
+#[derive(Debug, Serialize)]
+pub struct TorrentStatusReport {
+    pub info_hash: String,
+    pub name: String,
+    /// `checking`, `downloading`, `seeding`, `paused` or `error`.
+    pub state: &'static str,
+    /// What went wrong, in the `error` state.
+    pub error: Option<String>,
+    pub pieces_have: usize,
+    pub pieces_wanted: usize,
+}
+
+impl TorrentStatusReport {
+    pub fn new(status: &TorrentStatus) -> Self {
+        let (state, error) = match &status.state {
+            TorrentState::Checking => ("checking", None),
+            TorrentState::Downloading => ("downloading", None),
+            TorrentState::Seeding => ("seeding", None),
+            TorrentState::Paused => ("paused", None),
+            TorrentState::Error(e) => ("error", Some(e.clone())),
+        };
+        Self {
+            info_hash: hex::encode(status.info_hash),
+            name: status.name.clone(),
+            state,
+            error,
+            pieces_have: status.pieces_have,
+            pieces_wanted: status.pieces_wanted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrent_report_has_the_documented_shape() {
+        let bytes = b"d8:announce10:http://a/a13:announce-listll10:http://a/ael10:http://b/bee7:comment2:hi13:creation datei7e4:infod5:filesld6:lengthi3e4:pathl5:a.binee\
+d4:attr1:p6:lengthi13e4:pathl4:.pad2:13eed4:attr1:x6:lengthi16e4:pathl3:sub5:b.bineee4:name1:t12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb7:privatei1e6:source1:se8:url-list10:http://w/we";
+        let torrent = TorrentFile::from_bytes(bytes).unwrap();
+        let report = serde_json::to_value(TorrentReport::new(&torrent)).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "name": "t",
+                "length": 19,
+                "piece_length": 16,
+                "pieces": 2,
+                "info_hash": {"v1": hex::encode(torrent.info_hash()), "v2": null},
+                "meta_version": null,
+                "private": true,
+                "source": "s",
+                "comment": "hi",
+                "created_by": null,
+                "creation_date": 7,
+                "trackers": [["http://a/a"], ["http://b/b"]],
+                "web_seeds": ["http://w/w"],
+                "http_seeds": [],
+                "files": [
+                    {
+                        "path": ["t", "a.bin"],
+                        "length": 3,
+                        "offset": 0,
+                        "pieces": [0, 0],
+                        "executable": false,
+                        "hidden": false,
+                        "symlink": null,
+                    },
+                    {
+                        "path": ["t", "sub", "b.bin"],
+                        "length": 16,
+                        "offset": 16,
+                        "pieces": [1, 1],
+                        "executable": true,
+                        "hidden": false,
+                        "symlink": null,
+                    },
+                ],
+            })
+        );
+    }
+}
@@ -1,317 +1,661 @@
-pub mod peers {
-    use anyhow::Result;
-    use bytes::{Buf, BufMut, BytesMut};
-    use futures_util::{SinkExt, StreamExt};
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
-    use serde::ser::{Serialize, Serializer};
-    use sha1::Digest;
-    use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    use std::sync::Arc;
-    use std::time::Duration;
-    use tokio::net::TcpStream;
-    use tokio::sync::mpsc;
-    use tokio::time;
-    use tokio_util::codec::Decoder;
-    use tokio_util::codec::Encoder;
-
-    use crate::activepeer::activepeer::ActivePeer;
-
-    const BLOCK_MAX: usize = 1 << 14;
-
-    #[derive(Debug, Clone)]
-    pub struct Peer {
-        pub ip4: SocketAddrV4,
-    }
-
-    impl Peer {
-        pub fn new(ip4: SocketAddrV4) -> Self {
-            Self { ip4 }
-        }
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
+
+use crate::activepeer::ActivePeer;
+use crate::mse::{self, EncryptionPolicy};
+use crate::priority::FilePriority;
+use crate::utp::UtpSocket;
+
+/// Anything a peer connection can run over: a plain socket or an encrypted one.
+pub trait PeerIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerIo for T {}
+pub type PeerStream = Box<dyn PeerIo>;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub ip4: SocketAddrV4,
+    /// Only known when the peer came from a tracker's non-compact peer list.
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl Peer {
+    pub fn new(ip4: SocketAddrV4) -> Self {
+        Self { ip4, peer_id: None }
     }
+}
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<Peer>);
+/// A peer in a tracker's non-compact peer list.
+#[derive(serde::Deserialize)]
+struct PeerDict {
+    ip: String,
+    port: u16,
+    #[serde(rename = "peer id", default, with = "serde_bytes")]
+    peer_id: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Peers(pub Vec<Peer>);
 
-    struct PeersVisitor;
-    impl<'de> Visitor<'de> for PeersVisitor {
-        type Value = Peers;
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+struct PeersVisitor;
+impl<'de> Visitor<'de> for PeersVisitor {
+    type Value = Peers;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number, or a list of peer dictionaries")
+    }
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if !v.len().is_multiple_of(6) {
+            return Err(E::custom(format!("length is {}", v.len())));
         }
-        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
-            }
-            // TODO: use array_chunks when stable; then we can also pattern-match in closure args
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .map(|slice_6| {
-                        Peer::new(SocketAddrV4::new(
-                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                            u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                        ))
-                    })
-                    .collect(),
-            ))
+        // TODO: use array_chunks when stable; then we can also pattern-match in closure args
+        Ok(Peers(
+            v.chunks_exact(6)
+                .map(|slice_6| {
+                    Peer::new(SocketAddrV4::new(
+                        Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
+                        u16::from_be_bytes([slice_6[4], slice_6[5]]),
+                    ))
+                })
+                .collect(),
+        ))
+    }
+    /// The original, non-compact model: a list of dictionaries. Peers that aren't on IPv4
+    /// are left out.
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        while let Some(peer) = seq.next_element::<PeerDict>()? {
+            let Ok(ip) = peer.ip.parse::<Ipv4Addr>() else {
+                continue;
+            };
+            peers.push(Peer {
+                ip4: SocketAddrV4::new(ip, peer.port),
+                peer_id: peer.peer_id.and_then(|id| id.try_into().ok()),
+            });
         }
+        Ok(Peers(peers))
     }
-    impl<'de> Deserialize<'de> for Peers {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            deserializer.deserialize_bytes(PeersVisitor)
+}
+impl<'de> Deserialize<'de> for Peers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PeersVisitor)
+    }
+}
+impl Serialize for Peers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut single_slice = Vec::with_capacity(6 * self.0.len());
+        for peer in &self.0 {
+            single_slice.extend(peer.ip4.ip().octets());
+            single_slice.extend(peer.ip4.port().to_be_bytes());
         }
+        serializer.serialize_bytes(&single_slice)
     }
-    impl Serialize for Peers {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            let mut single_slice = Vec::with_capacity(6 * self.0.len());
-            for peer in &self.0 {
-                single_slice.extend(peer.ip4.ip().octets());
-                single_slice.extend(peer.ip4.port().to_be_bytes());
-            }
-            serializer.serialize_bytes(&single_slice)
+}
+
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct Request {
+    index: [u8; 4],
+    begin: [u8; 4],
+    length: [u8; 4],
+}
+impl Request {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index: index.to_be_bytes(),
+            begin: begin.to_be_bytes(),
+            length: length.to_be_bytes(),
         }
     }
+    pub fn index(&self) -> u32 {
+        u32::from_be_bytes(self.index)
+    }
+    pub fn begin(&self) -> u32 {
+        u32::from_be_bytes(self.begin)
+    }
+    pub fn length(&self) -> u32 {
+        u32::from_be_bytes(self.length)
+    }
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
+        // Safety: Handshake is a POD with repr(c)
+        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+        bytes
+    }
+}
+
+#[repr(C)]
+pub struct Piece<T: ?Sized = [u8]> {
+    index: [u8; 4],
+    begin: [u8; 4],
+    block: T,
+}
 
-    #[derive(Debug, Clone)]
-    #[repr(C)]
-    pub struct Request {
-        index: [u8; 4],
-        begin: [u8; 4],
-        length: [u8; 4],
+impl Piece {
+    pub fn index(&self) -> u32 {
+        u32::from_be_bytes(self.index)
     }
-    impl Request {
-        pub fn new(index: u32, begin: u32, length: u32) -> Self {
-            Self {
-                index: index.to_be_bytes(),
-                begin: begin.to_be_bytes(),
-                length: length.to_be_bytes(),
-            }
-        }
-        pub fn index(&self) -> u32 {
-            u32::from_be_bytes(self.index)
-        }
-        pub fn begin(&self) -> u32 {
-            u32::from_be_bytes(self.begin)
+    pub fn begin(&self) -> u32 {
+        u32::from_be_bytes(self.begin)
+    }
+    pub fn block(&self) -> &[u8] {
+        &self.block
+    }
+    const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
+    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
+        if data.len() < Self::PIECE_LEAD {
+            return None;
         }
-        pub fn length(&self) -> u32 {
-            u32::from_be_bytes(self.length)
+        let n = data.len();
+        // NOTE: The slicing here looks really weird. The reason we do it is because we need the
+        // length part of the fat pointer to Piece to old the length of _just_ the `block` field.
+        // And the only way we can change the length of the fat pointer to Piece is by changing the
+        // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
+        // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
+        // the back!
+        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
+        // Safety: Piece is a POD with repr(c), _and_ the fat pointer data length is the length of
+        // the trailing DST field (thanks to the PIECE_LEAD offset).
+        Some(unsafe { &*piece })
+    }
+}
+
+/// The header shared by `hash request`, `hashes` and `hash reject` (BEP 52): which hashes
+/// of a file's merkle tree are wanted. `hashes` follows it with the hashes themselves.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct HashRequest {
+    pieces_root: [u8; 32],
+    base_layer: [u8; 4],
+    index: [u8; 4],
+    length: [u8; 4],
+    proof_layers: [u8; 4],
+}
+impl HashRequest {
+    pub fn new(
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    ) -> Self {
+        Self {
+            pieces_root,
+            base_layer: base_layer.to_be_bytes(),
+            index: index.to_be_bytes(),
+            length: length.to_be_bytes(),
+            proof_layers: proof_layers.to_be_bytes(),
         }
-        pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-            let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-            // Safety: Handshake is a POD with repr(c)
-            let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-            bytes
+    }
+    pub fn pieces_root(&self) -> [u8; 32] {
+        self.pieces_root
+    }
+    /// The layer `index` and `length` count in; 0 is the layer of 16 KiB blocks.
+    pub fn base_layer(&self) -> u32 {
+        u32::from_be_bytes(self.base_layer)
+    }
+    pub fn index(&self) -> u32 {
+        u32::from_be_bytes(self.index)
+    }
+    pub fn length(&self) -> u32 {
+        u32::from_be_bytes(self.length)
+    }
+    /// How many layers of uncle hashes should follow, to prove the base hashes.
+    pub fn proof_layers(&self) -> u32 {
+        u32::from_be_bytes(self.proof_layers)
+    }
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
+        // Safety: HashRequest is a POD with repr(c)
+        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+        bytes
+    }
+    /// Splits a `hashes` payload into its header and hashes; a `hash request` or
+    /// `hash reject` payload has no hashes.
+    pub fn from_bytes(data: &[u8]) -> Option<(Self, Vec<[u8; 32]>)> {
+        const LEAD: usize = std::mem::size_of::<HashRequest>();
+        if data.len() < LEAD || !(data.len() - LEAD).is_multiple_of(32) {
+            return None;
         }
+        let field = |at: usize| data[at..at + 4].try_into().expect("4 byte field");
+        let request = Self {
+            pieces_root: data[..32].try_into().expect("32 byte root"),
+            base_layer: field(32),
+            index: field(36),
+            length: field(40),
+            proof_layers: field(44),
+        };
+        let hashes = data[LEAD..]
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+            .collect();
+        Some((request, hashes))
     }
+}
 
-    #[repr(C)]
-    pub struct Piece<T: ?Sized = [u8]> {
-        index: [u8; 4],
-        begin: [u8; 4],
-        block: T,
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageTag {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    /// Asks for hashes from a file's merkle tree (BEP 52).
+    HashRequest = 21,
+    /// Answers a `HashRequest`.
+    Hashes = 22,
+    /// Declines a `HashRequest`.
+    HashReject = 23,
+}
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub tag: MessageTag,
+    pub payload: Vec<u8>,
+}
 
-    impl Piece {
-        pub fn index(&self) -> u32 {
-            u32::from_be_bytes(self.index)
+pub struct MessageFramer;
+/// Big enough for a block, or for a `hashes` message with 512 hashes and their proof.
+const MAX: usize = 1 << 16;
+impl Decoder for MessageFramer {
+    type Item = Message;
+    type Error = std::io::Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 4 {
+            // Not enough data to read length marker.
+            return Ok(None);
         }
-        pub fn begin(&self) -> u32 {
-            u32::from_be_bytes(self.begin)
+        // Read length marker.
+        let mut length_bytes = [0u8; 4];
+        length_bytes.copy_from_slice(&src[..4]);
+        let length = u32::from_be_bytes(length_bytes) as usize;
+        if length == 0 {
+            // this is a heartbeat message.
+            // discard it.
+            src.advance(4);
+            // and then try again in case the buffer has more messages
+            return self.decode(src);
         }
-        pub fn block(&self) -> &[u8] {
-            &self.block
+        if src.len() < 5 {
+            // Not enough data to read tag marker.
+            return Ok(None);
         }
-        const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
-        pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
-            if data.len() < Self::PIECE_LEAD {
-                return None;
-            }
-            let n = data.len();
-            // NOTE: The slicing here looks really weird. The reason we do it is because we need the
-            // length part of the fat pointer to Piece to old the length of _just_ the `block` field.
-            // And the only way we can change the length of the fat pointer to Piece is by changing the
-            // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
-            // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
-            // the back!
-            let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-            // Safety: Piece is a POD with repr(c), _and_ the fat pointer data length is the length of
-            // the trailing DST field (thanks to the PIECE_LEAD offset).
-            Some(unsafe { &*piece })
+        // Check that the length is not too large to avoid a denial of
+        // service attack where the server runs out of memory.
+        if length > MAX {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Frame of length {} is too large.", length),
+            ));
         }
-    }
-
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    #[repr(u8)]
-    pub enum MessageTag {
-        Choke = 0,
-        Unchoke = 1,
-        Interested = 2,
-        NotInterested = 3,
-        Have = 4,
-        Bitfield = 5,
-        Request = 6,
-        Piece = 7,
-        Cancel = 8,
-    }
-    #[derive(Debug, Clone)]
-    pub struct Message {
-        pub tag: MessageTag,
-        pub payload: Vec<u8>,
-    }
-
-    pub struct MessageFramer;
-    const MAX: usize = 1 << 16;
-    impl Decoder for MessageFramer {
-        type Item = Message;
-        type Error = std::io::Error;
-        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-            if src.len() < 4 {
-                // Not enough data to read length marker.
-                return Ok(None);
-            }
-            // Read length marker.
-            let mut length_bytes = [0u8; 4];
-            length_bytes.copy_from_slice(&src[..4]);
-            let length = u32::from_be_bytes(length_bytes) as usize;
-            if length == 0 {
-                // this is a heartbeat message.
-                // discard it.
-                src.advance(4);
-                // and then try again in case the buffer has more messages
-                return self.decode(src);
-            }
-            if src.len() < 5 {
-                // Not enough data to read tag marker.
-                return Ok(None);
-            }
-            // Check that the length is not too large to avoid a denial of
-            // service attack where the server runs out of memory.
-            if length > MAX {
+        if src.len() < 4 + length {
+            // The full string has not yet arrived.
+            //
+            // We reserve more space in the buffer. This is not strictly
+            // necessary, but is a good idea performance-wise.
+            src.reserve(4 + length - src.len());
+            // We inform the Framed that we need more bytes to form the next
+            // frame.
+            return Ok(None);
+        }
+        // Use advance to modify src such that it no longer contains
+        // this frame.
+        let tag = match src[4] {
+            0 => MessageTag::Choke,
+            1 => MessageTag::Unchoke,
+            2 => MessageTag::Interested,
+            3 => MessageTag::NotInterested,
+            4 => MessageTag::Have,
+            5 => MessageTag::Bitfield,
+            6 => MessageTag::Request,
+            7 => MessageTag::Piece,
+            8 => MessageTag::Cancel,
+            21 => MessageTag::HashRequest,
+            22 => MessageTag::Hashes,
+            23 => MessageTag::HashReject,
+            tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    format!("Frame of length {} is too large.", length),
-                ));
-            }
-            if src.len() < 4 + length {
-                // The full string has not yet arrived.
-                //
-                // We reserve more space in the buffer. This is not strictly
-                // necessary, but is a good idea performance-wise.
-                src.reserve(4 + length - src.len());
-                // We inform the Framed that we need more bytes to form the next
-                // frame.
-                return Ok(None);
+                    format!("Unknown message type {}.", tag),
+                ))
             }
-            // Use advance to modify src such that it no longer contains
-            // this frame.
-            let tag = match src[4] {
-                0 => MessageTag::Choke,
-                1 => MessageTag::Unchoke,
-                2 => MessageTag::Interested,
-                3 => MessageTag::NotInterested,
-                4 => MessageTag::Have,
-                5 => MessageTag::Bitfield,
-                6 => MessageTag::Request,
-                7 => MessageTag::Piece,
-                8 => MessageTag::Cancel,
-                tag => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Unknown message type {}.", tag),
-                    ))
-                }
-            };
-            let data = if src.len() > 5 {
-                src[5..4 + length].to_vec()
-            } else {
-                Vec::new()
-            };
-            src.advance(4 + length);
-            Ok(Some(Message { tag, payload: data }))
+        };
+        let data = if src.len() > 5 {
+            src[5..4 + length].to_vec()
+        } else {
+            Vec::new()
+        };
+        src.advance(4 + length);
+        Ok(Some(Message { tag, payload: data }))
+    }
+}
+impl Encoder<Message> for MessageFramer {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Don't send a message if it is longer than the other end will
+        // accept.
+        if item.payload.len() + 1 > MAX {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Frame of length {} is too large.", item.payload.len()),
+            ));
         }
+        // Convert the length into a byte array.
+        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
+        // Reserve space in the buffer.
+        dst.reserve(4 /* length */ + 1 /* tag */ + item.payload.len());
+        // Write the length and string to the buffer.
+        dst.extend_from_slice(&len_slice);
+        dst.put_u8(item.tag as u8);
+        dst.extend_from_slice(&item.payload);
+        Ok(())
     }
-    impl Encoder<Message> for MessageFramer {
-        type Error = std::io::Error;
-        fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-            // Don't send a message if it is longer than the other end will
-            // accept.
-            if item.payload.len() + 1 > MAX {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Frame of length {} is too large.", item.payload.len()),
-                ));
-            }
-            // Convert the length into a byte array.
-            let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
-            // Reserve space in the buffer.
-            dst.reserve(4 /* length */ + 1 /* tag */ + item.payload.len());
-            // Write the length and string to the buffer.
-            dst.extend_from_slice(&len_slice);
-            dst.put_u8(item.tag as u8);
-            dst.extend_from_slice(&item.payload);
-            Ok(())
+}
+
+/// Pieces waiting to be downloaded, shared by every peer and web seed.
+pub(crate) struct WorkQueue {
+    pieces: std::sync::Mutex<VecDeque<usize>>,
+    /// Each piece's priority, by piece index; empty when every piece is as wanted as the rest.
+    priorities: Vec<FilePriority>,
+    /// In sequential mode, the playback position: pieces are handed out in order from here.
+    position: std::sync::Mutex<Option<usize>>,
+}
+
+impl WorkQueue {
+    pub fn new(pieces: Vec<usize>) -> Self {
+        WorkQueue {
+            pieces: std::sync::Mutex::new(pieces.into()),
+            priorities: Vec::new(),
+            position: std::sync::Mutex::new(None),
         }
     }
 
-    pub struct WorkQueue {
-        pub sender: mpsc::Sender<usize>,
-        pub receiver: tokio::sync::Mutex<mpsc::Receiver<usize>>,
+    /// Queues `pieces` highest priority first, leaving out the ones that aren't wanted.
+    pub fn with_priorities(pieces: Vec<usize>, priorities: &[FilePriority]) -> Self {
+        let mut pieces: Vec<usize> = pieces
+            .into_iter()
+            .filter(|&piece| priorities[piece] != FilePriority::Skip)
+            .collect();
+        pieces.sort_by_key(|&piece| std::cmp::Reverse(priorities[piece]));
+        Self {
+            priorities: priorities.to_vec(),
+            ..Self::new(pieces)
+        }
     }
 
-    impl WorkQueue {
-        pub fn new(pieces: Vec<usize>) -> Self {
-            let (sender, receiver) = mpsc::channel(pieces.len());
-            for piece in pieces {
-                let _ = sender.try_send(piece); // Load initial pieces
-            }
-            WorkQueue {
-                sender,
-                receiver: tokio::sync::Mutex::new(receiver),
-            }
+    fn priority(&self, piece_index: usize) -> FilePriority {
+        self.priorities
+            .get(piece_index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub async fn get_piece(&self) -> Option<usize> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let Some(position) = *self.position.lock().unwrap() else {
+            return pieces.pop_front();
+        };
+        // The first piece at or after the playback position, wrapping around to the start.
+        let next = pieces
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &piece)| (piece < position, piece))
+            .map(|(i, _)| i)?;
+        pieces.remove(next)
+    }
+
+    /// Puts a piece back, e.g. because the peer that had it went away, behind the queued
+    /// pieces of the same priority and ahead of less wanted ones. A piece that is already
+    /// queued isn't added twice.
+    pub async fn return_piece(&self, piece_index: usize) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if pieces.contains(&piece_index) {
+            return;
         }
+        let priority = self.priority(piece_index);
+        let at = pieces
+            .iter()
+            .position(|&queued| self.priority(queued) < priority)
+            .unwrap_or(pieces.len());
+        pieces.insert(at, piece_index);
+    }
 
-        pub async fn get_piece(&self) -> Option<usize> {
-            let mut receiver = self.receiver.lock().await;
+    /// Switches to sequential mode from `position` on, or back to queue order with `None`.
+    pub fn set_position(&self, position: Option<usize>) {
+        *self.position.lock().unwrap() = position;
+    }
+}
 
-            if receiver.is_empty() {
-                return None;
+/// How `connect_to_peer` should reach peers.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectOptions {
+    pub encryption: EncryptionPolicy,
+    /// When set, every connection attempt races uTP over this socket against TCP.
+    pub utp: Option<Arc<UtpSocket>>,
+}
+
+/// Opens a raw transport to `peer`, over whichever of TCP and uTP connects first.
+async fn connect_transport(peer: &Peer, utp: Option<&UtpSocket>) -> Result<PeerStream> {
+    let timeout_duration = Duration::from_secs(2);
+    let tcp = async {
+        let stream = time::timeout(timeout_duration, TcpStream::connect(peer.ip4)).await??;
+        Ok::<PeerStream, anyhow::Error>(Box::new(stream))
+    };
+    let Some(utp) = utp else {
+        return tcp.await;
+    };
+    let utp = async {
+        let stream = time::timeout(timeout_duration, utp.connect(peer.ip4.into())).await??;
+        Ok::<PeerStream, anyhow::Error>(Box::new(stream))
+    };
+    tokio::pin!(tcp, utp);
+    tokio::select! {
+        result = &mut tcp => match result {
+            Ok(stream) => Ok(stream),
+            Err(_) => utp.await,
+        },
+        result = &mut utp => match result {
+            Ok(stream) => Ok(stream),
+            Err(_) => tcp.await,
+        },
+    }
+}
+
+async fn connect_encrypted(
+    peer: &Peer,
+    info_hash: [u8; 20],
+    options: &ConnectOptions,
+) -> Result<PeerStream> {
+    let stream = connect_transport(peer, options.utp.as_deref()).await?;
+    let stream = time::timeout(
+        Duration::from_secs(10),
+        mse::initiate(stream, info_hash, options.encryption),
+    )
+    .await??;
+    if !stream.is_encrypted() {
+        log::debug!("Peer {:?} chose a plaintext stream", peer.ip4);
+    }
+    Ok(Box::new(stream))
+}
+
+/// Connects to `peer`, trying Message Stream Encryption first unless the options disable it.
+///
+/// Under `EncryptionPolicy::Prefer` a failed encrypted attempt is retried in plaintext.
+pub(crate) async fn connect_to_peer(
+    peer: &Peer,
+    info_hash: [u8; 20],
+    options: &ConnectOptions,
+) -> Option<ActivePeer> {
+    log::debug!("connecting to {:?}", peer.ip4);
+    if options.encryption != EncryptionPolicy::Disable {
+        match connect_encrypted(peer, info_hash, options).await {
+            Ok(stream) => {
+                return Some(ActivePeer::new(
+                    tokio_util::codec::Framed::new(stream, MessageFramer),
+                    peer.ip4,
+                ))
+            }
+            Err(e) => {
+                log::debug!("Encrypted connection to {:?} failed: {e}", peer.ip4);
+                if options.encryption == EncryptionPolicy::Require {
+                    return None;
+                }
             }
+        }
+    }
 
-            receiver.recv().await
+    match connect_transport(peer, options.utp.as_deref()).await {
+        Ok(stream) => Some(ActivePeer::new(
+            tokio_util::codec::Framed::new(stream, MessageFramer),
+            peer.ip4,
+        )),
+        Err(_) => {
+            log::debug!("Failed to connect to peer {:?}", peer.ip4);
+            None
         }
+    }
+}
 
-        pub async fn return_piece(&self, piece_index: usize) {
-            let _ = self.sender.send(piece_index).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn returned_piece_goes_back_by_priority() {
+        use FilePriority::*;
+        let priorities = [High, Low, High, Normal, Skip, Normal];
+        let queue = WorkQueue::with_priorities((0..6).collect(), &priorities);
+        assert_eq!(queue.get_piece().await, Some(0));
+        assert_eq!(queue.get_piece().await, Some(2));
+        assert_eq!(queue.get_piece().await, Some(3));
+
+        // Behind what is left of its own priority, ahead of anything less wanted.
+        queue.return_piece(3).await;
+        queue.return_piece(0).await;
+        queue.return_piece(0).await;
+        let mut order = Vec::new();
+        while let Some(piece) = queue.get_piece().await {
+            order.push(piece);
         }
+        assert_eq!(order, [0, 5, 3, 1]);
+
+        let plain = WorkQueue::new(vec![4, 2]);
+        plain.return_piece(7).await;
+        assert_eq!(plain.get_piece().await, Some(4));
     }
 
-    pub async fn connect_to_peer(peer: &Peer) -> Option<ActivePeer> {
-        let timeout_duration = Duration::from_secs(2);
-        println!("connecting to {:?}", peer.ip4);
-        let connection_attempt =
-            time::timeout(timeout_duration, TcpStream::connect(peer.ip4)).await;
-        match connection_attempt {
-            Ok(Ok(stream)) => Some(ActivePeer::new(tokio_util::codec::Framed::new(
-                stream,
-                MessageFramer,
-            ))),
-            Ok(Err(_)) => {
-                println!("Failed to connect to peer {:?}", peer.ip4);
-                None
-            }
-            Err(_) => {
-                println!("Failed to connect to peer {:?}", peer.ip4);
-                None
+    #[tokio::test]
+    async fn playhead_hands_out_pieces_in_order_from_it() {
+        let queue = WorkQueue::new(vec![5, 1, 7, 3, 0, 6]);
+        assert_eq!(queue.get_piece().await, Some(5));
+
+        queue.set_position(Some(3));
+        assert_eq!(queue.get_piece().await, Some(3));
+        assert_eq!(queue.get_piece().await, Some(6));
+        // A piece that missed its deadline comes back and goes out again once reached.
+        queue.return_piece(3).await;
+        assert_eq!(queue.get_piece().await, Some(3));
+
+        // Seeking back starts from there; past the end it wraps around to the start.
+        queue.set_position(Some(1));
+        assert_eq!(queue.get_piece().await, Some(1));
+        assert_eq!(queue.get_piece().await, Some(7));
+        assert_eq!(queue.get_piece().await, Some(0));
+        assert_eq!(queue.get_piece().await, None);
+
+        // Back to queue order.
+        queue.return_piece(4).await;
+        queue.return_piece(2).await;
+        queue.set_position(None);
+        assert_eq!(queue.get_piece().await, Some(4));
+    }
+
+    #[test]
+    fn hash_messages_round_trip_through_the_framer() {
+        let root = [7; 32];
+        let hashes = vec![[1; 32], [2; 32], [3; 32], [4; 32]];
+        let mut request = HashRequest::new(root, 0, 8, 4, 2);
+        let header = request.as_bytes_mut().to_vec();
+        assert_eq!(header.len(), 48);
+        let mut with_hashes = header.clone();
+        with_hashes.extend(hashes.concat());
+
+        let mut bytes = BytesMut::new();
+        for (tag, payload) in [
+            (MessageTag::HashRequest, header.clone()),
+            (MessageTag::Hashes, with_hashes),
+            (MessageTag::HashReject, header),
+        ] {
+            MessageFramer
+                .encode(Message { tag, payload }, &mut bytes)
+                .unwrap();
+        }
+        // Arriving in pieces makes no difference.
+        let mut arriving = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in bytes.chunks(50) {
+            arriving.extend_from_slice(chunk);
+            while let Some(message) = MessageFramer.decode(&mut arriving).unwrap() {
+                decoded.push(message);
             }
         }
+        assert!(arriving.is_empty());
+        let tags: Vec<_> = decoded.iter().map(|message| message.tag).collect();
+        assert_eq!(
+            tags,
+            [
+                MessageTag::HashRequest,
+                MessageTag::Hashes,
+                MessageTag::HashReject
+            ]
+        );
+        for message in &decoded {
+            let (header, received) = HashRequest::from_bytes(&message.payload).unwrap();
+            assert_eq!(header.pieces_root(), root);
+            assert_eq!(
+                (
+                    header.base_layer(),
+                    header.index(),
+                    header.length(),
+                    header.proof_layers()
+                ),
+                (0, 8, 4, 2)
+            );
+            let expected = if message.tag == MessageTag::Hashes {
+                hashes.clone()
+            } else {
+                Vec::new()
+            };
+            assert_eq!(received, expected);
+        }
+
+        assert!(HashRequest::from_bytes(&decoded[0].payload[..47]).is_none());
+        assert!(HashRequest::from_bytes(&decoded[1].payload[..60]).is_none());
     }
 }
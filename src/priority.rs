@@ -0,0 +1,290 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::str::FromStr;
+
+use crate::{
+    torrent::{FileSlice, Info},
+    Error,
+};
+
+/// How much a file of the torrent is wanted. Pieces are fetched highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    /// Don't download the file and don't create it on disk.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    /// Parses `skip`, `low`, `normal` or `high`, in any case.
+    fn from_str(level: &str) -> Result<Self> {
+        match level.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => bail!("invalid priority {level:?}, expected skip, low, normal or high"),
+        }
+    }
+}
+
+/// Compiles a shell-style glob: `*` and `?` stay within one path component, `**` crosses them.
+pub(crate) fn glob(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).with_context(|| format!("invalid glob {pattern:?}"))
+}
+
+/// The path globs are matched against: relative to the torrent's root, `/`-separated.
+pub fn display_path(file: &FileSlice) -> String {
+    match &file.path[..] {
+        [name] => name.clone(),
+        [_, rest @ ..] => rest.join("/"),
+        [] => String::new(),
+    }
+}
+
+/// Where the file `info` lists at `index` is in `files`; pad files aren't listed, so they
+/// don't count.
+pub fn listed_file(files: &[FileSlice], index: usize) -> Option<usize> {
+    (0..files.len()).filter(|&i| !files[i].attr.pad).nth(index)
+}
+
+/// Parses `GLOB=LEVEL`, e.g. `*.iso=high`.
+pub(crate) fn parse_rule(rule: &str) -> Result<(Regex, FilePriority)> {
+    let Some((pattern, level)) = rule.rsplit_once('=') else {
+        bail!("expected GLOB=LEVEL, got {rule:?}");
+    };
+    let priority = level.parse()?;
+    Ok((glob(pattern)?, priority))
+}
+
+/// Works out each file's priority from `--only` globs, `--files` indices and `GLOB=LEVEL`
+/// rules. Without `only` or `files` every file starts out wanted; rules are applied in order.
+pub fn file_priorities(
+    info: &Info,
+    only: &[String],
+    indices: &[usize],
+    rules: &[String],
+) -> crate::Result<Vec<FilePriority>> {
+    let files = info.files();
+    let indices = indices
+        .iter()
+        .map(|&index| {
+            listed_file(&files, index).ok_or_else(|| {
+                let listed = files.iter().filter(|file| !file.attr.pad).count();
+                Error::Other(format!(
+                    "file index {index} is out of range, the torrent has {listed} files"
+                ))
+            })
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    let only = only
+        .iter()
+        .map(|pattern| glob(pattern))
+        .collect::<Result<Vec<_>>>()?;
+    let rules = rules
+        .iter()
+        .map(|rule| parse_rule(rule))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let path = display_path(file);
+            let selected = (only.is_empty() && indices.is_empty())
+                || indices.contains(&index)
+                || only.iter().any(|glob| glob.is_match(&path));
+            // Pad files are only zeros; they're never fetched for their own sake.
+            if file.attr.pad {
+                return FilePriority::Skip;
+            }
+            let mut priority = if selected {
+                FilePriority::Normal
+            } else {
+                FilePriority::Skip
+            };
+            for (glob, level) in &rules {
+                if glob.is_match(&path) {
+                    priority = *level;
+                }
+            }
+            priority
+        })
+        .collect())
+}
+
+/// A piece is as important as the most important file it overlaps, so pieces on the boundary
+/// of a wanted file are fetched even when the file next to it is skipped.
+pub fn piece_priorities(info: &Info, files: &[FilePriority]) -> Vec<FilePriority> {
+    let mut pieces = vec![FilePriority::Skip; info.num_pieces()];
+    for (file, &priority) in info.files().iter().zip(files) {
+        if file.length == 0 || file.attr.pad {
+            continue;
+        }
+        let first = file.offset / info.plength;
+        let last = (file.offset + file.length - 1) / info.plength;
+        for piece in &mut pieces[first..=last] {
+            *piece = (*piece).max(priority);
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{torrent::TorrentFile, value::Value};
+    use std::collections::BTreeMap;
+
+    /// `a.txt` (10000 bytes), `b/c.iso` (30000) and `b/d.txt` (5000), in 16 KiB pieces: the
+    /// first piece is shared by `a.txt` and `b/c.iso`, the last by `b/c.iso` and `b/d.txt`.
+    fn info() -> Info {
+        torrent(vec![
+            file(&["a.txt"], 10_000, ""),
+            file(&["b", "c.iso"], 30_000, ""),
+            file(&["b", "d.txt"], 5_000, ""),
+        ])
+    }
+
+    fn file(path: &[&str], length: i64, attr: &str) -> Value {
+        let mut file = BTreeMap::from([
+            (b"length".to_vec(), Value::Int(length)),
+            (
+                b"path".to_vec(),
+                Value::List(
+                    path.iter()
+                        .map(|part| Value::Bytes(part.as_bytes().to_vec()))
+                        .collect(),
+                ),
+            ),
+        ]);
+        if !attr.is_empty() {
+            file.insert(b"attr".to_vec(), Value::Bytes(attr.as_bytes().to_vec()));
+        }
+        Value::Dict(file)
+    }
+
+    fn torrent(files: Vec<Value>) -> Info {
+        let info = Value::Dict(BTreeMap::from([
+            (b"files".to_vec(), Value::List(files)),
+            (b"name".to_vec(), Value::Bytes(b"t".to_vec())),
+            (b"piece length".to_vec(), Value::Int(1 << 14)),
+            (b"pieces".to_vec(), Value::Bytes(vec![0; 60])),
+        ]));
+        let torrent = Value::Dict(BTreeMap::from([(b"info".to_vec(), info)]));
+        TorrentFile::from_bytes(&torrent.encode()).unwrap().info
+    }
+
+    #[test]
+    fn file_indices_skip_pad_files() {
+        use FilePriority::*;
+        // As `info` lists them: a.txt is 0 and b.txt is 1.
+        let info = torrent(vec![
+            file(&["a.txt"], 10_000, ""),
+            file(&[".pad", "6384"], 6_384, "p"),
+            file(&["b.txt"], 20_000, ""),
+        ]);
+        assert_eq!(listed_file(&info.files(), 1), Some(2));
+        assert_eq!(
+            file_priorities(&info, &[], &[1], &[]).unwrap(),
+            [Skip, Skip, Normal]
+        );
+        assert_eq!(
+            file_priorities(&info, &[], &[], &[]).unwrap(),
+            [Normal, Skip, Normal]
+        );
+        assert_eq!(
+            piece_priorities(&info, &[Skip, Skip, Normal]),
+            [Skip, Normal, Normal]
+        );
+        let out_of_range = file_priorities(&info, &[], &[2], &[]).unwrap_err();
+        assert!(
+            out_of_range.to_string().contains("has 2 files"),
+            "{out_of_range}"
+        );
+    }
+
+    #[test]
+    fn globs_match_within_or_across_components() {
+        let matches = |pattern: &str, path: &str| glob(pattern).unwrap().is_match(path);
+        assert!(matches("*.iso", "c.iso"));
+        assert!(!matches("*.iso", "b/c.iso"));
+        assert!(matches("**.iso", "b/c.iso"));
+        assert!(matches("b/**", "b/x/y.txt"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(!matches("?", "/"));
+        // Everything else is literal, regex syntax included.
+        assert!(!matches("a.txt", "abtxt"));
+        assert!(matches("(1)+[2].txt", "(1)+[2].txt"));
+    }
+
+    #[test]
+    fn selection_and_rules_give_file_priorities() {
+        use FilePriority::*;
+        let info = info();
+        let priorities = |only: &[&str], indices: &[usize], rules: &[&str]| {
+            let only: Vec<String> = only.iter().map(|s| s.to_string()).collect();
+            let rules: Vec<String> = rules.iter().map(|s| s.to_string()).collect();
+            file_priorities(&info, &only, indices, &rules)
+        };
+        assert_eq!(priorities(&[], &[], &[]).unwrap(), [Normal; 3]);
+        assert_eq!(priorities(&[], &[1], &[]).unwrap(), [Skip, Normal, Skip]);
+        assert_eq!(
+            priorities(&["*.txt"], &[2], &[]).unwrap(),
+            [Normal, Skip, Normal]
+        );
+        assert_eq!(
+            priorities(&[], &[], &["**=LOW", "b/*.iso=high", "a.txt=skip"]).unwrap(),
+            [Skip, High, Low]
+        );
+        // Rules apply to selected and unselected files alike.
+        assert_eq!(
+            priorities(&[], &[0], &["**.iso=high"]).unwrap(),
+            [Normal, High, Skip]
+        );
+
+        assert!(priorities(&[], &[3], &[]).is_err());
+        assert!(priorities(&[], &[], &["*.iso=urgent"]).is_err());
+        assert!(priorities(&[], &[], &["*.iso"]).is_err());
+    }
+
+    #[test]
+    fn boundary_pieces_take_the_most_wanted_file() {
+        use FilePriority::*;
+        let info = info();
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(
+            piece_priorities(&info, &[High, Skip, Low]),
+            [High, Skip, Low]
+        );
+        assert_eq!(piece_priorities(&info, &[Skip, Normal, Skip]), [Normal; 3]);
+        assert_eq!(
+            piece_priorities(&info, &[Low, Skip, Skip]),
+            [Low, Skip, Skip]
+        );
+        assert_eq!(
+            piece_priorities(&info, &[Skip, Skip, High]),
+            [Skip, Skip, High]
+        );
+    }
+}
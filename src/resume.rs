@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+use crate::{
+    activepeer::BLOCK_MAX,
+    peers::Peers,
+    storage::{file_path, DownloadBuffer},
+    torrent::Info,
+};
+
+/// Size and modification time of a file when the resume data was saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    length: u64,
+    /// Seconds since the Unix epoch; 0 for a file that didn't exist.
+    mtime: i64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Self {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Self {
+                length: 0,
+                mtime: 0,
+            };
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0);
+        Self {
+            length: metadata.len(),
+            mtime,
+        }
+    }
+}
+
+/// Which blocks of a piece that wasn't finished are already written. The data itself is read
+/// back from the files when the piece is picked up again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialPiece {
+    index: usize,
+    /// Bitfield of the piece's `BLOCK_MAX`-sized blocks, high bit first.
+    #[serde(with = "serde_bytes")]
+    blocks: Vec<u8>,
+}
+
+/// What is kept in the sidecar file next to a download so it can pick up where it stopped.
+///
+/// Loading it only compares file sizes and modification times, so a restart doesn't have to
+/// hash everything again; when they don't match, the caller falls back to a full recheck.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    /// Bitfield of the pieces that are verified and on disk, high bit first.
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    files: Vec<FileStamp>,
+    partial: Vec<PartialPiece>,
+    /// Peers we knew about, to try before the tracker answers.
+    peers: Peers,
+}
+
+impl ResumeData {
+    /// Captures the state of `buffer`, whose storage must already be flushed to `dir`: only
+    /// which blocks are there is kept, not their data.
+    pub fn new(
+        info_hash: [u8; 20],
+        info: &Info,
+        dir: &Path,
+        buffer: &DownloadBuffer,
+        peers: &Peers,
+    ) -> Result<Self> {
+        let mut pieces = vec![0u8; buffer.have.len().div_ceil(8)];
+        for (i, _) in buffer.have.iter().enumerate().filter(|(_, &have)| have) {
+            pieces[i / 8] |= 0x80 >> (i % 8);
+        }
+        let files = info
+            .files()
+            .iter()
+            .map(|file| Ok(FileStamp::of(&file_path(dir, file)?)))
+            .collect::<Result<_>>()?;
+        let partial = buffer
+            .partial
+            .iter()
+            .map(|(&index, begins)| {
+                let mut blocks = vec![0u8; info.piece_size(index).div_ceil(BLOCK_MAX).div_ceil(8)];
+                // A block that doesn't start on a block boundary can't be told apart from
+                // the one it overlaps, so it is fetched again.
+                for block in begins
+                    .iter()
+                    .filter(|&&begin| begin % BLOCK_MAX == 0)
+                    .map(|begin| begin / BLOCK_MAX)
+                {
+                    blocks[block / 8] |= 0x80 >> (block % 8);
+                }
+                PartialPiece { index, blocks }
+            })
+            .collect();
+        Ok(Self {
+            info_hash: info_hash.to_vec(),
+            pieces,
+            files,
+            partial,
+            peers: peers.clone(),
+        })
+    }
+
+    /// Reads the resume file at `path`, if there is a readable one.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = fs::read(path).ok()?;
+        serde_bencode::from_bytes(&bytes).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_bencode::to_bytes(self).context("encode resume data")?;
+        // Write next to it and rename, so a crash mid-write can't leave a torn file behind.
+        let tmp = path.with_extension("resume.tmp");
+        fs::write(&tmp, bytes).with_context(|| format!("write resume data to {tmp:?}"))?;
+        fs::rename(&tmp, path).with_context(|| format!("write resume data to {path:?}"))
+    }
+
+    /// Whether this data belongs to the torrent and the files haven't changed since.
+    pub fn is_valid(&self, info_hash: [u8; 20], info: &Info, dir: &Path) -> bool {
+        let files = info.files();
+        if self.info_hash != info_hash
+            || self.pieces.len() != info.num_pieces().div_ceil(8)
+            || self.files.len() != files.len()
+        {
+            return false;
+        }
+        files.iter().zip(&self.files).all(|(file, stamp)| {
+            file_path(dir, file).is_ok_and(|path| FileStamp::of(&path) == *stamp)
+        })
+    }
+
+    /// Which pieces are on disk.
+    pub fn have(&self, info: &Info) -> Vec<bool> {
+        (0..info.num_pieces())
+            .map(|i| self.pieces[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect()
+    }
+
+    /// Marks the blocks of unfinished pieces that are already on disk in `buffer`.
+    pub fn restore_partial(&self, info: &Info, buffer: &mut DownloadBuffer) {
+        for piece in &self.partial {
+            if piece.index >= buffer.have.len() || buffer.have[piece.index] {
+                continue;
+            }
+            let nblocks = info.piece_size(piece.index).div_ceil(BLOCK_MAX);
+            for block in (0..nblocks.min(piece.blocks.len() * 8))
+                .filter(|&block| piece.blocks[block / 8] & (0x80 >> (block % 8)) != 0)
+            {
+                buffer.add_block(piece.index, block * BLOCK_MAX);
+            }
+        }
+    }
+
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use super::*;
+    use crate::{disk::DiskIo, storage::FileStorage, TorrentBuilder};
+
+    #[tokio::test]
+    async fn partial_pieces_are_saved_as_block_bitmaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, vec![7; 100_000]).unwrap();
+        let torrent_file = TorrentBuilder::new(&path)
+            .piece_length(1 << 16)
+            .build()
+            .unwrap();
+        let info_hash = torrent_file.info_hash();
+        let info = &torrent_file.info;
+        let storage = FileStorage::new(info, dir.path(), Vec::new()).unwrap();
+        let disk = DiskIo::new(Arc::new(storage), info);
+
+        let mut buffer = DownloadBuffer::new(disk.clone(), vec![false; 2]);
+        buffer.add_block(0, 0);
+        buffer.add_block(0, 2 * BLOCK_MAX);
+        buffer.add_block(1, 2 * BLOCK_MAX);
+        buffer.add_block(1, 5);
+        let data =
+            ResumeData::new(info_hash, info, dir.path(), &buffer, &Peers(Vec::new())).unwrap();
+        // A byte of bitmap per piece rather than 48 KiB of blocks.
+        assert_eq!(
+            data.partial
+                .iter()
+                .map(|piece| piece.blocks.len())
+                .sum::<usize>(),
+            2
+        );
+
+        let resume_file = dir.path().join("data.bin.resume");
+        data.save(&resume_file).unwrap();
+        let data = ResumeData::load(&resume_file).unwrap();
+        assert!(data.is_valid(info_hash, info, dir.path()));
+        let mut restored = DownloadBuffer::new(disk, data.have(info));
+        data.restore_partial(info, &mut restored);
+        assert_eq!(
+            restored.partial_blocks(0),
+            BTreeSet::from([0, 2 * BLOCK_MAX])
+        );
+        assert_eq!(restored.partial_blocks(1), BTreeSet::from([2 * BLOCK_MAX]));
+    }
+}
@@ -0,0 +1,525 @@
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{watch, Semaphore},
+    task::JoinHandle,
+    time,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    activepeer::ActivePeer,
+    dht::{Dht, DhtConfig},
+    download::{Download, DownloadOptions},
+    error::IoContext,
+    lsd::Lsd,
+    mse::{self, EncryptionPolicy},
+    peers::MessageFramer,
+    torrent::{Torrent, TorrentFile},
+    utp::UtpSocket,
+    Error,
+};
+
+/// How often a torrent looks at how its download is doing.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a torrent waits before trying again after failing to start or running out of
+/// peers.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a peer that connects to us gets to say which torrent it wants.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings for `Session::start`.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Port peers connect to over TCP and, with `utp`, over uTP; shared by every torrent.
+    pub listen_port: u16,
+    /// Most peer connections open at once, inbound and outbound, across all torrents.
+    pub max_connections: usize,
+    /// Join the DHT with this configuration; every torrent shares the one node.
+    pub dht: Option<DhtConfig>,
+    /// Look for peers on the local network (BEP 14).
+    pub lsd: bool,
+    pub encryption: EncryptionPolicy,
+    /// Take uTP connections on `listen_port` and race uTP against TCP when connecting to
+    /// peers.
+    pub utp: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            max_connections: 200,
+            dht: None,
+            lsd: false,
+            encryption: EncryptionPolicy::Prefer,
+            utp: true,
+        }
+    }
+}
+
+/// Settings for `Session::add`.
+#[derive(Clone, Default)]
+pub struct AddOptions {
+    /// How the torrent is downloaded. Encryption, uTP and the connection limit are the
+    /// session's and are overridden.
+    pub download: DownloadOptions,
+    /// Add the torrent without starting it.
+    pub paused: bool,
+}
+
+/// Where a torrent in a session is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Checking which pieces are already on disk and looking for peers.
+    Checking,
+    Downloading,
+    /// Every wanted piece is on disk. Peers that connect are still served.
+    Seeding,
+    Paused,
+    /// The download couldn't be started, or every peer gave up on it. It is tried again after
+    /// a while, or straight away on `TorrentHandle::resume`.
+    Error(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentState::Checking => write!(f, "checking"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+/// A snapshot of a torrent in a session.
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Wanted pieces that are on disk; 0 while the torrent isn't running.
+    pub pieces_have: usize,
+    pub pieces_wanted: usize,
+}
+
+/// What a torrent's driver is asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Remove,
+}
+
+/// Many torrents downloading and seeding at once, sharing one peer listener, one DHT node and
+/// one connection limit.
+///
+/// Torrents keep running until they are removed or the session is shut down; dropping the
+/// session doesn't stop them.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: SessionConfig,
+    dht: Option<Dht>,
+    lsd: Option<Lsd>,
+    /// Bound to `listen_port`; every torrent's outgoing uTP connections go through it too.
+    utp: Option<Arc<UtpSocket>>,
+    connections: Arc<Semaphore>,
+    torrents: Mutex<HashMap<[u8; 20], TorrentHandle>>,
+    listeners: Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// A torrent in a session. Cloning gives another handle to the same torrent.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentInner>,
+}
+
+struct TorrentInner {
+    info_hash: [u8; 20],
+    name: String,
+    control: watch::Sender<Control>,
+    state: watch::Sender<TorrentState>,
+    /// The running download, while there is one.
+    download: Mutex<Option<Arc<Download>>>,
+    driver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Session {
+    /// Starts listening for peers and, if configured, joins the DHT and the local network.
+    pub async fn start(config: SessionConfig) -> crate::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", config.listen_port))
+            .await
+            .io_context(|| format!("listen for peers on port {}", config.listen_port))?;
+        let utp = if config.utp {
+            // The same port as TCP, even when `listen_port` leaves the choice to the system.
+            let port = listener
+                .local_addr()
+                .io_context(|| "peer listener address")?
+                .port();
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            Some(Arc::new(UtpSocket::bind(addr).await?))
+        } else {
+            None
+        };
+        let dht = match &config.dht {
+            Some(dht_config) => Some(Dht::start(dht_config.clone()).await?),
+            None => None,
+        };
+        let lsd = if config.lsd {
+            Some(Lsd::start(config.listen_port)?)
+        } else {
+            None
+        };
+        let inner = Arc::new(Inner {
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            config,
+            dht,
+            lsd,
+            utp: utp.clone(),
+            torrents: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(Vec::new()),
+        });
+        let mut listeners = vec![tokio::spawn(accept_peers(inner.clone(), listener))];
+        if let Some(utp) = utp {
+            listeners.push(tokio::spawn(accept_utp_peers(inner.clone(), utp)));
+        }
+        *inner.listeners.lock().unwrap() = listeners;
+        Ok(Self { inner })
+    }
+
+    /// Adds a torrent and, unless it is added paused, starts checking and downloading it.
+    pub fn add(
+        &self,
+        torrent_file: TorrentFile,
+        options: AddOptions,
+    ) -> crate::Result<TorrentHandle> {
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.port = self.inner.config.listen_port;
+        torrent.listening = true;
+        let info_hash = torrent.info_hash;
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::AlreadyAdded(info_hash));
+        }
+
+        let mut download = options.download;
+        download.encryption = self.inner.config.encryption;
+        download.utp = self.inner.config.utp;
+        download.connection_limit = Some(self.inner.connections.clone());
+        let (control, state) = if options.paused {
+            (Control::Pause, TorrentState::Paused)
+        } else {
+            (Control::Run, TorrentState::Checking)
+        };
+        let handle = TorrentHandle {
+            inner: Arc::new(TorrentInner {
+                info_hash,
+                name: torrent.torrent_file.info.name.clone(),
+                control: watch::Sender::new(control),
+                state: watch::Sender::new(state),
+                download: Mutex::new(None),
+                driver: Mutex::new(None),
+            }),
+        };
+        let driver = tokio::spawn(drive(
+            self.inner.clone(),
+            handle.inner.clone(),
+            torrent,
+            download,
+        ));
+        *handle.inner.driver.lock().unwrap() = Some(driver);
+        torrents.insert(info_hash, handle.clone());
+        Ok(handle)
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.inner.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Stops a torrent and takes it out of the session. Its files and resume data stay where
+    /// they are.
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> crate::Result<()> {
+        let Some(handle) = self.inner.torrents.lock().unwrap().remove(info_hash) else {
+            return Err(Error::UnknownTorrent(*info_hash));
+        };
+        handle.inner.control.send_replace(Control::Remove);
+        let driver = handle.inner.driver.lock().unwrap().take();
+        if let Some(driver) = driver {
+            driver.await.context("torrent task")?;
+        }
+        Ok(())
+    }
+
+    /// Stops taking connections and removes every torrent, saving their resume data and the
+    /// DHT's state.
+    pub async fn shutdown(&self) -> crate::Result<()> {
+        for listener in self.inner.listeners.lock().unwrap().drain(..) {
+            listener.abort();
+        }
+        let info_hashes: Vec<[u8; 20]> = self
+            .inner
+            .torrents
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for info_hash in info_hashes {
+            self.remove(&info_hash).await?;
+        }
+        if let Some(dht) = &self.inner.dht {
+            dht.save()?;
+        }
+        Ok(())
+    }
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.inner.state.borrow().clone()
+    }
+
+    /// Follows the torrent's state as it changes.
+    pub fn subscribe(&self) -> watch::Receiver<TorrentState> {
+        self.inner.state.subscribe()
+    }
+
+    /// Whether the torrent was paused, as opposed to running or trying to.
+    pub fn is_paused(&self) -> bool {
+        *self.inner.control.borrow() == Control::Pause
+    }
+
+    /// Disconnects from every peer and saves the torrent's progress until it is resumed.
+    pub fn pause(&self) {
+        self.inner
+            .control
+            .send_if_modified(|control| match control {
+                Control::Run => {
+                    *control = Control::Pause;
+                    true
+                }
+                Control::Pause | Control::Remove => false,
+            });
+    }
+
+    /// Starts a paused torrent again, or retries one in the error state straight away.
+    pub fn resume(&self) {
+        self.inner
+            .control
+            .send_if_modified(|control| match control {
+                Control::Run | Control::Pause => {
+                    *control = Control::Run;
+                    true
+                }
+                Control::Remove => false,
+            });
+    }
+
+    pub async fn status(&self) -> TorrentStatus {
+        let download = self.inner.download.lock().unwrap().clone();
+        let (pieces_have, pieces_wanted) = match download {
+            Some(download) => download.progress().await,
+            None => (0, 0),
+        };
+        TorrentStatus {
+            info_hash: self.inner.info_hash,
+            name: self.inner.name.clone(),
+            state: self.state(),
+            pieces_have,
+            pieces_wanted,
+        }
+    }
+}
+
+/// Runs a torrent's state machine until it is removed.
+async fn drive(
+    session: Arc<Inner>,
+    torrent: Arc<TorrentInner>,
+    metainfo: Torrent,
+    options: DownloadOptions,
+) {
+    let mut control = torrent.control.subscribe();
+    let set_state = |state: TorrentState| {
+        torrent.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    };
+    loop {
+        let wanted = *control.borrow_and_update();
+        match wanted {
+            Control::Remove => return,
+            Control::Pause => {
+                set_state(TorrentState::Paused);
+                if control.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Control::Run => {}
+        }
+
+        set_state(TorrentState::Checking);
+        let (dht, lsd) = if metainfo.torrent_file.info.is_private() {
+            (None, None)
+        } else {
+            (session.dht.as_ref(), session.lsd.as_ref())
+        };
+        let start = Download::start_with_utp(
+            metainfo.clone(),
+            options.clone(),
+            dht,
+            lsd,
+            session.utp.clone(),
+        );
+        let started = tokio::select! {
+            started = start => started,
+            // Paused or removed before it got going.
+            _ = control.changed() => continue,
+        };
+        let download = match started {
+            Ok(download) => Arc::new(download),
+            Err(e) => {
+                log::warn!("{}: {e:#}", torrent.name);
+                set_state(TorrentState::Error(format!("{e:#}")));
+                tokio::select! {
+                    _ = time::sleep(RETRY_INTERVAL) => {}
+                    _ = control.changed() => {}
+                }
+                continue;
+            }
+        };
+        *torrent.download.lock().unwrap() = Some(download.clone());
+
+        let stalled = loop {
+            let complete = download.is_complete().await;
+            if complete {
+                set_state(TorrentState::Seeding);
+            } else if download.workers_done() {
+                break true;
+            } else {
+                set_state(TorrentState::Downloading);
+            }
+            tokio::select! {
+                _ = time::sleep(POLL_INTERVAL) => {}
+                changed = control.changed() => {
+                    if changed.is_err() || *control.borrow() != Control::Run {
+                        break false;
+                    }
+                }
+            }
+        };
+        *torrent.download.lock().unwrap() = None;
+        if let Err(e) = download.shutdown().await {
+            log::warn!("{}: could not save progress: {e:#}", torrent.name);
+        }
+        if stalled {
+            let e = "every peer and web seed gave up before the download finished";
+            set_state(TorrentState::Error(e.to_string()));
+            tokio::select! {
+                _ = time::sleep(RETRY_INTERVAL) => {}
+                _ = control.changed() => {}
+            }
+        }
+    }
+}
+
+/// Takes TCP peer connections for every torrent and hands each to the torrent it asks for.
+async fn accept_peers(session: Arc<Inner>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => admit_peer(&session, stream, addr),
+            Err(e) => log::warn!("Could not accept a peer: {e}"),
+        }
+    }
+}
+
+/// `accept_peers` for uTP connections.
+async fn accept_utp_peers(session: Arc<Inner>, utp: Arc<UtpSocket>) {
+    loop {
+        match utp.accept().await {
+            Ok((stream, addr)) => admit_peer(&session, stream, addr),
+            Err(e) => {
+                log::warn!("Could not accept a uTP peer: {e:#}");
+                return;
+            }
+        }
+    }
+}
+
+/// Works out which torrent a peer that connected to us wants and hands it over.
+fn admit_peer<S>(session: &Arc<Inner>, stream: S, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The listeners are IPv4.
+    let SocketAddr::V4(addr) = addr else {
+        return;
+    };
+    // At the connection limit, new peers are turned away.
+    let Ok(permit) = session.connections.clone().try_acquire_owned() else {
+        return;
+    };
+    let session = session.clone();
+    tokio::spawn(async move {
+        let info_hashes: Vec<[u8; 20]> = session.torrents.lock().unwrap().keys().copied().collect();
+        let accepted = time::timeout(
+            HANDSHAKE_TIMEOUT,
+            mse::accept_inbound(stream, &info_hashes, session.config.encryption),
+        )
+        .await;
+        let (stream, info_hash) = match accepted {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                log::debug!("Turned away {addr}: {e:#}");
+                return;
+            }
+            Err(_) => return,
+        };
+        let download = session
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&info_hash)
+            .and_then(|torrent| torrent.inner.download.lock().unwrap().clone());
+        // Paused or still checking.
+        let Some(download) = download else {
+            return;
+        };
+        let peer = ActivePeer::new(Framed::new(stream, MessageFramer), addr);
+        download.serve_peer(peer, Some(permit));
+    });
+}
@@ -0,0 +1,970 @@
+use anyhow::{bail, Context, Result};
+use memmap2::{MmapMut, MmapOptions};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddrV4,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    disk::DiskIo,
+    error::IoContext,
+    torrent::{FileSlice, Info},
+    Error,
+};
+
+/// Where a torrent's content lives while it downloads.
+///
+/// Blocks are addressed the way the wire protocol does: a piece index and a byte offset into
+/// that piece. Every method takes `&self`, so one storage can be shared by all workers; it is
+/// also the extension point for keeping data somewhere other than the local disk.
+pub trait Storage: Send + Sync {
+    /// Fills `buf` from piece `piece_index`, starting `begin` bytes in. Returns `Ok(false)` when
+    /// some of it isn't stored (yet).
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> crate::Result<bool>;
+
+    /// Stores `data` in piece `piece_index`, starting `begin` bytes in.
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> crate::Result<()>;
+
+    /// Makes sure everything written so far is durable.
+    fn flush(&self) -> crate::Result<()>;
+
+    /// Whether the stored piece matches its hash from the metainfo.
+    fn verify_piece(&self, info: &Info, piece_index: usize) -> crate::Result<bool> {
+        let mut piece = vec![0; info.piece_size(piece_index)];
+        Ok(self.read_block(piece_index, 0, &mut piece)? && info.verify_piece(piece_index, &piece))
+    }
+}
+
+/// The built-in on-disk backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageKind {
+    /// Plain reads and writes on the torrent's files.
+    #[default]
+    File,
+    /// Memory-mapped files; every wanted file is created at its full size up front.
+    Mmap,
+}
+
+/// How files are sized when they are created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    /// Reserve every file's blocks up front (`fallocate`), so a full volume shows right away.
+    Full,
+    /// Set every file to its full length without reserving any blocks.
+    #[default]
+    Sparse,
+    /// Create empty files that grow as pieces are written. Memory-mapped storage can't grow
+    /// files, so it treats this like `Sparse`.
+    None,
+}
+
+/// Grows `f` to `length` bytes the way `mode` asks; a file that is already longer keeps its
+/// length.
+fn preallocate(f: &fs::File, path: &Path, length: u64, mode: Preallocation) -> Result<()> {
+    match mode {
+        Preallocation::None => Ok(()),
+        Preallocation::Sparse => {
+            if f.metadata()?.len() < length {
+                f.set_len(length)
+                    .with_context(|| format!("resize {}", path.display()))?;
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        Preallocation::Full if length > 0 => {
+            use std::os::fd::AsRawFd;
+
+            // Safety: the descriptor belongs to `f`, which outlives the call.
+            let errno = unsafe { libc::posix_fallocate(f.as_raw_fd(), 0, length as libc::off_t) };
+            if errno != 0 {
+                return Err(io::Error::from_raw_os_error(errno))
+                    .with_context(|| format!("allocate {length} bytes for {}", path.display()));
+            }
+            Ok(())
+        }
+        Preallocation::Full => preallocate(f, path, length, Preallocation::Sparse),
+    }
+}
+
+/// Fails if the volume holding `dir` can't fit what is left to write of the wanted files.
+///
+/// Blocks that are already allocated count as written, so a partly downloaded or fully
+/// preallocated file only needs what it is missing.
+#[cfg(unix)]
+pub(crate) fn check_disk_space(info: &Info, dir: &Path, skip: &[bool]) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut required = 0u64;
+    for (file_index, file) in info.files().iter().enumerate() {
+        if skip.get(file_index).copied().unwrap_or(false) || file.attr.pad || file.attr.symlink {
+            continue;
+        }
+        let allocated = fs::metadata(file_path(dir, file)?)
+            .map(|metadata| metadata.blocks() * 512)
+            .unwrap_or(0);
+        required += (file.length as u64).saturating_sub(allocated);
+    }
+    if required == 0 {
+        return Ok(());
+    }
+
+    // The download directory may not exist yet; its closest existing parent is on the
+    // same volume.
+    let volume = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    let c_path = std::ffi::CString::new(volume.as_os_str().as_encoded_bytes())
+        .with_context(|| format!("invalid path {}", volume.display()))?;
+    // Safety: `stat` is plain old data that statvfs fills in.
+    let available = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("check free space on {}", volume.display()));
+        }
+        stat.f_bavail as u64 * stat.f_frsize as u64
+    };
+    if available < required {
+        bail!(
+            "not enough disk space in {}: {required} bytes required, {available} bytes available",
+            volume.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_disk_space(_info: &Info, _dir: &Path, _skip: &[bool]) -> Result<()> {
+    Ok(())
+}
+
+/// Where a file of the torrent lives under the download directory `dir`.
+///
+/// Paths come from the metainfo, so anything that could escape `dir` is refused.
+pub(crate) fn file_path(dir: &Path, file: &FileSlice) -> Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for part in &file.path {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(part),
+            _ => bail!("unsafe path component {part:?} in torrent"),
+        }
+    }
+    Ok(path)
+}
+
+/// Where the parts of boundary pieces that belong to skipped files are kept.
+///
+/// It is laid out like the whole torrent, so a byte's offset in it is its offset in the
+/// torrent; everything that was never written stays a hole.
+pub(crate) fn partfile_path(info: &Info, dir: &Path) -> PathBuf {
+    dir.join(format!(".{}.parts", info.name))
+}
+
+/// The part of a byte range of the torrent that falls inside one file.
+struct Span {
+    file_index: usize,
+    /// Offset of the span within the file.
+    position: usize,
+    /// Where the span sits in the caller's buffer.
+    range: Range<usize>,
+}
+
+/// Splits `len` bytes of the torrent starting at `offset` at file boundaries.
+fn spans(files: &[FileSlice], offset: usize, len: usize) -> Vec<Span> {
+    let end = offset + len;
+    files
+        .iter()
+        .enumerate()
+        .filter_map(|(file_index, file)| {
+            let start = offset.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            (start < stop).then(|| Span {
+                file_index,
+                position: start - file.offset,
+                range: start - offset..stop - offset,
+            })
+        })
+        .collect()
+}
+
+fn open_for_write(path: &Path) -> Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("create directory {}", parent.display()))?;
+    }
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))
+}
+
+/// Sets the executable bit on wanted files marked `x` and creates the wanted symlinks.
+///
+/// `paths` are where the files go, from `file_path`. A symlink's target is made relative to
+/// the link and may only name paths inside the torrent, so it can't reach outside the
+/// download directory.
+fn apply_attributes(files: &[FileSlice], paths: &[PathBuf], skip: &[bool]) -> Result<()> {
+    for (file_index, (file, path)) in files.iter().zip(paths).enumerate() {
+        if skip.get(file_index).copied().unwrap_or(false) || file.attr.pad {
+            continue;
+        }
+        if file.attr.symlink {
+            let Some(target) = &file.symlink_path else {
+                bail!("symlink {} has no target", path.display());
+            };
+            create_symlink(file, path, target)?;
+        } else if file.attr.executable {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                let mut permissions = fs::metadata(path)
+                    .with_context(|| format!("read permissions of {}", path.display()))?
+                    .permissions();
+                // Executable by whoever may read it.
+                let mode = permissions.mode();
+                permissions.set_mode(mode | (mode & 0o444) >> 2);
+                fs::set_permissions(path, permissions)
+                    .with_context(|| format!("make {} executable", path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn create_symlink(file: &FileSlice, path: &Path, target: &[String]) -> Result<()> {
+    if target.is_empty()
+        || target.iter().any(|part| {
+            let mut components = Path::new(part).components();
+            !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            )
+        })
+    {
+        bail!("unsafe symlink target {target:?} for {}", path.display());
+    }
+    // Up from the link's directory to the torrent's root, then down to the target.
+    let mut relative = PathBuf::new();
+    for _ in 0..file.path.len().saturating_sub(2) {
+        relative.push("..");
+    }
+    relative.extend(target);
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            if fs::read_link(path).is_ok_and(|existing| existing == relative) {
+                return Ok(());
+            }
+            fs::remove_file(path).with_context(|| format!("replace {}", path.display()))?;
+        }
+        Ok(_) => bail!("{} is in the way of a symlink", path.display()),
+        Err(_) => {}
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("create directory {}", parent.display()))?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&relative, path)
+        .with_context(|| format!("create symlink {}", path.display()))?;
+    #[cfg(not(unix))]
+    log::warn!(
+        "Skipping symlink {} -> {}: not supported on this platform",
+        path.display(),
+        relative.display()
+    );
+    Ok(())
+}
+
+/// Keeps the torrent in its files under a directory. The parts of skipped files that share a
+/// piece with wanted ones go to the partfile instead.
+pub struct FileStorage {
+    plength: usize,
+    files: Vec<FileSlice>,
+    paths: Vec<PathBuf>,
+    partfile: PathBuf,
+    skip: Vec<bool>,
+    /// Files opened for writing so far, by path.
+    handles: Mutex<HashMap<PathBuf, fs::File>>,
+}
+
+impl FileStorage {
+    /// `skip` marks the files, by index, that aren't wanted and must not be created.
+    pub fn new(info: &Info, dir: &Path, skip: Vec<bool>) -> crate::Result<Self> {
+        let files = info.files();
+        let paths = files
+            .iter()
+            .map(|file| file_path(dir, file))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            plength: info.plength,
+            files,
+            paths,
+            partfile: partfile_path(info, dir),
+            skip,
+            handles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn is_skipped(&self, file_index: usize) -> bool {
+        self.skip.get(file_index).copied().unwrap_or(false)
+    }
+
+    /// Creates the directories and files of the torrent, except for skipped files, and
+    /// sizes them from their length as `mode` asks.
+    ///
+    /// A file that was skipped before may have parts of it in the partfile; those are copied
+    /// over.
+    pub fn create_files(&self, mode: Preallocation) -> crate::Result<()> {
+        for (file_index, file) in self.files.iter().enumerate() {
+            let path = &self.paths[file_index];
+            if self.is_skipped(file_index) || file.attr.pad || file.attr.symlink {
+                continue;
+            }
+            let exists = path.exists();
+            let mut f = open_for_write(path)?;
+            if !exists {
+                if let Ok(mut parts) = fs::File::open(&self.partfile) {
+                    parts
+                        .seek(SeekFrom::Start(file.offset as u64))
+                        .with_context(|| format!("seek in {}", self.partfile.display()))?;
+                    io::copy(&mut parts.take(file.length as u64), &mut f)
+                        .with_context(|| format!("copy {} out of the partfile", path.display()))?;
+                }
+            }
+            preallocate(&f, path, file.length as u64, mode)?;
+        }
+        Ok(apply_attributes(&self.files, &self.paths, &self.skip)?)
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> crate::Result<bool> {
+        let offset = piece_index * self.plength + begin;
+        for span in spans(&self.files, offset, buf.len()) {
+            if self.files[span.file_index].attr.pad {
+                buf[span.range].fill(0);
+                continue;
+            }
+            let mut path = &self.paths[span.file_index];
+            let mut position = span.position;
+            // Files that don't exist may have their boundary pieces in the partfile.
+            if self.is_skipped(span.file_index) || !path.exists() {
+                path = &self.partfile;
+                position += self.files[span.file_index].offset;
+            }
+            let mut f = match fs::File::open(path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e).io_context(|| format!("open {}", path.display())),
+            };
+            f.seek(SeekFrom::Start(position as u64))
+                .io_context(|| format!("seek in {}", path.display()))?;
+            match f.read_exact(&mut buf[span.range]) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e).io_context(|| format!("read {}", path.display())),
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> crate::Result<()> {
+        let offset = piece_index * self.plength + begin;
+        let mut handles = self.handles.lock().unwrap();
+        for span in spans(&self.files, offset, data.len()) {
+            if self.files[span.file_index].attr.pad {
+                continue;
+            }
+            let (path, position) = if self.is_skipped(span.file_index) {
+                let file_offset = self.files[span.file_index].offset;
+                (&self.partfile, file_offset + span.position)
+            } else {
+                (&self.paths[span.file_index], span.position)
+            };
+            let f = match handles.entry(path.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(open_for_write(path)?),
+            };
+            f.seek(SeekFrom::Start(position as u64))
+                .io_context(|| format!("seek in {}", path.display()))?;
+            f.write_all(&data[span.range])
+                .io_context(|| format!("write {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        for (path, f) in self.handles.lock().unwrap().iter() {
+            f.sync_data()
+                .io_context(|| format!("sync {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the whole torrent in memory, for downloads that are consumed in-process.
+pub struct MemoryStorage {
+    plength: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Self {
+        Self {
+            plength: info.plength,
+            data: Mutex::new(vec![0; info.calculate_length()]),
+        }
+    }
+
+    /// A copy of the torrent's bytes, all files concatenated.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> crate::Result<bool> {
+        let offset = piece_index * self.plength + begin;
+        let data = self.data.lock().unwrap();
+        let Some(block) = data.get(offset..offset + buf.len()) else {
+            return Ok(false);
+        };
+        buf.copy_from_slice(block);
+        Ok(true)
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, block: &[u8]) -> crate::Result<()> {
+        let offset = piece_index * self.plength + begin;
+        let mut data = self.data.lock().unwrap();
+        let Some(target) = data.get_mut(offset..offset + block.len()) else {
+            return Err(Error::Other(format!(
+                "block at {offset} runs past the end of the torrent"
+            )));
+        };
+        target.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+/// Maps every file of the torrent into memory. Wanted files are created at their full size up
+/// front; skipped ones are mapped out of the partfile.
+pub struct MmapStorage {
+    plength: usize,
+    files: Vec<FileSlice>,
+    /// One map per file; empty files have none.
+    maps: Vec<Option<Mutex<MmapMut>>>,
+}
+
+impl MmapStorage {
+    pub fn new(info: &Info, dir: &Path, skip: &[bool], mode: Preallocation) -> crate::Result<Self> {
+        let files = info.files();
+        let partfile = partfile_path(info, dir);
+        let mut maps = Vec::with_capacity(files.len());
+        for (file_index, file) in files.iter().enumerate() {
+            let skipped = skip.get(file_index).copied().unwrap_or(false);
+            let (path, base) = if skipped {
+                (partfile.clone(), file.offset)
+            } else {
+                (file_path(dir, file)?, 0)
+            };
+            if (skipped && file.length == 0) || file.attr.pad || file.attr.symlink {
+                maps.push(None);
+                continue;
+            }
+            let f = open_for_write(&path)?;
+            let mode = match mode {
+                // The partfile stays sparse whatever the mode.
+                _ if skipped => Preallocation::Sparse,
+                Preallocation::None => Preallocation::Sparse,
+                mode => mode,
+            };
+            preallocate(&f, &path, (base + file.length) as u64, mode)?;
+            if file.length == 0 {
+                maps.push(None);
+                continue;
+            }
+            // Safety: the map is only reached through its mutex, and nothing else is expected
+            // to truncate the file while the download runs.
+            let map = unsafe {
+                MmapOptions::new()
+                    .offset(base as u64)
+                    .len(file.length)
+                    .map_mut(&f)
+            }
+            .with_context(|| format!("map {}", path.display()))?;
+            maps.push(Some(Mutex::new(map)));
+        }
+        let paths = files
+            .iter()
+            .map(|file| file_path(dir, file))
+            .collect::<Result<Vec<_>>>()?;
+        apply_attributes(&files, &paths, skip)?;
+        Ok(Self {
+            plength: info.plength,
+            files,
+            maps,
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> crate::Result<bool> {
+        let offset = piece_index * self.plength + begin;
+        for span in spans(&self.files, offset, buf.len()) {
+            if self.files[span.file_index].attr.pad {
+                buf[span.range].fill(0);
+                continue;
+            }
+            let Some(map) = &self.maps[span.file_index] else {
+                return Ok(false);
+            };
+            let map = map.lock().unwrap();
+            let len = span.range.len();
+            buf[span.range].copy_from_slice(&map[span.position..span.position + len]);
+        }
+        Ok(true)
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> crate::Result<()> {
+        let offset = piece_index * self.plength + begin;
+        for span in spans(&self.files, offset, data.len()) {
+            if self.files[span.file_index].attr.pad {
+                continue;
+            }
+            let Some(map) = &self.maps[span.file_index] else {
+                return Err(Error::Other(format!(
+                    "file {} is not mapped",
+                    span.file_index
+                )));
+            };
+            let mut map = map.lock().unwrap();
+            let len = span.range.len();
+            map[span.position..span.position + len].copy_from_slice(&data[span.range]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        for map in self.maps.iter().flatten() {
+            map.lock()
+                .unwrap()
+                .flush()
+                .io_context(|| "flush mapped file")?;
+        }
+        Ok(())
+    }
+}
+
+/// Bookkeeping for a running download: which pieces are done and which blocks of unfinished
+/// pieces have arrived. The bytes themselves go through `disk`.
+pub(crate) struct DownloadBuffer {
+    pub disk: DiskIo,
+    /// Pieces that are verified and handed to `disk`.
+    pub have: Vec<bool>,
+    /// Offsets of the blocks that arrived for pieces that aren't finished yet.
+    pub partial: HashMap<usize, BTreeSet<usize>>,
+    /// Which peer sent each block in `partial`, so a bad block can be blamed on its sender.
+    senders: HashMap<(usize, usize), SocketAddrV4>,
+}
+
+impl DownloadBuffer {
+    /// A buffer over `disk`, where the pieces in `have` are already stored.
+    pub fn new(disk: DiskIo, have: Vec<bool>) -> Self {
+        Self {
+            disk,
+            have,
+            partial: HashMap::new(),
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Records a piece that passed its hash check.
+    pub fn mark_have(&mut self, piece_index: usize) {
+        self.have[piece_index] = true;
+        self.discard_partial(piece_index);
+    }
+
+    /// Records a block of a piece that isn't finished yet.
+    pub fn add_block(&mut self, piece_index: usize, begin: usize) {
+        self.partial.entry(piece_index).or_default().insert(begin);
+    }
+
+    /// Records a block of a piece that isn't finished yet, along with the peer that sent it.
+    pub fn add_block_from(&mut self, piece_index: usize, begin: usize, peer: SocketAddrV4) {
+        self.add_block(piece_index, begin);
+        self.senders.insert((piece_index, begin), peer);
+    }
+
+    /// The peer a block came from, if it came from a peer in this session.
+    pub fn block_sender(&self, piece_index: usize, begin: usize) -> Option<SocketAddrV4> {
+        self.senders.get(&(piece_index, begin)).copied()
+    }
+
+    /// Forgets the blocks of a piece, e.g. after it failed its hash check.
+    pub fn discard_partial(&mut self, piece_index: usize) {
+        if let Some(blocks) = self.partial.remove(&piece_index) {
+            for begin in blocks {
+                self.senders.remove(&(piece_index, begin));
+            }
+        }
+    }
+
+    /// The offsets of the blocks that already arrived for a piece.
+    pub fn partial_blocks(&self, piece_index: usize) -> BTreeSet<usize> {
+        self.partial.get(&piece_index).cloned().unwrap_or_default()
+    }
+}
+
+/// How much of one file is backed by verified pieces. Pad files are left out.
+#[derive(Debug, Clone)]
+pub struct FileStatus {
+    /// The file's path inside the torrent, starting with `Info::name`.
+    pub path: PathBuf,
+    pub length: usize,
+    pub verified_bytes: usize,
+    /// Whether a complete file matches the `sha1` from the metainfo, if it has one.
+    pub sha1_matches: Option<bool>,
+}
+
+impl FileStatus {
+    pub fn is_complete(&self) -> bool {
+        self.verified_bytes == self.length && self.sha1_matches != Some(false)
+    }
+}
+
+/// The result of checking stored data against the torrent's piece hashes.
+#[derive(Debug, Clone)]
+pub struct Recheck {
+    /// Whether each piece is stored and matches its hash.
+    pub have: Vec<bool>,
+    pub files: Vec<FileStatus>,
+}
+
+impl Recheck {
+    pub fn bad_pieces(&self) -> Vec<usize> {
+        (0..self.have.len()).filter(|&i| !self.have[i]).collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|&have| have)
+    }
+}
+
+/// Hashes whatever is already in `storage`, piece by piece, on one thread per core.
+pub fn recheck(info: &Info, storage: &dyn Storage) -> crate::Result<Recheck> {
+    let npieces = info.num_pieces();
+    let have = Mutex::new(vec![false; npieces]);
+    let next_piece = AtomicUsize::new(0);
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(npieces.max(1));
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    loop {
+                        let index = next_piece.fetch_add(1, Ordering::Relaxed);
+                        if index >= npieces {
+                            return Ok(());
+                        }
+                        if storage.verify_piece(info, index)? {
+                            have.lock().expect("recheck workers do not panic")[index] = true;
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("recheck worker panicked"))
+    })?;
+    let have = have.into_inner().expect("recheck workers do not panic");
+
+    let files = info
+        .files()
+        .into_iter()
+        .filter(|file| !file.attr.pad)
+        .map(|file| {
+            let file_end = file.offset + file.length;
+            // Only the pieces the file overlaps; an empty file overlaps none.
+            let pieces = file.offset / info.plength..file_end.div_ceil(info.plength);
+            let verified_bytes = pieces
+                .filter(|&i| have.get(i).copied().unwrap_or(false))
+                .map(|i| {
+                    let start = (i * info.plength).max(file.offset);
+                    let end = (i * info.plength + info.piece_size(i)).min(file_end);
+                    end.saturating_sub(start)
+                })
+                .sum();
+            let sha1_matches = match file.sha1 {
+                Some(sha1) if verified_bytes == file.length => {
+                    Some(file_sha1(info, storage, &file)? == Some(sha1))
+                }
+                _ => None,
+            };
+            Ok(FileStatus {
+                path: file.path.iter().collect(),
+                length: file.length,
+                verified_bytes,
+                sha1_matches,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Recheck { have, files })
+}
+
+/// Hashes one file's contents as they are in `storage`, for comparing with its `sha1`.
+/// Returns `None` if some of it isn't stored.
+fn file_sha1(info: &Info, storage: &dyn Storage, file: &FileSlice) -> Result<Option<[u8; 20]>> {
+    let mut hasher = Sha1::new();
+    if file.length > 0 {
+        let first = file.offset / info.plength;
+        let last = (file.offset + file.length - 1) / info.plength;
+        let mut piece = vec![0; info.plength];
+        for piece_index in first..=last {
+            let piece = &mut piece[..info.piece_size(piece_index)];
+            if !storage.read_block(piece_index, 0, piece)? {
+                return Ok(None);
+            }
+            let piece_start = piece_index * info.plength;
+            let start = file.offset.max(piece_start) - piece_start;
+            let end = (file.offset + file.length).min(piece_start + piece.len()) - piece_start;
+            hasher.update(&piece[start..end]);
+        }
+    }
+    Ok(Some(hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        download::missing_pieces, peers::WorkQueue, priority::piece_priorities,
+        priority::FilePriority, TorrentBuilder,
+    };
+
+    /// `t/a.bin` (20000 bytes), `t/b.bin` (30000) and `t/c.bin` (10000) in 16 KiB pieces,
+    /// written under a new directory. Piece 1 spans `a.bin` and `b.bin`, piece 3 `b.bin`
+    /// and `c.bin`.
+    fn three_files() -> (tempfile::TempDir, Info) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("t");
+        fs::create_dir(&root).unwrap();
+        for (name, length) in [("a.bin", 20_000), ("b.bin", 30_000), ("c.bin", 10_000)] {
+            let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+            fs::write(root.join(name), data).unwrap();
+        }
+        let info = TorrentBuilder::new(&root)
+            .piece_length(1 << 14)
+            .build()
+            .unwrap()
+            .info;
+        (dir, info)
+    }
+
+    /// Writes `data` into `storage` piece by piece, in two blocks per piece.
+    fn write_all(storage: &dyn Storage, plength: usize, data: &[u8]) {
+        for (piece_index, piece) in data.chunks(plength).enumerate() {
+            let (first, second) = piece.split_at(piece.len() / 2);
+            storage.write_block(piece_index, 0, first).unwrap();
+            storage
+                .write_block(piece_index, first.len(), second)
+                .unwrap();
+        }
+        storage.flush().unwrap();
+    }
+
+    /// Reads the whole torrent back in reads that straddle piece and file boundaries.
+    fn read_all(storage: &dyn Storage, plength: usize, length: usize) -> Vec<u8> {
+        let mut data = vec![0xaa; length];
+        for (i, chunk) in data.chunks_mut(7_000).enumerate() {
+            let offset = i * 7_000;
+            let read = storage.read_block(offset / plength, offset % plength, chunk);
+            assert!(read.unwrap(), "read at {offset}");
+        }
+        data
+    }
+
+    /// Every file under `dir`, as paths relative to it.
+    fn files_on_disk(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(next) = dirs.pop() {
+            for entry in fs::read_dir(next).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path.strip_prefix(dir).unwrap().to_path_buf());
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn every_backend_reads_back_what_it_wrote() {
+        let (source, info) = three_files();
+        let data: Vec<u8> = ["a.bin", "b.bin", "c.bin"]
+            .iter()
+            .flat_map(|name| fs::read(source.path().join("t").join(name)).unwrap())
+            .collect();
+        let expected_files: Vec<PathBuf> = ["t/a.bin", "t/b.bin", "t/c.bin"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let memory = MemoryStorage::new(&info);
+        write_all(&memory, info.plength, &data);
+        assert_eq!(read_all(&memory, info.plength, data.len()), data);
+        assert_eq!(memory.contents(), data);
+        assert!(memory.write_block(3, 10_840, &[0; 16]).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = FileStorage::new(&info, dir.path(), vec![false; 3]).unwrap();
+        let mut buf = [0; 16];
+        assert!(!files.read_block(0, 0, &mut buf).unwrap());
+        write_all(&files, info.plength, &data);
+        assert_eq!(read_all(&files, info.plength, data.len()), data);
+        assert_eq!(files_on_disk(dir.path()), expected_files);
+        assert_eq!(
+            fs::read(dir.path().join("t/b.bin")).unwrap(),
+            data[20_000..50_000]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let mapped = MmapStorage::new(&info, dir.path(), &[false; 3], Preallocation::None).unwrap();
+        // Mapped files exist at full size from the start.
+        assert_eq!(files_on_disk(dir.path()), expected_files);
+        assert_eq!(
+            fs::metadata(dir.path().join("t/c.bin")).unwrap().len(),
+            10_000
+        );
+        write_all(&mapped, info.plength, &data);
+        assert_eq!(read_all(&mapped, info.plength, data.len()), data);
+        drop(mapped);
+        assert_eq!(
+            fs::read(dir.path().join("t/b.bin")).unwrap(),
+            data[20_000..50_000]
+        );
+    }
+
+    #[test]
+    fn files_are_sized_as_the_preallocation_mode_asks() {
+        let (_source, info) = three_files();
+        for (mode, lengths) in [
+            (Preallocation::None, [0, 0]),
+            (Preallocation::Sparse, [20_000, 10_000]),
+            (Preallocation::Full, [20_000, 10_000]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let files = FileStorage::new(&info, dir.path(), vec![false, true, false]).unwrap();
+            files.create_files(mode).unwrap();
+            let created: Vec<_> = files_on_disk(dir.path())
+                .iter()
+                .map(|path| fs::metadata(dir.path().join(path)).unwrap().len())
+                .collect();
+            // The skipped b.bin isn't created.
+            assert_eq!(created, lengths, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn pad_files_never_reach_the_disk() {
+        // a.bin (3 bytes) is padded out to the end of piece 0, where b.bin (16 bytes) starts.
+        let torrent = b"d4:infod5:filesld6:lengthi3e4:pathl5:a.bineed4:attr1:p6:lengthi13e4:pathl4:.pad2:13eed6:lengthi16e4:pathl5:b.bineee4:name1:t12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let info = crate::TorrentFile::from_bytes(torrent).unwrap().info;
+        let mut data = vec![0; 32];
+        data[..3].copy_from_slice(b"abc");
+        data[16..].copy_from_slice(b"0123456789abcdef");
+        let expected_files: Vec<PathBuf> =
+            ["t/a.bin", "t/b.bin"].iter().map(PathBuf::from).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = FileStorage::new(&info, dir.path(), vec![false; 3]).unwrap();
+        files.create_files(Preallocation::Sparse).unwrap();
+        // Whatever a peer sends for the padding is dropped, and it reads back as zeros.
+        files.write_block(0, 0, &[b'x'; 16]).unwrap();
+        write_all(&files, info.plength, &data);
+        assert_eq!(read_all(&files, info.plength, data.len()), data);
+        assert_eq!(files_on_disk(dir.path()), expected_files);
+        assert_eq!(fs::read(dir.path().join("t/a.bin")).unwrap(), b"abc");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mapped = MmapStorage::new(&info, dir.path(), &[false; 3], Preallocation::Full).unwrap();
+        mapped.write_block(0, 0, &[b'x'; 16]).unwrap();
+        write_all(&mapped, info.plength, &data);
+        assert_eq!(read_all(&mapped, info.plength, data.len()), data);
+        assert_eq!(files_on_disk(dir.path()), expected_files);
+    }
+
+    #[tokio::test]
+    async fn recheck_reports_a_bad_piece_against_the_files_it_spans() {
+        let (dir, info) = three_files();
+        // Offset 25000 of the torrent is in b.bin, inside piece 1.
+        let b = dir.path().join("t/b.bin");
+        let mut data = fs::read(&b).unwrap();
+        data[5_000] ^= 1;
+        fs::write(&b, data).unwrap();
+
+        let storage = FileStorage::new(&info, dir.path(), vec![false; 3]).unwrap();
+        let recheck = recheck(&info, &storage).unwrap();
+        assert_eq!(recheck.have, [true, false, true, true]);
+        assert_eq!(recheck.bad_pieces(), [1]);
+        let verified: Vec<_> = recheck
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.verified_bytes, file.is_complete()))
+            .collect();
+        assert_eq!(
+            verified,
+            [
+                ("t/a.bin".into(), 16_384, false),
+                // Piece 2, and the part of piece 3 before c.bin starts.
+                ("t/b.bin".into(), 16_384 + 848, false),
+                ("t/c.bin".into(), 10_000, true),
+            ]
+        );
+
+        // Only the bad piece is downloaded again, and only while a file it spans is wanted.
+        use FilePriority::*;
+        for (files, missing) in [
+            (vec![Normal; 3], vec![1]),
+            (vec![Skip, Skip, Normal], vec![]),
+            (vec![High, Skip, Skip], vec![1]),
+        ] {
+            let pieces = piece_priorities(&info, &files);
+            let queue = WorkQueue::with_priorities(missing_pieces(&pieces, &recheck.have), &pieces);
+            let mut queued = Vec::new();
+            while let Some(piece) = queue.get_piece().await {
+                queued.push(piece);
+            }
+            assert_eq!(queued, missing, "{files:?}");
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::{bail, Context, Result};
+use std::{future::Future, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    dht::Dht,
+    download::{Download, DownloadOptions},
+    error::IoContext,
+    lsd::Lsd,
+    priority::{self, FilePriority},
+    torrent::{FileSlice, Info, Torrent},
+    Error,
+};
+
+/// Longest request head the HTTP endpoint accepts.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// The file to stream: the one asked for, counted the way `info` lists them, or else the
+/// largest one. Returns its index in `Info::files`.
+pub fn pick_file(info: &Info, index: Option<usize>) -> crate::Result<usize> {
+    let files = info.files();
+    match index {
+        Some(index) => priority::listed_file(&files, index).ok_or_else(|| {
+            Error::Other(format!(
+                "file index {index} is out of range, the torrent has {} files",
+                files.iter().filter(|file| !file.attr.pad).count()
+            ))
+        }),
+        None => Ok((0..files.len())
+            .filter(|&i| !files[i].attr.pad)
+            .max_by_key(|&i| files[i].length)
+            .unwrap_or(0)),
+    }
+}
+
+/// File priorities that fetch only the streamed file.
+pub fn priorities(info: &Info, file_index: usize) -> Vec<FilePriority> {
+    (0..info.files().len())
+        .map(|i| {
+            if i == file_index {
+                FilePriority::High
+            } else {
+                FilePriority::Skip
+            }
+        })
+        .collect()
+}
+
+/// Writes bytes `start..end` of `file` to `out` in order, waiting for each piece as the
+/// playback position reaches it.
+pub async fn write_range<W: AsyncWrite + Unpin>(
+    download: &Download,
+    file: &FileSlice,
+    start: usize,
+    end: usize,
+    out: &mut W,
+) -> crate::Result<()> {
+    let info = &download.torrent.torrent_file.info;
+    let mut position = file.offset + start;
+    let end = file.offset + end;
+    while position < end {
+        let piece_index = position / info.plength;
+        download.set_playhead(piece_index);
+        download.wait_for_piece(piece_index).await?;
+
+        let Some(piece) = download.disk().read_piece(piece_index).await? else {
+            return Err(Error::Other(format!(
+                "piece {piece_index} went missing from storage"
+            )));
+        };
+        let piece_start = piece_index * info.plength;
+        let chunk_end = end.min(piece_start + piece.len());
+        out.write_all(&piece[position - piece_start..chunk_end - piece_start])
+            .await
+            .io_context(|| "write streamed data")?;
+        position = chunk_end;
+    }
+    out.flush().await.io_context(|| "write streamed data")?;
+    Ok(())
+}
+
+/// Where [`stream`] plays the file out to.
+pub enum StreamOutput<W> {
+    /// Over HTTP, to any number of players; see [`serve_http`].
+    Http(TcpListener),
+    /// Written once from start to end, e.g. to stdout.
+    Writer(W),
+}
+
+/// Downloads one file of `torrent`, the largest if `file_index` is `None`, and plays it out
+/// to `output` while it downloads, until it has all been written or `shutdown` resolves.
+///
+/// `options` apply as given, except that only that file is fetched and in order.
+pub async fn stream<W: AsyncWrite + Unpin>(
+    torrent: Torrent,
+    file_index: Option<usize>,
+    options: DownloadOptions,
+    dht: Option<&Dht>,
+    lsd: Option<&Lsd>,
+    output: StreamOutput<W>,
+    shutdown: impl Future<Output = ()>,
+) -> crate::Result<()> {
+    let info = &torrent.torrent_file.info;
+    let file_index = pick_file(info, file_index)?;
+    let options = DownloadOptions {
+        file_priorities: priorities(info, file_index),
+        sequential: true,
+        ..options
+    };
+    let download = Arc::new(Download::start(torrent, options, dht, lsd).await?);
+    let file = download.torrent.torrent_file.info.files()[file_index].clone();
+
+    let streaming = async {
+        match output {
+            StreamOutput::Http(listener) => {
+                serve_http(download.clone(), file_index, listener).await
+            }
+            StreamOutput::Writer(mut out) => {
+                write_range(&download, &file, 0, file.length, &mut out).await
+            }
+        }
+    };
+    tokio::select! {
+        result = streaming => result?,
+        _ = shutdown => {}
+    }
+    download.shutdown().await
+}
+
+/// Serves one file of the download on `listener`, with Range support so players can seek.
+///
+/// Every request moves the playback position to where it starts reading.
+pub async fn serve_http(
+    download: Arc<Download>,
+    file_index: usize,
+    listener: TcpListener,
+) -> crate::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await.io_context(|| "accept connection")?;
+        let download = download.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(&download, file_index, stream).await {
+                log::warn!("Stream request failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    download: &Download,
+    file_index: usize,
+    mut stream: TcpStream,
+) -> Result<()> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD {
+            bail!("request head too long");
+        }
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte).await.context("read request")? == 0 {
+            return Ok(());
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let method = lines
+        .next()
+        .and_then(|line| line.split(' ').next())
+        .unwrap_or_default()
+        .to_string();
+    let range = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("range")
+            .then(|| value.trim().to_string())
+    });
+
+    let file = &download.torrent.torrent_file.info.files()[file_index];
+    let length = file.length;
+    if method != "GET" && method != "HEAD" {
+        stream
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+    let range = range.map_or(ByteRange::Whole, |range| parse_range(&range, length));
+    let (start, end, status) = match range {
+        ByteRange::Whole => (0, length, "200 OK"),
+        ByteRange::Part(start, end) => (start, end, "206 Partial Content"),
+        ByteRange::Unsatisfiable => {
+            let response = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{length}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            stream.write_all(response.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n",
+        content_type(file),
+        end - start
+    );
+    if range != ByteRange::Whole {
+        response.push_str(&format!(
+            "Content-Range: bytes {}-{}/{length}\r\n",
+            start,
+            end - 1
+        ));
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    if method == "GET" {
+        write_range(download, file, start, end, &mut stream).await?;
+    }
+    Ok(())
+}
+
+/// What a `Range` header asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    Whole,
+    /// A half-open `start..end`.
+    Part(usize, usize),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Headers that aren't one valid byte range, several ranges
+/// among them, are ignored and the whole file is sent, as RFC 9110 allows.
+fn parse_range(range: &str, length: usize) -> ByteRange {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return ByteRange::Whole;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Whole;
+    };
+    let bounds = match (first.trim(), last.trim()) {
+        ("", suffix) => suffix
+            .parse::<usize>()
+            .ok()
+            .map(|suffix| (length.saturating_sub(suffix), length)),
+        (first, "") => first.parse().ok().map(|start| (start, length)),
+        (first, last) => match (first.parse::<usize>(), last.parse::<usize>()) {
+            (Ok(start), Ok(last)) if start <= last => {
+                Some((start, last.saturating_add(1).min(length)))
+            }
+            _ => None,
+        },
+    };
+    match bounds {
+        None => ByteRange::Whole,
+        Some((start, end)) if start < end => ByteRange::Part(start, end),
+        Some(_) => ByteRange::Unsatisfiable,
+    }
+}
+
+fn content_type(file: &FileSlice) -> &'static str {
+    let name = file.path.last().map(String::as_str).unwrap_or_default();
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Points file descriptor 1 at stderr and hands back the real stdout, so progress messages
+/// can't end up in the middle of streamed data.
+#[cfg(unix)]
+pub fn take_stdout() -> crate::Result<tokio::fs::File> {
+    use std::os::fd::FromRawFd;
+
+    // Safety: plain descriptor juggling; the duplicate is owned by the returned file only.
+    unsafe {
+        let stdout = libc::dup(libc::STDOUT_FILENO);
+        if stdout < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error()).io_context(|| "redirect stdout");
+        }
+        Ok(tokio::fs::File::from_std(std::fs::File::from_raw_fd(
+            stdout,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Part(0, 100));
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Part(900, 1000));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Part(900, 1000));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Part(0, 1000));
+        assert_eq!(
+            parse_range("bytes=990-5000", 1000),
+            ByteRange::Part(990, 1000)
+        );
+        assert_eq!(parse_range("bytes= 10 - 19", 1000), ByteRange::Part(10, 20));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(
+            parse_range("bytes=1000-1001", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_serve() {
+        for range in [
+            "bytes=0-1,5-6",
+            "bytes=0-1, 500-",
+            "items=0-1",
+            "bytes=abc",
+            "bytes=5-2",
+            "bytes=-",
+            "bytes=x-1",
+        ] {
+            assert_eq!(parse_range(range, 1000), ByteRange::Whole, "{range}");
+        }
+    }
+}
@@ -1,31 +1,244 @@
 use anyhow::Context;
 use reqwest::{header::USER_AGENT, Client};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
 
 use crate::{
-    hashes::hashes::Hashes,
-    peers::peers::Peer,
+    dht::Dht,
+    edit,
+    hashes::Hashes,
+    lsd::Lsd,
+    merkle,
+    peers::{Peer, Peers},
     tracker::{TrackerRequest, TrackerResponse},
+    Error,
 };
 
+/// How long `Torrent::discover_peers` waits for LAN peers to show up.
+const LSD_WAIT: Duration = Duration::from_secs(1);
+
 /// A Metainfo file (also known as .torrent files).
-#[derive(Debug, Clone, Deserialize, Serialize)]
+///
+/// Built with [`TorrentFile::from_bytes`], or [`crate::TorrentBuilder`] for a new one. It isn't
+/// `Deserialize`, since the info hashes need the info dictionary's bytes as they were.
+#[derive(Debug, Clone, Serialize)]
 pub struct TorrentFile {
     /// The URL of the tracker.
-    pub announce: String,
+    ///
+    /// Trackerless torrents leave this out and rely on the DHT instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    /// Tiers of backup trackers (BEP 12); each tier is a list of tracker URLs.
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Name and version of the program that created the torrent.
+    #[serde(
+        default,
+        rename = "created by",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// Creation time in seconds since the Unix epoch.
+    #[serde(
+        default,
+        rename = "creation date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// DHT nodes suggested by the creator of a trackerless torrent, as `(host, port)` pairs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
+    /// HTTP/FTP servers that serve the torrent's content as plain files (BEP 19).
+    #[serde(default, rename = "url-list", skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+    /// Hoffman-style HTTP seeds (BEP 17): scripts that serve pieces by index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<Vec<String>>,
+    /// BitTorrent v2 piece hashes of every file larger than a piece, keyed by its `pieces root`.
+    #[serde(
+        default,
+        rename = "piece layers",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
     pub info: Info,
+    /// The info dictionary exactly as it appears in the .torrent file. The info hashes are
+    /// taken over these bytes, as `Info` doesn't model every key a creator may have put in.
+    #[serde(skip)]
+    pub(crate) info_bytes: Vec<u8>,
+}
+
+/// The fields of a `TorrentFile` as serde reads them; `info_bytes` is filled in afterwards.
+#[derive(Deserialize)]
+#[serde(remote = "TorrentFile")]
+struct TorrentFileDef {
+    #[serde(default)]
+    announce: Option<String>,
+    #[serde(default, rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default, rename = "created by")]
+    created_by: Option<String>,
+    #[serde(default, rename = "creation date")]
+    creation_date: Option<i64>,
+    #[serde(default)]
+    nodes: Option<Vec<(String, u16)>>,
+    #[serde(default, rename = "url-list")]
+    url_list: Option<UrlList>,
+    #[serde(default)]
+    httpseeds: Option<Vec<String>>,
+    #[serde(default, rename = "piece layers")]
+    piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    info: Info,
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
+}
+
+/// `url-list` may be a single URL or a list of them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl UrlList {
+    pub fn urls(&self) -> Vec<String> {
+        match self {
+            UrlList::One(url) if url.is_empty() => Vec::new(),
+            UrlList::One(url) => vec![url.clone()],
+            UrlList::Many(urls) => urls.iter().filter(|url| !url.is_empty()).cloned().collect(),
+        }
+    }
 }
 impl TorrentFile {
+    /// Parses a .torrent file and, for v2 and hybrid torrents, checks its piece layers against
+    /// the file tree.
+    pub fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
+        let mut t = TorrentFileDef::deserialize(&mut serde_bencode::Deserializer::new(bytes))
+            .map_err(|e| Error::InvalidTorrent(e.to_string()))?;
+        if t.info.keys.is_none() && t.info.file_tree.is_none() {
+            return Err(Error::InvalidTorrent(
+                "neither a file list nor a file tree".into(),
+            ));
+        }
+        if t.info.file_tree.is_some() {
+            t.info.v2_pieces = t
+                .v2_pieces()
+                .map_err(|e| Error::InvalidTorrent(format!("{e:#}")))?;
+            if t.info.keys.is_some() && t.info.v2_pieces.len() != t.info.num_pieces() {
+                return Err(Error::InvalidTorrent(format!(
+                    "hybrid torrent has {} v1 pieces but {} v2 pieces",
+                    t.info.num_pieces(),
+                    t.info.v2_pieces.len()
+                )));
+            }
+        } else if t.info.pieces.is_empty() {
+            return Err(Error::InvalidTorrent("no piece hashes".into()));
+        }
+        t.info_bytes = edit::info_bytes(bytes)?.to_vec();
+        Ok(t)
+    }
+
+    /// The hash peers and trackers know the torrent by.
+    ///
+    /// That is the SHA-1 of the info dictionary when v1 clients can join (v1 and hybrid
+    /// torrents), and the v2 info hash truncated to 20 bytes otherwise.
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_encoded =
-            serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
+        if self.info.keys.is_none() {
+            let mut truncated = [0; 20];
+            truncated.copy_from_slice(&Sha256::digest(&self.info_bytes)[..20]);
+            return truncated;
+        }
+        Sha1::digest(&self.info_bytes).into()
+    }
+
+    /// The SHA-256 of the info dictionary, for torrents with a v2 file tree.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.info.file_tree.as_ref()?;
+        Some(Sha256::digest(&self.info_bytes).into())
+    }
+
+    /// Splits every file in the file tree into pieces, each with the root of its merkle subtree.
+    fn v2_pieces(&self) -> anyhow::Result<Vec<V2Piece>> {
+        let info = &self.info;
+        if info.plength < merkle::BLOCK_SIZE || !info.plength.is_power_of_two() {
+            anyhow::bail!(
+                "v2 piece length {} is not a power of two of at least 16 KiB",
+                info.plength
+            );
+        }
+        let leaves_per_piece = info.plength / merkle::BLOCK_SIZE;
+        let pad = merkle::pad_root(leaves_per_piece);
+        let mut pieces = Vec::new();
+        for (path, file) in info.tree_files() {
+            if file.length == 0 {
+                continue;
+            }
+            let root: [u8; 32] = file
+                .pieces_root
+                .as_deref()
+                .and_then(|root| root.try_into().ok())
+                .with_context(|| format!("{} has no valid pieces root", path.join("/")))?;
+            if file.length <= info.plength {
+                pieces.push(V2Piece {
+                    hash: root,
+                    length: file.length,
+                    leaves: file.length.div_ceil(merkle::BLOCK_SIZE),
+                    pieces_root: root,
+                    first_leaf: 0,
+                });
+                continue;
+            }
+
+            let layer = self
+                .piece_layers
+                .as_ref()
+                .and_then(|layers| layers.get(serde_bytes::Bytes::new(&root)))
+                .with_context(|| format!("missing piece layer for {}", path.join("/")))?;
+            let npieces = file.length.div_ceil(info.plength);
+            if layer.len() != npieces * 32 {
+                anyhow::bail!(
+                    "piece layer for {} has {} bytes, expected {}",
+                    path.join("/"),
+                    layer.len(),
+                    npieces * 32
+                );
+            }
+            let hashes: Vec<[u8; 32]> = layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+                .collect();
+            if merkle::root(hashes.clone(), npieces, pad) != root {
+                anyhow::bail!(
+                    "piece layer for {} doesn't match its pieces root",
+                    path.join("/")
+                );
+            }
+            for (index, hash) in hashes.into_iter().enumerate() {
+                pieces.push(V2Piece {
+                    hash,
+                    length: (file.length - index * info.plength).min(info.plength),
+                    leaves: leaves_per_piece,
+                    pieces_root: root,
+                    first_leaf: index * leaves_per_piece,
+                });
+            }
+        }
+        Ok(pieces)
     }
 }
 
@@ -45,15 +258,302 @@ pub struct Info {
     #[serde(rename = "piece length")]
     pub plength: usize,
     /// Each entry of `pieces` is the SHA1 hash of the piece at the corresponding index.
+    ///
+    /// v2-only torrents leave this out and hash pieces with `file_tree` and `piece layers`.
+    #[serde(default, skip_serializing_if = "Hashes::is_empty")]
     pub pieces: Hashes,
+    /// When set to 1, peers may only come from the trackers in the metainfo (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// Tags the torrent with the site it was made for, giving it a distinct info hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// 2 for BitTorrent v2 and hybrid torrents (BEP 52).
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+    /// The v2 file tree: directories map names to subtrees and files hold a single `""` entry.
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTree>>,
+    /// The v1 `length` or `files`; absent in v2-only torrents.
     #[serde(flatten)]
-    pub keys: Keys,
+    pub keys: Option<Keys>,
+    /// Filled in from `file_tree` and `piece layers` by `TorrentFile::from_bytes`.
+    #[serde(skip)]
+    pub v2_pieces: Vec<V2Piece>,
+}
+
+/// A node of the v2 file tree.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTree {
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Dir(BTreeMap<String, FileTree>),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct V2File {
+    pub length: usize,
+    /// The root of the file's merkle tree of 16 KiB blocks; absent for empty files.
+    #[serde(
+        default,
+        rename = "pieces root",
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+/// What a v2 piece hashes to: the root of the merkle subtree over its blocks.
+#[derive(Debug, Clone)]
+pub struct V2Piece {
+    pub hash: [u8; 32],
+    /// The bytes of the piece that belong to the file; the rest, if any, is padding.
+    pub length: usize,
+    /// How many leaves the subtree has, counting those past the end of the file.
+    pub leaves: usize,
+    /// The root of the file the piece belongs to, which names the file in hash requests.
+    pub pieces_root: [u8; 32],
+    /// The index of the piece's first block among the file's blocks.
+    pub first_leaf: usize,
+}
+
+/// Where a file sits in the torrent's byte stream, which is all files concatenated in order.
+#[derive(Debug, Clone)]
+pub struct FileSlice {
+    /// The path relative to the download directory, starting with `Info::name`.
+    pub path: Vec<String>,
+    pub offset: usize,
+    pub length: usize,
+    pub attr: Attributes,
+    /// Where a symlink points, relative to the torrent's root directory.
+    pub symlink_path: Option<Vec<String>>,
+    /// SHA-1 of the whole file, when the creator included one.
+    pub sha1: Option<[u8; 20]>,
+}
+
+/// The flags in a file's `attr` string (BEP 47).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// `p`: zeros that align the next file to a piece boundary; never written to disk.
+    pub pad: bool,
+    /// `x`: should be executable.
+    pub executable: bool,
+    /// `h`: should be hidden.
+    pub hidden: bool,
+    /// `l`: a symlink to `symlink path`; its length is 0.
+    pub symlink: bool,
+}
+
+impl Attributes {
+    /// Reads an `attr` string; letters this client doesn't know are ignored.
+    pub fn parse(attr: &str) -> Self {
+        Self {
+            pad: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
 }
 
 impl Info {
+    /// Private torrents (BEP 27) may only get peers from their trackers, not the DHT or LSD.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn files(&self) -> Vec<FileSlice> {
+        let Some(keys) = &self.keys else {
+            return self.v2_files();
+        };
+        match keys {
+            Keys::SingleFile { length } => vec![FileSlice {
+                path: vec![self.name.clone()],
+                offset: 0,
+                length: *length,
+                attr: Attributes::default(),
+                symlink_path: None,
+                sha1: None,
+            }],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|file| {
+                        let slice = FileSlice {
+                            path: std::iter::once(self.name.clone())
+                                .chain(file.path.iter().cloned())
+                                .collect(),
+                            offset,
+                            length: file.length,
+                            attr: file.attributes(),
+                            symlink_path: file.symlink_path.clone(),
+                            sha1: file.sha1.as_deref().and_then(|sha1| sha1.try_into().ok()),
+                        };
+                        offset += file.length;
+                        slice
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Files in a v2-only torrent, with pad files in between so each starts on a piece boundary.
+    fn v2_files(&self) -> Vec<FileSlice> {
+        let files = self.tree_files();
+        let single = files.len() == 1 && files[0].0.len() == 1;
+        let mut offset = 0;
+        let mut slices = Vec::new();
+        for (path, file) in files {
+            let gap = (self.plength - offset % self.plength) % self.plength;
+            if file.length > 0 && gap > 0 {
+                slices.push(FileSlice {
+                    path: vec![self.name.clone(), ".pad".into(), gap.to_string()],
+                    offset,
+                    length: gap,
+                    attr: Attributes {
+                        pad: true,
+                        ..Default::default()
+                    },
+                    symlink_path: None,
+                    sha1: None,
+                });
+                offset += gap;
+            }
+            let path = if single {
+                vec![self.name.clone()]
+            } else {
+                std::iter::once(self.name.clone()).chain(path).collect()
+            };
+            slices.push(FileSlice {
+                path,
+                offset,
+                length: file.length,
+                attr: Attributes::parse(file.attr.as_deref().unwrap_or_default()),
+                symlink_path: None,
+                sha1: None,
+            });
+            offset += file.length;
+        }
+        slices
+    }
+
+    /// The files of the v2 file tree in order, each with its path below the root.
+    fn tree_files(&self) -> Vec<(Vec<String>, &V2File)> {
+        fn walk<'a>(
+            tree: &'a BTreeMap<String, FileTree>,
+            prefix: &mut Vec<String>,
+            out: &mut Vec<(Vec<String>, &'a V2File)>,
+        ) {
+            for (name, node) in tree {
+                prefix.push(name.clone());
+                match node {
+                    FileTree::File { file } => out.push((prefix.clone(), file)),
+                    FileTree::Dir(subtree) => walk(subtree, prefix, out),
+                }
+                prefix.pop();
+            }
+        }
+        let mut out = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            walk(tree, &mut Vec::new(), &mut out);
+        }
+        out
+    }
+
+    /// The number of pieces, from the v1 hashes if there are any and the v2 file tree otherwise.
+    pub fn num_pieces(&self) -> usize {
+        if self.pieces.is_empty() {
+            self.v2_pieces.len()
+        } else {
+            self.pieces.0.len()
+        }
+    }
+
+    /// The size of a piece; only the last one may be shorter than `plength`.
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        if piece_index == self.num_pieces() - 1 {
+            let md = self.calculate_length() % self.plength;
+            if md == 0 {
+                self.plength
+            } else {
+                md
+            }
+        } else {
+            self.plength
+        }
+    }
+
+    /// Checks downloaded piece data against its SHA-1 from `pieces` and, for v2 and hybrid
+    /// torrents, against the merkle root of its blocks; both must match when both are present.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        if !self.pieces.is_empty() {
+            let mut hasher = Sha1::new();
+            hasher.update(data);
+            let hash: [u8; 20] = hasher.finalize().into();
+            if self.pieces.0.get(piece_index) != Some(&hash) {
+                return false;
+            }
+        }
+        if self.file_tree.is_some() {
+            let Some(piece) = self.v2_pieces.get(piece_index) else {
+                return false;
+            };
+            let Some(data) = data.get(..piece.length) else {
+                return false;
+            };
+            return merkle::root(merkle::leaves(data), piece.leaves, [0; 32]) == piece.hash;
+        }
+        true
+    }
+
+    /// Checks each block of a piece against its leaf hash, as sent by a peer in a `hashes`
+    /// message, and returns the offsets of the blocks that don't match.
+    ///
+    /// Returns `None` if the leaf hashes don't add up to the piece's hash, in which case the
+    /// peer that sent them can't be trusted either.
+    pub fn bad_blocks(
+        &self,
+        piece_index: usize,
+        data: &[u8],
+        leaves: &[[u8; 32]],
+    ) -> Option<Vec<usize>> {
+        let piece = self.v2_pieces.get(piece_index)?;
+        if leaves.len() < piece.leaves
+            || merkle::root(leaves.to_vec(), leaves.len(), [0; 32]) != piece.hash
+        {
+            return None;
+        }
+        let data = data.get(..piece.length)?;
+        Some(
+            merkle::leaves(data)
+                .iter()
+                .zip(leaves)
+                .enumerate()
+                .filter(|(_, (hash, expected))| hash != expected)
+                .map(|(block, _)| block * merkle::BLOCK_SIZE)
+                .collect(),
+        )
+    }
+
     pub fn calculate_length(&self) -> usize {
-        match &self.keys {
-            Keys::SingleFile { length } => length.clone(),
+        let Some(keys) = &self.keys else {
+            return self
+                .v2_files()
+                .last()
+                .map_or(0, |file| file.offset + file.length);
+        };
+        match keys {
+            Keys::SingleFile { length } => *length,
             Keys::MultiFile { files } => {
                 let mut sum: usize = 0;
                 for file in files.iter() {
@@ -86,6 +586,25 @@ pub struct File {
     /// Subdirectory names for this file, the last of which is the actual file name
     /// (a zero length list is an error case).
     pub path: Vec<String>,
+    /// Flags such as `p` for pad files and `x` for executables (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    /// Target of a symlink (attr `l`), as path components relative to the torrent's root.
+    #[serde(
+        default,
+        rename = "symlink path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+    /// SHA-1 of the file's contents.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<Vec<u8>>,
+}
+
+impl File {
+    pub fn attributes(&self) -> Attributes {
+        Attributes::parse(self.attr.as_deref().unwrap_or_default())
+    }
 }
 
 #[derive(Debug)]
@@ -95,10 +614,16 @@ pub struct DownloadInfo {
     pub left: usize,
 }
 
+#[derive(Clone)]
 pub struct Torrent {
     pub torrent_file: TorrentFile,
     pub peers: Vec<Peer>,
     pub info_hash: [u8; 20],
+    /// The port we take peer connections on, as announced to the tracker and the DHT.
+    pub port: u16,
+    /// Whether anything accepts peer connections on `port`. Only then is it announced to the
+    /// DHT; otherwise the DHT is only asked for peers.
+    pub listening: bool,
 }
 
 impl Torrent {
@@ -107,13 +632,73 @@ impl Torrent {
             torrent_file: torrent_file.clone(),
             peers: Vec::new(),
             info_hash: torrent_file.info_hash(),
+            port: 6881,
+            listening: false,
         }
     }
 
-    pub async fn contact_tracker(&self) -> anyhow::Result<TrackerResponse> {
+    /// Collects peer candidates from the tracker and, if given, the DHT and the LAN.
+    ///
+    /// LAN peers come first in the returned list. Failing to reach the tracker is only an error
+    /// if no other source comes up with any peers either.
+    pub async fn discover_peers(
+        &self,
+        dht: Option<&Dht>,
+        lsd: Option<&Lsd>,
+    ) -> crate::Result<Peers> {
+        let (dht, lsd) = if self.torrent_file.info.is_private() {
+            (None, None)
+        } else {
+            (dht, lsd)
+        };
+        if let Some(lsd) = lsd {
+            if let Err(e) = lsd.add_torrent(self.info_hash).await {
+                log::warn!("{e:#}");
+            }
+        }
+
+        let mut peers = Vec::new();
+        let mut tracker_error = None;
+        if self.torrent_file.announce.is_some() {
+            match self.contact_tracker().await {
+                Ok(tracker_info) => peers.extend(tracker_info.peers.0),
+                Err(e) => tracker_error = Some(e),
+            }
+        }
+        if let Some(dht) = dht {
+            peers.extend(if self.listening {
+                dht.announce(self.info_hash, self.port).await
+            } else {
+                dht.get_peers(self.info_hash).await
+            });
+        }
+        if let Some(lsd) = lsd {
+            let mut lan_peers = lsd.peers(&self.info_hash);
+            if lan_peers.is_empty() {
+                // Give clients on the LAN a moment to answer our announce.
+                tokio::time::sleep(LSD_WAIT).await;
+                lan_peers = lsd.peers(&self.info_hash);
+            }
+            peers.splice(0..0, lan_peers);
+        }
+
+        let mut seen = HashSet::new();
+        peers.retain(|peer: &Peer| seen.insert(peer.ip4));
+        match tracker_error {
+            Some(e) if peers.is_empty() => Err(e.context("getting info from tracker").into()),
+            _ => Ok(Peers(peers)),
+        }
+    }
+
+    pub(crate) async fn contact_tracker(&self) -> anyhow::Result<TrackerResponse> {
+        let announce = self
+            .torrent_file
+            .announce
+            .as_ref()
+            .context("torrent has no tracker")?;
         let request = TrackerRequest {
             peer_id: String::from("00112233445566718890"),
-            port: 6881,
+            port: self.port,
             uploaded: 0,
             downloaded: 0,
             left: self.torrent_file.info.calculate_length(),
@@ -125,7 +710,7 @@ impl Torrent {
             serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
         let tracker_url = format!(
             "{}?{}&info_hash={}",
-            self.torrent_file.announce,
+            announce,
             url_params,
             &urlencode(&self.info_hash),
         );
@@ -144,11 +729,171 @@ impl Torrent {
     }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
+pub fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
     encoded
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{edit::InfoHashes, value::Value};
+
+    /// A hybrid torrent of a single file named `a`, whose v1 piece hashes are those of
+    /// `v1_data` and whose v2 piece layer is that of `v2_data`; the two are the same file
+    /// unless a test wants them to disagree.
+    pub(crate) fn hybrid_torrent(plength: usize, v1_data: &[u8], v2_data: &[u8]) -> Vec<u8> {
+        assert_eq!(v1_data.len(), v2_data.len());
+        let bytes = |bytes: &[u8]| Value::Bytes(bytes.to_vec());
+        let dict = |entries: Vec<(&[u8], Value)>| {
+            Value::Dict(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.to_vec(), value))
+                    .collect(),
+            )
+        };
+        let leaves_per_piece = plength / merkle::BLOCK_SIZE;
+        let layer: Vec<[u8; 32]> = v2_data
+            .chunks(plength)
+            .map(|piece| merkle::root(merkle::leaves(piece), leaves_per_piece, [0; 32]))
+            .collect();
+        let pieces_root = merkle::root(
+            layer.clone(),
+            layer.len(),
+            merkle::pad_root(leaves_per_piece),
+        );
+        let file = dict(vec![(
+            b"",
+            dict(vec![
+                (b"length", Value::Int(v2_data.len() as i64)),
+                (b"pieces root", bytes(&pieces_root)),
+            ]),
+        )]);
+        let info = dict(vec![
+            (b"file tree", dict(vec![(b"a", file)])),
+            (b"length", Value::Int(v1_data.len() as i64)),
+            (b"meta version", Value::Int(2)),
+            (b"name", bytes(b"a")),
+            (b"piece length", Value::Int(plength as i64)),
+            (
+                b"pieces",
+                bytes(
+                    &v1_data
+                        .chunks(plength)
+                        .flat_map(Sha1::digest)
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+        ]);
+        let mut piece_layers = BTreeMap::new();
+        if layer.len() > 1 {
+            piece_layers.insert(pieces_root.to_vec(), bytes(&layer.concat()));
+        }
+        dict(vec![
+            (b"info", info),
+            (b"piece layers", Value::Dict(piece_layers)),
+        ])
+        .encode()
+    }
+
+    #[test]
+    fn hybrid_piece_must_match_both_hashes() {
+        let plength = 2 * merkle::BLOCK_SIZE;
+        let data: Vec<u8> = (0..plength + 7000).map(|i| (i % 251) as u8).collect();
+        let mut other = data.clone();
+        other[100] ^= 1;
+        other[plength + 100] ^= 1;
+        let (first, last) = data.split_at(plength);
+
+        let info = TorrentFile::from_bytes(&hybrid_torrent(plength, &data, &data))
+            .unwrap()
+            .info;
+        assert_eq!(info.num_pieces(), 2);
+        assert!(info.verify_piece(0, first));
+        assert!(info.verify_piece(1, last));
+        assert!(!info.verify_piece(0, &other[..plength]));
+        assert!(!info.verify_piece(1, &other[plength..]));
+        assert!(!info.verify_piece(0, last));
+
+        // Data that matches only one of the two kinds of hash is rejected either way.
+        let bad_v1 = TorrentFile::from_bytes(&hybrid_torrent(plength, &other, &data))
+            .unwrap()
+            .info;
+        assert!(!bad_v1.verify_piece(0, first));
+        assert!(!bad_v1.verify_piece(1, last));
+        let bad_v2 = TorrentFile::from_bytes(&hybrid_torrent(plength, &data, &other))
+            .unwrap()
+            .info;
+        assert!(!bad_v2.verify_piece(0, first));
+        assert!(!bad_v2.verify_piece(1, last));
+    }
+
+    #[test]
+    fn info_hash_covers_keys_info_does_not_model() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa5:x-fooi7ee";
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        let t = TorrentFile::from_bytes(&torrent).unwrap();
+        assert_eq!(t.info_hash(), <[u8; 20]>::from(Sha1::digest(info)));
+        assert_eq!(
+            InfoHashes::of(&torrent).unwrap().v1,
+            Some(t.info_hash()),
+            "`info` and `edit` agree"
+        );
+    }
+
+    #[test]
+    fn bad_blocks_are_found_from_the_leaf_hashes() {
+        let plength = 4 * merkle::BLOCK_SIZE;
+        let data: Vec<u8> = (0..plength + 100).map(|i| (i % 251) as u8).collect();
+        let info = TorrentFile::from_bytes(&hybrid_torrent(plength, &data, &data))
+            .unwrap()
+            .info;
+        let leaves = merkle::leaves(&data[..plength]);
+        let mut bad = data[..plength].to_vec();
+        bad[merkle::BLOCK_SIZE + 1] ^= 1;
+        bad[3 * merkle::BLOCK_SIZE] ^= 1;
+
+        assert_eq!(
+            info.bad_blocks(0, &bad, &leaves),
+            Some(vec![merkle::BLOCK_SIZE, 3 * merkle::BLOCK_SIZE])
+        );
+        assert_eq!(info.bad_blocks(0, &data[..plength], &leaves), Some(vec![]));
+        // Leaf hashes that don't add up to the piece's hash can't be trusted.
+        assert_eq!(info.bad_blocks(0, &bad, &merkle::leaves(&bad)), None);
+        assert_eq!(info.bad_blocks(0, &bad, &leaves[..3]), None);
+
+        // The last piece is one short block, padded out with zero leaves.
+        let mut last_leaves = merkle::leaves(&data[plength..]);
+        last_leaves.resize(4, [0; 32]);
+        assert_eq!(info.bad_blocks(1, &[0; 100], &last_leaves), Some(vec![0]));
+    }
+
+    #[tokio::test]
+    async fn port_is_announced_to_the_dht_only_when_listening() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut dot_torrent = b"d4:info".to_vec();
+        dot_torrent.extend_from_slice(info);
+        dot_torrent.push(b'e');
+        let mut torrent = Torrent::new(TorrentFile::from_bytes(&dot_torrent).unwrap());
+        torrent.port = 51413;
+        let nodes = crate::dht::tests::network(3).await;
+        let us = std::net::SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 51413);
+
+        torrent.discover_peers(Some(&nodes[0]), None).await.unwrap();
+        let peers = nodes[2].get_peers(torrent.info_hash).await;
+        assert!(!peers.iter().any(|peer| peer.ip4 == us));
+
+        torrent.listening = true;
+        torrent.discover_peers(Some(&nodes[0]), None).await.unwrap();
+        let peers = nodes[2].get_peers(torrent.info_hash).await;
+        assert!(peers.iter().any(|peer| peer.ip4 == us));
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::peers::peers::Peers;
+use crate::peers::Peers;
 /// Note: the info hash field is _not_ included.
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
@@ -24,12 +24,18 @@ pub struct TrackerRequest {
     /// representation is mostly supported for backward-compatibility.
     pub compact: u8,
 }
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TrackerResponse {
     /// An integer, indicating how often your client should make a request to the tracker in seconds.
     ///
     /// You can ignore this value for the purposes of this challenge.
     pub interval: usize,
+    /// The number of seeders in the swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+    /// The number of leechers in the swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
 
     /// A string, which contains list of peers that your client can connect to.
     ///
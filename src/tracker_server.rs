@@ -0,0 +1,885 @@
+use anyhow::{bail, Context, Result};
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time,
+};
+
+use crate::{
+    error::IoContext,
+    peers::{Peer, Peers},
+    tracker::TrackerResponse,
+    udp_tracker,
+    value::Value,
+    Error,
+};
+
+/// Longest request head the HTTP tracker accepts.
+const MAX_REQUEST_HEAD: usize = 8 << 10;
+/// How long a client gets to send its whole request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers handed out when an announce doesn't say how many it wants.
+const DEFAULT_NUMWANT: usize = 50;
+/// How often expired peers are swept out and the state file is written.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// How often clients are told to announce.
+    pub interval: Duration,
+    /// Peers that haven't announced for this long are dropped.
+    pub peer_ttl: Duration,
+    /// The only info hashes announces are accepted for; any if `None`.
+    pub allowed: Option<HashSet<[u8; 20]>>,
+    /// Most peers handed out per announce, whatever `numwant` asks for.
+    pub max_numwant: usize,
+    /// Where swarms are saved between runs, if anywhere.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30 * 60),
+            peer_ttl: Duration::from_secs(60 * 60),
+            allowed: None,
+            max_numwant: 200,
+            state_file: None,
+        }
+    }
+}
+
+/// What an announce says about the peer making it.
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    /// Bytes the peer still needs; 0 makes it a seeder.
+    pub left: u64,
+    pub event: Event,
+    pub numwant: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A regular announce at the interval.
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+/// What the tracker answers an announce with.
+#[derive(Debug, Clone)]
+pub struct AnnounceReply {
+    pub interval: Duration,
+    /// Peers of the same address family as the one announcing.
+    pub peers: Vec<SwarmMember>,
+    pub complete: usize,
+    pub incomplete: usize,
+}
+
+/// A peer handed out in an announce reply.
+#[derive(Debug, Clone, Copy)]
+pub struct SwarmMember {
+    pub addr: SocketAddr,
+    pub peer_id: [u8; 20],
+}
+
+/// Swarm totals, as reported by scrape.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Scrape {
+    pub complete: usize,
+    /// How many peers ever announced `completed`.
+    pub downloaded: usize,
+    pub incomplete: usize,
+}
+
+struct SwarmPeer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    /// By peer id.
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    downloaded: usize,
+}
+
+impl Swarm {
+    fn scrape(&self) -> Scrape {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count();
+        Scrape {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() - complete,
+        }
+    }
+}
+
+/// What is written to `TrackerConfig::state_file`.
+#[derive(Debug, Serialize, Deserialize)]
+struct TrackerState {
+    swarms: Vec<SwarmState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SwarmState {
+    #[serde(with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    downloaded: usize,
+    peers: Vec<PeerState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerState {
+    #[serde(with = "serde_bytes")]
+    peer_id: Vec<u8>,
+    /// Compact address: 4 or 16 bytes of IP, 2 of port.
+    #[serde(with = "serde_bytes")]
+    addr: Vec<u8>,
+    left: u64,
+    /// Seconds since the peer last announced, when the state was saved.
+    age: u64,
+}
+
+/// The swarms a tracker knows about. Cloning gives another handle to the same swarms, so
+/// several front ends can serve them at once.
+#[derive(Clone)]
+pub struct Swarms {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: TrackerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl Swarms {
+    /// Starts with the swarms from the state file, if there is one.
+    pub fn new(config: TrackerConfig) -> Self {
+        let mut swarms = HashMap::new();
+        let state = config.state_file.as_ref().and_then(|path| {
+            let bytes = std::fs::read(path).ok()?;
+            serde_bencode::from_bytes::<TrackerState>(&bytes).ok()
+        });
+        let now = Instant::now();
+        for saved in state.map(|state| state.swarms).unwrap_or_default() {
+            let Ok(info_hash) = saved.info_hash.as_slice().try_into() else {
+                continue;
+            };
+            let mut swarm = Swarm {
+                peers: HashMap::new(),
+                downloaded: saved.downloaded,
+            };
+            for peer in saved.peers {
+                let age = Duration::from_secs(peer.age);
+                let (Ok(peer_id), Some(addr)) = (
+                    peer.peer_id.as_slice().try_into(),
+                    decode_compact_peer(&peer.addr),
+                ) else {
+                    continue;
+                };
+                if age >= config.peer_ttl {
+                    continue;
+                }
+                let last_seen = now.checked_sub(age).unwrap_or(now);
+                swarm.peers.insert(
+                    peer_id,
+                    SwarmPeer {
+                        addr,
+                        left: peer.left,
+                        last_seen,
+                    },
+                );
+            }
+            swarms.insert(info_hash, swarm);
+        }
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                swarms: Mutex::new(swarms),
+            }),
+        }
+    }
+
+    /// Records an announce and picks peers for the announcing peer to try.
+    pub fn announce(&self, announce: &Announce) -> crate::Result<AnnounceReply> {
+        let config = &self.inner.config;
+        if let Some(allowed) = &config.allowed {
+            if !allowed.contains(&announce.info_hash) {
+                return Err(Error::NotTracked);
+            }
+        }
+        let now = Instant::now();
+        let mut swarms = self.inner.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        swarm
+            .peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < config.peer_ttl);
+
+        let mut peers = Vec::new();
+        if announce.event == Event::Stopped {
+            swarm.peers.remove(&announce.peer_id);
+        } else {
+            let was_leeching = swarm
+                .peers
+                .get(&announce.peer_id)
+                .is_none_or(|peer| peer.left > 0);
+            if announce.event == Event::Completed && was_leeching {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                announce.peer_id,
+                SwarmPeer {
+                    addr: announce.addr,
+                    left: announce.left,
+                    last_seen: now,
+                },
+            );
+
+            let numwant = announce
+                .numwant
+                .unwrap_or(DEFAULT_NUMWANT)
+                .min(config.max_numwant);
+            // Seeders have no use for other seeders.
+            let seeding = announce.left == 0;
+            peers = swarm
+                .peers
+                .iter()
+                .filter(|(id, peer)| **id != announce.peer_id && !(seeding && peer.left == 0))
+                .filter(|(_, peer)| peer.addr.is_ipv4() == announce.addr.is_ipv4())
+                .map(|(id, peer)| SwarmMember {
+                    addr: peer.addr,
+                    peer_id: *id,
+                })
+                .choose_multiple(&mut rand::thread_rng(), numwant);
+        }
+
+        let scrape = swarm.scrape();
+        Ok(AnnounceReply {
+            interval: config.interval,
+            peers,
+            complete: scrape.complete,
+            incomplete: scrape.incomplete,
+        })
+    }
+
+    /// Swarm totals for the given info hashes, or for every swarm if none are given. Unknown
+    /// and disallowed torrents are left out.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> BTreeMap<[u8; 20], Scrape> {
+        let allowed = |info_hash: &[u8; 20]| {
+            self.inner
+                .config
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(info_hash))
+        };
+        let swarms = self.inner.swarms.lock().unwrap();
+        swarms
+            .iter()
+            .filter(|(info_hash, _)| info_hashes.is_empty() || info_hashes.contains(info_hash))
+            .filter(|(info_hash, _)| allowed(info_hash))
+            .map(|(info_hash, swarm)| (*info_hash, swarm.scrape()))
+            .collect()
+    }
+
+    /// Drops peers that stopped announcing, and swarms left without peers or history.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let ttl = self.inner.config.peer_ttl;
+        let mut swarms = self.inner.swarms.lock().unwrap();
+        for swarm in swarms.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| now.duration_since(peer.last_seen) < ttl);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+
+    /// Writes the swarms to the state file, if there is one.
+    pub fn save(&self) -> crate::Result<()> {
+        let Some(path) = &self.inner.config.state_file else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let state = {
+            let swarms = self.inner.swarms.lock().unwrap();
+            TrackerState {
+                swarms: swarms
+                    .iter()
+                    .map(|(info_hash, swarm)| SwarmState {
+                        info_hash: info_hash.to_vec(),
+                        downloaded: swarm.downloaded,
+                        peers: swarm
+                            .peers
+                            .iter()
+                            .map(|(peer_id, peer)| PeerState {
+                                peer_id: peer_id.to_vec(),
+                                addr: compact_peer(peer.addr),
+                                left: peer.left,
+                                age: now.duration_since(peer.last_seen).as_secs(),
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }
+        };
+        let bytes = serde_bencode::to_bytes(&state).context("encode tracker state")?;
+        std::fs::write(path, bytes).io_context(|| format!("write tracker state to {path:?}"))
+    }
+
+    /// Sweeps out expired peers and saves the state file every `SWEEP_INTERVAL`, forever.
+    pub async fn maintain(&self) {
+        let mut interval = time::interval(SWEEP_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.expire();
+            if let Err(e) = self.save() {
+                log::warn!("Could not save tracker state: {e:#}");
+            }
+        }
+    }
+}
+
+/// A peer's address the way trackers send it: the IP's bytes followed by the port, big-endian.
+pub fn compact_peer(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend(addr.port().to_be_bytes());
+    compact
+}
+
+fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_last_chunk::<2>()?;
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes(*port)))
+}
+
+/// The IPv4 address behind an IPv4-mapped IPv6 one, as a dual-stack socket reports them.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Serves announce and scrape over HTTP on `listener` and over UDP on `udp`, whichever are
+/// given, until either fails or `shutdown` resolves. Expired peers are swept out meanwhile, and
+/// the state file is written one last time at the end.
+pub async fn run(
+    swarms: Swarms,
+    http: Option<TcpListener>,
+    udp: Option<UdpSocket>,
+    udp_rate_limit: u32,
+    shutdown: impl Future<Output = ()>,
+) -> crate::Result<()> {
+    let serve_http = async {
+        match http {
+            Some(listener) => serve_http(swarms.clone(), listener).await,
+            None => std::future::pending().await,
+        }
+    };
+    let serve_udp = async {
+        match udp {
+            Some(socket) => udp_tracker::serve_udp(swarms.clone(), socket, udp_rate_limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = serve_http => result?,
+        result = serve_udp => result?,
+        _ = swarms.maintain() => {}
+        _ = shutdown => {}
+    }
+    swarms.save()
+}
+
+/// Serves announce and scrape over HTTP on `listener` until it fails. Requests that fail are
+/// logged rather than stopping the tracker.
+pub async fn serve_http(swarms: Swarms, listener: TcpListener) -> crate::Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await.io_context(|| "accept connection")?;
+        let swarms = swarms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(&swarms, stream, remote).await {
+                log::warn!("Tracker request from {remote} failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_request(swarms: &Swarms, stream: TcpStream, remote: SocketAddr) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let head = time::timeout(HEAD_TIMEOUT, read_head(&mut stream))
+        .await
+        .context("request head timed out")??;
+    let Some(head) = head else {
+        return Ok(());
+    };
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.split("\r\n").next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", Vec::new())
+    } else {
+        match path {
+            "/announce" => ("200 OK", announce_reply(swarms, query, remote)),
+            "/scrape" => ("200 OK", scrape_reply(swarms, query)),
+            _ => ("404 Not Found", Vec::new()),
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    Ok(())
+}
+
+/// Reads a request head up to and including its blank line, or `None` if the client hung up
+/// before sending one.
+async fn read_head(stream: &mut BufReader<TcpStream>) -> Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    let mut limited = stream.take(MAX_REQUEST_HEAD as u64);
+    while !head.ends_with(b"\r\n\r\n") {
+        if limited
+            .read_until(b'\n', &mut head)
+            .await
+            .context("read request")?
+            == 0
+        {
+            if limited.limit() == 0 {
+                bail!("request head too long");
+            }
+            return Ok(None);
+        }
+    }
+    Ok(Some(head))
+}
+
+/// The query string's parameters, percent-decoded. Values stay bytes, since `info_hash` and
+/// `peer_id` are binary.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(percent_decode(name)?).ok()?;
+            Some((name, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+    }
+    Some(out)
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    dict.insert(
+        b"failure reason".to_vec(),
+        Value::Bytes(reason.as_bytes().to_vec()),
+    );
+    Value::Dict(dict).encode()
+}
+
+/// Handles the parameters of an HTTP announce (BEP 3, BEP 23). The peer's address is the one
+/// the request came from; the `ip` parameter is ignored, so peers can't announce others.
+fn announce_reply(swarms: &Swarms, query: &str, remote: SocketAddr) -> Vec<u8> {
+    let params = parse_query(query);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    };
+    let number =
+        |name: &str| -> Option<u64> { std::str::from_utf8(param(name)?).ok()?.parse().ok() };
+
+    let Some(info_hash) = param("info_hash").and_then(|v| <[u8; 20]>::try_from(v).ok()) else {
+        return failure("missing or invalid info_hash");
+    };
+    let Some(peer_id) = param("peer_id").and_then(|v| <[u8; 20]>::try_from(v).ok()) else {
+        return failure("missing or invalid peer_id");
+    };
+    let Some(port) = number("port").and_then(|port| u16::try_from(port).ok()) else {
+        return failure("missing or invalid port");
+    };
+    // Without `left` a leecher would count as a seeder and be kept from the other seeders.
+    let Some(left) = number("left") else {
+        return failure("missing or invalid left");
+    };
+    let SocketAddr::V4(remote) = unmap(remote) else {
+        return failure("only IPv4 peers are supported over HTTP");
+    };
+    let event = match param("event") {
+        Some(b"started") => Event::Started,
+        Some(b"completed") => Event::Completed,
+        Some(b"stopped") => Event::Stopped,
+        _ => Event::None,
+    };
+    let announce = Announce {
+        info_hash,
+        peer_id,
+        addr: SocketAddrV4::new(*remote.ip(), port).into(),
+        left,
+        event,
+        numwant: number("numwant").map(|n| n as usize),
+    };
+    let reply = match swarms.announce(&announce) {
+        Ok(reply) => reply,
+        Err(e) => return failure(&e.to_string()),
+    };
+
+    let compact = param("compact") != Some(b"0");
+    if compact {
+        let response = TrackerResponse {
+            interval: reply.interval.as_secs() as usize,
+            complete: Some(reply.complete),
+            incomplete: Some(reply.incomplete),
+            peers: Peers(reply.peers.iter().filter_map(http_peer).collect()),
+        };
+        return serde_bencode::to_bytes(&response).unwrap_or_else(|e| failure(&e.to_string()));
+    }
+
+    // The original peer list: one dictionary per peer.
+    let no_peer_id = param("no_peer_id") == Some(b"1");
+    let peers = reply
+        .peers
+        .iter()
+        .filter_map(http_peer)
+        .map(|peer| {
+            let mut dict = BTreeMap::new();
+            dict.insert(
+                b"ip".to_vec(),
+                Value::Bytes(peer.ip4.ip().to_string().into_bytes()),
+            );
+            dict.insert(b"port".to_vec(), Value::Int(peer.ip4.port().into()));
+            if let (Some(peer_id), false) = (peer.peer_id, no_peer_id) {
+                dict.insert(b"peer id".to_vec(), Value::Bytes(peer_id.to_vec()));
+            }
+            Value::Dict(dict)
+        })
+        .collect();
+    let mut dict = BTreeMap::new();
+    dict.insert(
+        b"interval".to_vec(),
+        Value::Int(reply.interval.as_secs() as i64),
+    );
+    dict.insert(b"complete".to_vec(), Value::Int(reply.complete as i64));
+    dict.insert(b"incomplete".to_vec(), Value::Int(reply.incomplete as i64));
+    dict.insert(b"peers".to_vec(), Value::List(peers));
+    Value::Dict(dict).encode()
+}
+
+/// Announces over HTTP come from IPv4 peers, so only get IPv4 peers back.
+fn http_peer(member: &SwarmMember) -> Option<Peer> {
+    match member.addr {
+        SocketAddr::V4(addr) => Some(Peer {
+            ip4: addr,
+            peer_id: Some(member.peer_id),
+        }),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// Handles a scrape: totals for each `info_hash` asked for, or for every swarm.
+fn scrape_reply(swarms: &Swarms, query: &str) -> Vec<u8> {
+    let info_hashes: Vec<[u8; 20]> = parse_query(query)
+        .into_iter()
+        .filter(|(name, _)| name == "info_hash")
+        .filter_map(|(_, value)| value.try_into().ok())
+        .collect();
+    let files = swarms
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(info_hash, scrape)| {
+            let mut dict = BTreeMap::new();
+            dict.insert(b"complete".to_vec(), Value::Int(scrape.complete as i64));
+            dict.insert(b"downloaded".to_vec(), Value::Int(scrape.downloaded as i64));
+            dict.insert(b"incomplete".to_vec(), Value::Int(scrape.incomplete as i64));
+            (info_hash.to_vec(), Value::Dict(dict))
+        })
+        .collect();
+    let mut dict = BTreeMap::new();
+    dict.insert(b"files".to_vec(), Value::Dict(files));
+    Value::Dict(dict).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Mode;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn announce(peer: u8, left: u64, event: Event) -> Announce {
+        Announce {
+            info_hash: INFO_HASH,
+            peer_id: [peer; 20],
+            addr: SocketAddr::from(([10, 0, 0, peer], 6881)),
+            left,
+            event,
+            numwant: None,
+        }
+    }
+
+    fn percent_encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("%{byte:02x}")).collect()
+    }
+
+    /// Sends a GET for `target` to the tracker at `addr` and returns the response body.
+    async fn get(addr: SocketAddr, target: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: {addr}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let body = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        response.split_off(body)
+    }
+
+    fn dict(bytes: &[u8]) -> BTreeMap<Vec<u8>, Value> {
+        match Value::decode(bytes, Mode::Strict).unwrap() {
+            Value::Dict(dict) => dict,
+            other => panic!("not a dictionary: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn announce_caps_numwant_and_keeps_seeders_from_seeders() {
+        let swarms = Swarms::new(TrackerConfig {
+            max_numwant: 3,
+            ..Default::default()
+        });
+        for peer in 1..=5 {
+            swarms
+                .announce(&announce(peer, 100, Event::Started))
+                .unwrap();
+        }
+        for peer in 6..=7 {
+            swarms.announce(&announce(peer, 0, Event::Started)).unwrap();
+        }
+
+        let mut greedy = announce(1, 100, Event::None);
+        greedy.numwant = Some(100);
+        let reply = swarms.announce(&greedy).unwrap();
+        assert_eq!(reply.peers.len(), 3);
+        assert!(reply.peers.iter().all(|member| member.peer_id != [1; 20]));
+        assert_eq!((reply.complete, reply.incomplete), (2, 5));
+
+        let mut seeder = announce(6, 0, Event::None);
+        seeder.numwant = Some(3);
+        let reply = swarms.announce(&seeder).unwrap();
+        assert_eq!(reply.peers.len(), 3);
+        assert!(reply.peers.iter().all(|member| member.peer_id[0] <= 5));
+        let mut seeder = announce(7, 0, Event::None);
+        seeder.numwant = Some(10);
+        assert_eq!(swarms.announce(&seeder).unwrap().peers.len(), 3);
+    }
+
+    #[test]
+    fn announce_only_tracks_allowed_torrents() {
+        let swarms = Swarms::new(TrackerConfig {
+            allowed: Some(HashSet::from([[2; 20]])),
+            ..Default::default()
+        });
+        assert!(matches!(
+            swarms.announce(&announce(1, 100, Event::Started)),
+            Err(Error::NotTracked)
+        ));
+        let mut allowed = announce(1, 100, Event::Started);
+        allowed.info_hash = [2; 20];
+        swarms.announce(&allowed).unwrap();
+        assert_eq!(
+            swarms.scrape(&[]).into_keys().collect::<Vec<_>>(),
+            [[2; 20]]
+        );
+    }
+
+    #[test]
+    fn completed_counts_each_finished_download_once() {
+        let swarms = Swarms::new(TrackerConfig::default());
+        swarms.announce(&announce(1, 100, Event::Started)).unwrap();
+        swarms.announce(&announce(1, 0, Event::Completed)).unwrap();
+        // Said again, or by a peer that started out seeding, it isn't a new download.
+        swarms.announce(&announce(1, 0, Event::Completed)).unwrap();
+        swarms.announce(&announce(2, 0, Event::Started)).unwrap();
+        swarms.announce(&announce(2, 0, Event::Completed)).unwrap();
+        swarms.announce(&announce(3, 50, Event::Started)).unwrap();
+        swarms.announce(&announce(3, 0, Event::Stopped)).unwrap();
+
+        let scrape = swarms.scrape(&[INFO_HASH])[&INFO_HASH];
+        assert_eq!(
+            (scrape.complete, scrape.downloaded, scrape.incomplete),
+            (2, 1, 0)
+        );
+    }
+
+    #[test]
+    fn expire_drops_silent_peers_and_empty_swarms() {
+        let swarms = Swarms::new(TrackerConfig {
+            peer_ttl: Duration::from_millis(50),
+            ..Default::default()
+        });
+        swarms.announce(&announce(1, 100, Event::Started)).unwrap();
+        let mut other = announce(2, 0, Event::Completed);
+        other.info_hash = [2; 20];
+        swarms.announce(&other).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        swarms.expire();
+
+        // The swarm with a completed download keeps its history; the other one goes.
+        let scrape = swarms.scrape(&[]);
+        assert_eq!(scrape.keys().collect::<Vec<_>>(), [&[2; 20]]);
+        let totals = scrape[&[2; 20]];
+        assert_eq!((totals.complete, totals.downloaded), (0, 1));
+    }
+
+    #[test]
+    fn state_file_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TrackerConfig {
+            state_file: Some(dir.path().join("tracker.state")),
+            ..Default::default()
+        };
+        let swarms = Swarms::new(config.clone());
+        swarms.announce(&announce(1, 100, Event::Started)).unwrap();
+        swarms.announce(&announce(2, 100, Event::Started)).unwrap();
+        swarms.announce(&announce(2, 0, Event::Completed)).unwrap();
+        let mut v6 = announce(3, 100, Event::Started);
+        v6.addr = "[2001:db8::1]:51413".parse().unwrap();
+        swarms.announce(&v6).unwrap();
+        swarms.save().unwrap();
+
+        let loaded = Swarms::new(config);
+        let scrape = loaded.scrape(&[INFO_HASH])[&INFO_HASH];
+        assert_eq!(
+            (scrape.complete, scrape.downloaded, scrape.incomplete),
+            (1, 1, 2)
+        );
+        let mut v6 = announce(4, 100, Event::Started);
+        v6.addr = "[2001:db8::2]:6881".parse().unwrap();
+        let reply = loaded.announce(&v6).unwrap();
+        assert_eq!(reply.peers.len(), 1);
+        assert_eq!(reply.peers[0].addr, "[2001:db8::1]:51413".parse().unwrap());
+        assert_eq!(reply.peers[0].peer_id, [3; 20]);
+    }
+
+    #[tokio::test]
+    async fn http_announces_and_scrapes() {
+        let swarms = Swarms::new(TrackerConfig::default());
+        swarms.announce(&announce(1, 0, Event::Started)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http(swarms, listener));
+
+        let query = format!(
+            "/announce?info_hash={}&peer_id={}&port=6881&left=10",
+            percent_encode(&INFO_HASH),
+            percent_encode(&[2; 20]),
+        );
+        let compact: TrackerResponse = serde_bencode::from_bytes(&get(addr, &query).await).unwrap();
+        assert_eq!((compact.complete, compact.incomplete), (Some(1), Some(1)));
+        assert_eq!(compact.peers.0.len(), 1);
+        assert_eq!(compact.peers.0[0].ip4, "10.0.0.1:6881".parse().unwrap());
+        assert_eq!(compact.peers.0[0].peer_id, None);
+
+        let full = dict(&get(addr, &format!("{query}&compact=0")).await);
+        let Value::List(peers) = &full[&b"peers".to_vec()] else {
+            panic!("peers aren't a list");
+        };
+        let Value::Dict(peer) = &peers[0] else {
+            panic!("peer isn't a dictionary");
+        };
+        assert_eq!(peer[&b"ip".to_vec()], Value::Bytes(b"10.0.0.1".to_vec()));
+        assert_eq!(peer[&b"port".to_vec()], Value::Int(6881));
+        assert_eq!(peer[&b"peer id".to_vec()], Value::Bytes(vec![1; 20]));
+        let anonymous = dict(&get(addr, &format!("{query}&compact=0&no_peer_id=1")).await);
+        let Value::List(peers) = &anonymous[&b"peers".to_vec()] else {
+            panic!("peers aren't a list");
+        };
+        assert_eq!(
+            peers[0],
+            Value::Dict(BTreeMap::from([
+                (b"ip".to_vec(), Value::Bytes(b"10.0.0.1".to_vec())),
+                (b"port".to_vec(), Value::Int(6881)),
+            ]))
+        );
+
+        let without_left = query.replace("&left=10", "");
+        assert_eq!(
+            dict(&get(addr, &without_left).await)[&b"failure reason".to_vec()],
+            Value::Bytes(b"missing or invalid left".to_vec())
+        );
+
+        let scrape = dict(
+            &get(
+                addr,
+                &format!("/scrape?info_hash={}", percent_encode(&INFO_HASH)),
+            )
+            .await,
+        );
+        assert_eq!(
+            scrape[&b"files".to_vec()],
+            Value::Dict(BTreeMap::from([(
+                INFO_HASH.to_vec(),
+                Value::Dict(BTreeMap::from([
+                    (b"complete".to_vec(), Value::Int(1)),
+                    (b"downloaded".to_vec(), Value::Int(0)),
+                    (b"incomplete".to_vec(), Value::Int(1)),
+                ]))
+            )]))
+        );
+        let unknown = dict(
+            &get(
+                addr,
+                &format!("/scrape?info_hash={}", percent_encode(&[9; 20])),
+            )
+            .await,
+        );
+        assert_eq!(unknown[&b"files".to_vec()], Value::Dict(BTreeMap::new()));
+    }
+}
@@ -0,0 +1,415 @@
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+use crate::{
+    error::IoContext,
+    tracker_server::{self, Announce, Event, Swarms},
+};
+
+/// What a connect request starts with, so stray packets aren't taken for one.
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// Clients may use a connection id for a minute, and BEP 15 has servers accept it for two.
+/// Rotating the secret every minute and accepting the previous one gives between one and two.
+const SECRET_ROTATION: Duration = Duration::from_secs(60);
+/// Most info hashes one scrape may ask about, which keeps the reply within a small packet.
+const MAX_SCRAPE: usize = 74;
+const ANNOUNCE_LENGTH: usize = 98;
+/// Most IPs the rate limit keeps buckets for. Past that, requests from IPs it hasn't seen are
+/// dropped until buckets fill up again and are forgotten.
+const MAX_RATE_LIMITED_IPS: usize = 1 << 16;
+
+/// Serves announce and scrape over UDP (BEP 15) on `socket` until it fails. Bind it to `[::]`
+/// to serve IPv4 and IPv6 peers on the same port.
+///
+/// Each IP may make up to `rate_limit` requests per second, in bursts of up to as many;
+/// requests beyond that are dropped without a reply. 0 turns the limit off.
+pub async fn serve_udp(swarms: Swarms, socket: UdpSocket, rate_limit: u32) -> crate::Result<()> {
+    let mut server = Server {
+        swarms,
+        secrets: Secrets {
+            current: rand::thread_rng().gen(),
+            previous: rand::thread_rng().gen(),
+            rotated_at: Instant::now(),
+        },
+        rate_limit: RateLimit {
+            per_second: f64::from(rate_limit),
+            buckets: HashMap::new(),
+            max_ips: MAX_RATE_LIMITED_IPS,
+            swept_at: Instant::now(),
+        },
+    };
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, from) = socket
+            .recv_from(&mut buf)
+            .await
+            .io_context(|| "receive request")?;
+        if let Some(reply) = server.handle(&buf[..len], tracker_server::unmap(from)) {
+            // A peer that went away is no reason to stop serving the others.
+            if let Err(e) = socket.send_to(&reply, from).await {
+                log::debug!("Could not reply to {from}: {e}");
+            }
+        }
+    }
+}
+
+struct Server {
+    swarms: Swarms,
+    secrets: Secrets,
+    rate_limit: RateLimit,
+}
+
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+/// A token bucket per IP.
+struct RateLimit {
+    per_second: f64,
+    /// Tokens left, and when they were last topped up.
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    max_ips: usize,
+    /// When idle buckets were last forgotten.
+    swept_at: Instant,
+}
+
+impl RateLimit {
+    fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.per_second == 0.0 {
+            return true;
+        }
+        if self.buckets.len() >= self.max_ips && !self.buckets.contains_key(&ip) {
+            // Sweeping is a pass over every bucket, so do it at most once a second.
+            if now.duration_since(self.swept_at) >= Duration::from_secs(1) {
+                self.forget_idle(now);
+            }
+            if self.buckets.len() >= self.max_ips {
+                return false;
+            }
+        }
+        let (tokens, updated) = self.buckets.entry(ip).or_insert((self.per_second, now));
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * self.per_second)
+            .min(self.per_second);
+        *updated = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Forgets IPs whose buckets have filled up again, as they are no different from new ones.
+    fn forget_idle(&mut self, now: Instant) {
+        let per_second = self.per_second;
+        self.swept_at = now;
+        self.buckets.retain(|_, (tokens, updated)| {
+            *tokens + now.duration_since(*updated).as_secs_f64() * per_second < per_second
+        });
+    }
+}
+
+impl Server {
+    fn handle(&mut self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        if now.duration_since(self.secrets.rotated_at) > SECRET_ROTATION {
+            self.secrets.previous = self.secrets.current;
+            self.secrets.current = rand::thread_rng().gen();
+            self.secrets.rotated_at = now;
+            self.rate_limit.forget_idle(now);
+        }
+        if packet.len() < 16 || !self.rate_limit.allow(from.ip(), now) {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().expect("4 bytes"));
+        let u64_at = |i: usize| u64::from_be_bytes(packet[i..i + 8].try_into().expect("8 bytes"));
+        let (action, transaction_id) = (u32_at(8), u32_at(12));
+
+        let mut reply = Vec::new();
+        reply.extend(action.to_be_bytes());
+        reply.extend(transaction_id.to_be_bytes());
+        if action == ACTION_CONNECT {
+            if u64_at(0) != PROTOCOL_ID {
+                return None;
+            }
+            reply.extend(self.connection_id(from, false).to_be_bytes());
+            return Some(reply);
+        }
+        let connection_id = u64_at(0);
+        if connection_id != self.connection_id(from, false)
+            && connection_id != self.connection_id(from, true)
+        {
+            return Some(error(transaction_id, "connection id expired"));
+        }
+
+        match action {
+            ACTION_ANNOUNCE if packet.len() >= ANNOUNCE_LENGTH => {
+                let event = match u32_at(80) {
+                    1 => Event::Completed,
+                    2 => Event::Started,
+                    3 => Event::Stopped,
+                    _ => Event::None,
+                };
+                // -1 asks for the default.
+                let numwant = u32_at(92) as i32;
+                let announce = Announce {
+                    info_hash: packet[16..36].try_into().expect("20 bytes"),
+                    peer_id: packet[36..56].try_into().expect("20 bytes"),
+                    // Like the HTTP tracker, ignore the IP field and use the sender's.
+                    addr: SocketAddr::new(from.ip(), u16::from_be_bytes([packet[96], packet[97]])),
+                    left: u64_at(64),
+                    event,
+                    numwant: usize::try_from(numwant).ok(),
+                };
+                let announced = match self.swarms.announce(&announce) {
+                    Ok(announced) => announced,
+                    Err(e) => return Some(error(transaction_id, &e.to_string())),
+                };
+                let interval = announced.interval.as_secs().min(u32::MAX.into()) as u32;
+                reply.extend(interval.to_be_bytes());
+                reply.extend((announced.incomplete as u32).to_be_bytes());
+                reply.extend((announced.complete as u32).to_be_bytes());
+                // Peers are of the sender's address family, so the reply's size tells the
+                // client whether they're 6 or 18 bytes each.
+                for peer in announced.peers {
+                    reply.extend(tracker_server::compact_peer(peer.addr));
+                }
+                Some(reply)
+            }
+            ACTION_SCRAPE => {
+                let info_hashes: Vec<[u8; 20]> = packet[16..]
+                    .chunks_exact(20)
+                    .take(MAX_SCRAPE)
+                    .map(|info_hash| info_hash.try_into().expect("20 bytes"))
+                    .collect();
+                let scrapes = self.swarms.scrape(&info_hashes);
+                // Torrents the tracker doesn't know about get zeros.
+                for info_hash in &info_hashes {
+                    let scrape = scrapes.get(info_hash).copied().unwrap_or_default();
+                    reply.extend((scrape.complete as u32).to_be_bytes());
+                    reply.extend((scrape.downloaded as u32).to_be_bytes());
+                    reply.extend((scrape.incomplete as u32).to_be_bytes());
+                }
+                Some(reply)
+            }
+            ACTION_ANNOUNCE => Some(error(transaction_id, "announce too short")),
+            _ => Some(error(transaction_id, "unknown action")),
+        }
+    }
+
+    /// Connection ids are a hash of the client's address and a secret that is rotated
+    /// periodically, so the server has nothing to remember per client.
+    fn connection_id(&self, from: SocketAddr, previous: bool) -> u64 {
+        let secret = if previous {
+            self.secrets.previous
+        } else {
+            self.secrets.current
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(tracker_server::compact_peer(from));
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().expect("8 bytes"))
+    }
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = Vec::new();
+    reply.extend(ACTION_ERROR.to_be_bytes());
+    reply.extend(transaction_id.to_be_bytes());
+    reply.extend(message.as_bytes());
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker_server::TrackerConfig;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn server(rate_limit: u32) -> Server {
+        Server {
+            swarms: Swarms::new(TrackerConfig::default()),
+            secrets: Secrets {
+                current: [1; 20],
+                previous: [2; 20],
+                rotated_at: Instant::now(),
+            },
+            rate_limit: RateLimit {
+                per_second: f64::from(rate_limit),
+                buckets: HashMap::new(),
+                max_ips: MAX_RATE_LIMITED_IPS,
+                swept_at: Instant::now(),
+            },
+        }
+    }
+
+    fn connect(server: &mut Server, from: SocketAddr) -> u64 {
+        let mut request = PROTOCOL_ID.to_be_bytes().to_vec();
+        request.extend(ACTION_CONNECT.to_be_bytes());
+        request.extend(7u32.to_be_bytes());
+        let reply = server.handle(&request, from).unwrap();
+        assert_eq!(reply[..8], [0, 0, 0, 0, 0, 0, 0, 7]);
+        u64::from_be_bytes(reply[8..16].try_into().unwrap())
+    }
+
+    fn announce_request(connection_id: u64, peer: u8, left: u64, event: u32) -> Vec<u8> {
+        let mut request = connection_id.to_be_bytes().to_vec();
+        request.extend(ACTION_ANNOUNCE.to_be_bytes());
+        request.extend(8u32.to_be_bytes());
+        request.extend(INFO_HASH);
+        request.extend([peer; 20]);
+        request.extend(0u64.to_be_bytes()); // downloaded
+        request.extend(left.to_be_bytes());
+        request.extend(0u64.to_be_bytes()); // uploaded
+        request.extend(event.to_be_bytes());
+        request.extend([0; 4]); // ip
+        request.extend(0u32.to_be_bytes()); // key
+        request.extend((-1i32).to_be_bytes()); // numwant
+        request.extend(6881u16.to_be_bytes());
+        assert_eq!(request.len(), ANNOUNCE_LENGTH);
+        request
+    }
+
+    fn u32_at(bytes: &[u8], i: usize) -> u32 {
+        u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap())
+    }
+
+    /// Makes the next request rotate the secret.
+    fn age_secrets(server: &mut Server) {
+        server.secrets.rotated_at -= SECRET_ROTATION + Duration::from_secs(1);
+    }
+
+    #[test]
+    fn connects_announces_and_scrapes() {
+        let mut server = server(0);
+        let seeder: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let leecher: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let id = connect(&mut server, seeder);
+        server
+            .handle(&announce_request(id, 1, 0, 2), seeder)
+            .unwrap();
+
+        let id = connect(&mut server, leecher);
+        let reply = server
+            .handle(&announce_request(id, 2, 100, 2), leecher)
+            .unwrap();
+        assert_eq!((u32_at(&reply, 0), u32_at(&reply, 4)), (ACTION_ANNOUNCE, 8));
+        assert_eq!(u32_at(&reply, 8), 30 * 60);
+        assert_eq!((u32_at(&reply, 12), u32_at(&reply, 16)), (1, 1));
+        // The seeder, at the port it announced rather than the one it sent from.
+        assert_eq!(reply[20..], [10, 0, 0, 1, 0x1a, 0xe1]);
+
+        let mut scrape = id.to_be_bytes().to_vec();
+        scrape.extend(ACTION_SCRAPE.to_be_bytes());
+        scrape.extend(9u32.to_be_bytes());
+        scrape.extend(INFO_HASH);
+        scrape.extend([9; 20]);
+        let reply = server.handle(&scrape, leecher).unwrap();
+        assert_eq!((u32_at(&reply, 0), u32_at(&reply, 4)), (ACTION_SCRAPE, 9));
+        let totals: Vec<u32> = (8..reply.len())
+            .step_by(4)
+            .map(|i| u32_at(&reply, i))
+            .collect();
+        // Complete, downloaded and incomplete, then zeros for the unknown torrent.
+        assert_eq!(totals, [1, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn connection_id_lasts_one_rotation() {
+        let mut server = server(0);
+        let from: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let id = connect(&mut server, from);
+        // Only good for the address it was given to.
+        let elsewhere = server
+            .handle(
+                &announce_request(id, 1, 0, 0),
+                "10.0.0.1:1001".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(u32_at(&elsewhere, 0), ACTION_ERROR);
+
+        age_secrets(&mut server);
+        let reply = server.handle(&announce_request(id, 1, 0, 0), from).unwrap();
+        assert_eq!(u32_at(&reply, 0), ACTION_ANNOUNCE);
+
+        age_secrets(&mut server);
+        let reply = server.handle(&announce_request(id, 1, 0, 0), from).unwrap();
+        assert_eq!(u32_at(&reply, 0), ACTION_ERROR);
+        assert_eq!(&reply[8..], b"connection id expired");
+    }
+
+    #[test]
+    fn ipv6_peers_get_18_byte_peers() {
+        let mut server = server(0);
+        let v4: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let seeder: SocketAddr = "[2001:db8::1]:1000".parse().unwrap();
+        let leecher: SocketAddr = "[2001:db8::2]:1000".parse().unwrap();
+        for (from, peer, left) in [(v4, 1, 0), (seeder, 2, 0)] {
+            let id = connect(&mut server, from);
+            server
+                .handle(&announce_request(id, peer, left, 2), from)
+                .unwrap();
+        }
+
+        let id = connect(&mut server, leecher);
+        let reply = server
+            .handle(&announce_request(id, 3, 100, 2), leecher)
+            .unwrap();
+        // Only the IPv6 seeder; the IPv4 one is of no use over this address family.
+        assert_eq!(reply.len(), 20 + 18);
+        let mut expected = "2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets()
+            .to_vec();
+        expected.extend(6881u16.to_be_bytes());
+        assert_eq!(reply[20..], expected);
+    }
+
+    #[test]
+    fn rate_limit_drops_requests_past_the_burst() {
+        let mut server = server(2);
+        let from: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        connect(&mut server, from);
+        connect(&mut server, from);
+        let mut request = PROTOCOL_ID.to_be_bytes().to_vec();
+        request.extend(ACTION_CONNECT.to_be_bytes());
+        request.extend(7u32.to_be_bytes());
+        assert!(server.handle(&request, from).is_none());
+        // Other IPs have their own buckets.
+        connect(&mut server, "10.0.0.2:1000".parse().unwrap());
+    }
+
+    #[test]
+    fn rate_limit_keeps_a_bounded_number_of_buckets() {
+        let mut limit = RateLimit {
+            per_second: 1.0,
+            buckets: HashMap::new(),
+            max_ips: 2,
+            swept_at: Instant::now(),
+        };
+        let now = Instant::now();
+        let ip = |last: u8| IpAddr::from([10, 0, 0, last]);
+        assert!(limit.allow(ip(1), now));
+        assert!(limit.allow(ip(2), now));
+        assert!(!limit.allow(ip(3), now));
+        assert_eq!(limit.buckets.len(), 2);
+
+        // Once the first two have refilled, they are forgotten to make room.
+        let later = now + Duration::from_secs(2);
+        assert!(limit.allow(ip(3), later));
+        assert_eq!(limit.buckets.keys().collect::<Vec<_>>(), [&ip(3)]);
+    }
+}
@@ -0,0 +1,885 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf,
+};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, sleep_until};
+
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+/// Payload bytes per packet; keeps datagrams under a typical 1500 byte MTU.
+const MSS: usize = 1400 - HEADER_LEN;
+/// LEDBAT aims to add no more than this much queuing delay (in microseconds).
+const TARGET_DELAY: i64 = 100_000;
+/// The most the congestion window may grow per round trip, in packets.
+const GAIN: f64 = 1.0;
+const MIN_CWND: usize = 2 * MSS;
+const MAX_CWND: usize = 1 << 20;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_TRANSMISSIONS: u32 = 6;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Incoming connections waiting for `UtpSocket::accept`; more than this are reset.
+const ACCEPT_BACKLOG: usize = 32;
+/// How much unread data a stream buffers between the application and the connection task.
+const STREAM_BUFFER: usize = 1 << 18;
+/// The most we ever advertise as our receive window: what the stream buffer holds. What is
+/// actually advertised is whatever of it is free.
+const RECV_WINDOW: usize = STREAM_BUFFER;
+/// Packets further than this ahead of the last in-order one would overflow the receive window
+/// and are dropped rather than buffered.
+const MAX_OUT_OF_ORDER: u16 = (RECV_WINDOW / MSS) as u16;
+/// Base delay is the minimum one-way delay seen over roughly the last two minutes.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+const BASE_DELAY_HISTORY: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push(((self.kind as u8) << 4) | VERSION);
+        bytes.push(0); // no extensions
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.wnd_size.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = match bytes[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+
+        // Skip the extension chain; we don't negotiate any extensions.
+        let mut next_extension = bytes[1];
+        let mut offset = HEADER_LEN;
+        while next_extension != 0 {
+            if bytes.len() < offset + 2 {
+                return None;
+            }
+            next_extension = bytes[offset];
+            offset += 2 + bytes[offset + 1] as usize;
+        }
+        if bytes.len() < offset {
+            return None;
+        }
+
+        Some(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+fn now_micros() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/// `a <= b` in 16-bit sequence number space.
+fn seq_less_eq(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+type ConnectionKey = (SocketAddr, u16);
+
+struct Shared {
+    socket: UdpSocket,
+    connections: Mutex<HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>>,
+    /// Set by the first `UtpSocket::accept`; until then a SYN is answered with a reset.
+    accepting: AtomicBool,
+    incoming: mpsc::Sender<(UtpStream, SocketAddr)>,
+}
+
+/// A UDP socket speaking the micro transport protocol (BEP 29).
+///
+/// One socket multiplexes any number of connections: outgoing ones from `connect`, and
+/// incoming ones once something calls `accept`. A socket nobody accepts on answers every SYN
+/// with a reset.
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("bind uTP socket on {addr}"))?;
+        let (incoming_sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Shared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            accepting: AtomicBool::new(false),
+            incoming: incoming_sender,
+        });
+        let receiver = tokio::spawn(Shared::receive_loop(shared.clone()));
+        Ok(Self {
+            shared,
+            incoming: tokio::sync::Mutex::new(incoming),
+            receiver,
+        })
+    }
+
+    /// Waits for a peer to connect to this socket.
+    pub async fn accept(&self) -> Result<(UtpStream, SocketAddr)> {
+        self.shared.accepting.store(true, Ordering::Relaxed);
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("uTP socket closed")
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream> {
+        let (recv_id, packets) = loop {
+            let recv_id: u16 = rand::thread_rng().gen();
+            let mut connections = self.shared.connections.lock().unwrap();
+            if let std::collections::hash_map::Entry::Vacant(entry) =
+                connections.entry((addr, recv_id))
+            {
+                let (sender, packets) = mpsc::unbounded_channel();
+                entry.insert(sender);
+                break (recv_id, packets);
+            }
+        };
+        let mut connection = Connection::new(
+            self.shared.clone(),
+            addr,
+            recv_id,
+            recv_id.wrapping_add(1),
+            1,
+            0,
+        );
+        match time::timeout(CONNECT_TIMEOUT, connection.handshake(packets)).await {
+            Ok(Ok(packets)) => Ok(connection.spawn(packets)),
+            result => {
+                connection.unregister();
+                match result {
+                    Ok(Err(e)) => Err(e),
+                    _ => Err(anyhow!("uTP connection to {addr} timed out")),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl Shared {
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buffer = vec![0u8; 1 << 16];
+        loop {
+            let Ok((length, from)) = self.socket.recv_from(&mut buffer).await else {
+                continue;
+            };
+            let Some(packet) = Packet::from_bytes(&buffer[..length]) else {
+                continue;
+            };
+            // A SYN carries the id the peer receives on; we receive on the one after it, which
+            // is where a repeated SYN for a connection we already accepted belongs.
+            let connection_id = if packet.kind == PacketType::Syn {
+                packet.connection_id.wrapping_add(1)
+            } else {
+                packet.connection_id
+            };
+            let delivered = {
+                let connections = self.connections.lock().unwrap();
+                connections
+                    .get(&(from, connection_id))
+                    .map(|sender| sender.send(packet.clone()).is_ok())
+            };
+            match (delivered, packet.kind) {
+                (Some(true), _) => {}
+                (_, PacketType::Reset) => {}
+                (None, PacketType::Syn) if self.accept_connection(&packet, from).await => {}
+                _ => {
+                    let reset = Packet {
+                        kind: PacketType::Reset,
+                        connection_id: packet.connection_id,
+                        timestamp: now_micros(),
+                        timestamp_diff: 0,
+                        wnd_size: 0,
+                        seq_nr: 0,
+                        ack_nr: packet.seq_nr,
+                        payload: Vec::new(),
+                    };
+                    let _ = self.socket.send_to(&reset.to_bytes(), from).await;
+                }
+            }
+        }
+    }
+
+    /// Answers a peer's SYN and queues the new connection for `UtpSocket::accept`. Returns
+    /// false, so the SYN gets a reset, when nobody accepts or the backlog is full.
+    async fn accept_connection(self: &Arc<Self>, syn: &Packet, from: SocketAddr) -> bool {
+        if !self.accepting.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Ok(permit) = self.incoming.try_reserve() else {
+            return false;
+        };
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let (sender, packets) = mpsc::unbounded_channel();
+        self.connections
+            .lock()
+            .unwrap()
+            .insert((from, recv_id), sender);
+        let mut connection = Connection::new(
+            self.clone(),
+            from,
+            recv_id,
+            syn.connection_id,
+            rand::thread_rng().gen(),
+            syn.seq_nr,
+        );
+        connection.peer_window = syn.wnd_size as usize;
+        connection.reply_micros = now_micros().wrapping_sub(syn.timestamp);
+        if connection.send_state().await.is_err() {
+            connection.unregister();
+            return false;
+        }
+        permit.send((connection.spawn(packets), from));
+        true
+    }
+}
+
+/// A connected uTP stream, usable wherever a `TcpStream` would be.
+pub struct UtpStream {
+    io: DuplexStream,
+    /// Bytes delivered by the connection task that the application hasn't read yet.
+    unread: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        this.unread.fetch_sub(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+struct InFlight {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// The state of one uTP connection, driven by its own task.
+///
+/// The application side talks to the task through a `DuplexStream`: whatever the application
+/// writes is packetised and sent under LEDBAT congestion control, and in-order payload from the
+/// peer is written back for the application to read.
+struct Connection {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// The sequence number of the next packet we send.
+    seq_nr: u16,
+    /// The last sequence number we have received in order.
+    ack_nr: u16,
+    in_flight: VecDeque<InFlight>,
+    /// Packets that arrived ahead of `ack_nr`.
+    out_of_order: HashMap<u16, Packet>,
+    /// Shared with the `UtpStream`, which takes off what the application reads.
+    unread: Arc<AtomicUsize>,
+    cwnd: usize,
+    peer_window: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    /// Minimum reported delays, one per `BASE_DELAY_WINDOW`, newest last.
+    delay_minimums: VecDeque<u32>,
+    delay_window_started: Instant,
+    /// What we put in `timestamp_diff`: our clock minus the peer's on its last packet.
+    reply_micros: u32,
+    duplicate_acks: u32,
+    fin_sent: bool,
+    /// The sequence number of the peer's FIN, once it arrives.
+    fin_received: Option<u16>,
+    eof_delivered: bool,
+}
+
+impl Connection {
+    fn new(
+        shared: Arc<Shared>,
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+    ) -> Self {
+        Self {
+            shared,
+            addr,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            unread: Arc::new(AtomicUsize::new(0)),
+            cwnd: MIN_CWND,
+            peer_window: MSS,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            delay_minimums: VecDeque::from([u32::MAX]),
+            delay_window_started: Instant::now(),
+            reply_micros: 0,
+            duplicate_acks: 0,
+            fin_sent: false,
+            fin_received: None,
+            eof_delivered: false,
+        }
+    }
+
+    fn unregister(&self) {
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&(self.addr, self.recv_id));
+    }
+
+    fn spawn(self, packets: mpsc::UnboundedReceiver<Packet>) -> UtpStream {
+        let (application, io) = tokio::io::duplex(STREAM_BUFFER);
+        let peer = self.addr;
+        let unread = self.unread.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(io);
+            if let Err(e) = self.run(packets, reader, writer).await {
+                log::debug!("uTP connection to {peer} closed: {e}");
+            }
+        });
+        UtpStream {
+            io: application,
+            unread,
+        }
+    }
+
+    fn header(&self, kind: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: if kind == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micros,
+            wnd_size: self.free_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        }
+    }
+
+    /// What is left of the receive window once unread and out-of-order data is counted.
+    fn free_window(&self) -> usize {
+        let buffered: usize = self
+            .out_of_order
+            .values()
+            .map(|packet| packet.payload.len())
+            .sum();
+        RECV_WINDOW.saturating_sub(self.unread.load(Ordering::Relaxed) + buffered)
+    }
+
+    async fn transmit(&self, packet: &Packet) -> Result<()> {
+        self.shared
+            .socket
+            .send_to(&packet.to_bytes(), self.addr)
+            .await
+            .context("send uTP packet")?;
+        Ok(())
+    }
+
+    async fn send_state(&self) -> Result<()> {
+        self.transmit(&self.header(PacketType::State, self.seq_nr, Vec::new()))
+            .await
+    }
+
+    /// Sends a packet that takes up a sequence number and has to be acknowledged.
+    async fn send_reliable(&mut self, kind: PacketType, payload: Vec<u8>) -> Result<()> {
+        let packet = self.header(kind, self.seq_nr, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&packet).await?;
+        self.in_flight.push_back(InFlight {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+        Ok(())
+    }
+
+    async fn retransmit_oldest(&mut self) -> Result<()> {
+        let (ack_nr, reply_micros) = (self.ack_nr, self.reply_micros);
+        let Some(oldest) = self.in_flight.front_mut() else {
+            return Ok(());
+        };
+        if oldest.transmissions >= MAX_TRANSMISSIONS {
+            bail!("peer stopped acknowledging packets");
+        }
+        oldest.packet.timestamp = now_micros();
+        oldest.packet.timestamp_diff = reply_micros;
+        oldest.packet.ack_nr = ack_nr;
+        oldest.sent_at = Instant::now();
+        oldest.transmissions += 1;
+        let packet = oldest.packet.clone();
+        self.transmit(&packet).await
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    /// Sends our SYN and waits for the state packet that answers it.
+    async fn handshake(
+        &mut self,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+    ) -> Result<mpsc::UnboundedReceiver<Packet>> {
+        self.send_reliable(PacketType::Syn, Vec::new()).await?;
+        loop {
+            let deadline = self.in_flight[0].sent_at + self.rto;
+            tokio::select! {
+                packet = packets.recv() => {
+                    let packet = packet.context("uTP socket closed")?;
+                    match packet.kind {
+                        PacketType::State if packet.ack_nr == self.in_flight[0].packet.seq_nr => {
+                            self.in_flight.clear();
+                            self.peer_window = packet.wnd_size as usize;
+                            self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+                            // The state packet doesn't consume a sequence number, so the
+                            // peer's first data packet will carry the same one.
+                            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                            return Ok(packets);
+                        }
+                        PacketType::Reset => bail!("peer refused the uTP connection"),
+                        _ => {}
+                    }
+                }
+                _ = sleep_until(deadline.into()) => {
+                    self.rto *= 2;
+                    self.retransmit_oldest().await?;
+                }
+            }
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+        mut application: ReadHalf<DuplexStream>,
+        mut deliver: WriteHalf<DuplexStream>,
+    ) -> Result<()> {
+        let result = async {
+            let mut buffer = vec![0u8; MSS];
+            let mut application_open = true;
+            loop {
+                if self.fin_sent && self.in_flight.is_empty() && self.eof_delivered {
+                    return Ok(());
+                }
+                let window = self.cwnd.min(self.peer_window).max(MSS);
+                let can_send =
+                    application_open && !self.fin_sent && self.bytes_in_flight() + MSS <= window;
+                let deadline = self
+                    .in_flight
+                    .front()
+                    .map(|oldest| oldest.sent_at + self.rto)
+                    .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+
+                tokio::select! {
+                    packet = packets.recv() => {
+                        let Some(packet) = packet else {
+                            bail!("uTP socket closed");
+                        };
+                        self.handle(packet, &mut deliver).await?;
+                    }
+                    read = application.read(&mut buffer), if can_send => {
+                        match read {
+                            Ok(0) | Err(_) => {
+                                application_open = false;
+                                self.send_reliable(PacketType::Fin, Vec::new()).await?;
+                                self.fin_sent = true;
+                            }
+                            Ok(n) => {
+                                self.send_reliable(PacketType::Data, buffer[..n].to_vec()).await?;
+                            }
+                        }
+                    }
+                    _ = sleep_until(deadline.into()), if !self.in_flight.is_empty() => {
+                        // A timeout means heavy loss: back off hard, as TCP would.
+                        self.cwnd = MIN_CWND;
+                        self.rto = (self.rto * 2).min(Duration::from_secs(60));
+                        self.retransmit_oldest().await?;
+                    }
+                }
+            }
+        }
+        .await;
+        self.unregister();
+        result
+    }
+
+    async fn handle(
+        &mut self,
+        packet: Packet,
+        deliver: &mut WriteHalf<DuplexStream>,
+    ) -> Result<()> {
+        self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+        match packet.kind {
+            PacketType::Reset => bail!("peer reset the connection"),
+            PacketType::Syn => {
+                // Our state packet got lost; say it again.
+                return self.send_state().await;
+            }
+            _ => {}
+        }
+
+        self.process_ack(&packet).await?;
+
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            if packet.kind == PacketType::Fin {
+                self.fin_received = Some(packet.seq_nr);
+            }
+            let next = self.ack_nr.wrapping_add(1);
+            if packet.seq_nr == next {
+                self.deliver(packet, deliver).await?;
+                while let Some(buffered) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                    self.deliver(buffered, deliver).await?;
+                }
+            } else if !seq_less_eq(packet.seq_nr, self.ack_nr)
+                && packet.seq_nr.wrapping_sub(self.ack_nr) <= MAX_OUT_OF_ORDER
+            {
+                self.out_of_order.insert(packet.seq_nr, packet);
+            }
+            self.send_state().await?;
+        }
+        Ok(())
+    }
+
+    async fn deliver(
+        &mut self,
+        packet: Packet,
+        deliver: &mut WriteHalf<DuplexStream>,
+    ) -> Result<()> {
+        self.ack_nr = packet.seq_nr;
+        if !packet.payload.is_empty() && !self.eof_delivered {
+            // If the application has gone away there is nobody to deliver to; keep acking.
+            self.unread
+                .fetch_add(packet.payload.len(), Ordering::Relaxed);
+            if deliver.write_all(&packet.payload).await.is_err() {
+                self.unread.store(0, Ordering::Relaxed);
+            }
+        }
+        if self.fin_received == Some(packet.seq_nr) {
+            let _ = deliver.shutdown().await;
+            self.eof_delivered = true;
+        }
+        Ok(())
+    }
+
+    async fn process_ack(&mut self, packet: &Packet) -> Result<()> {
+        let mut bytes_acked = 0;
+        let mut rtt_sample = None;
+        while let Some(oldest) = self.in_flight.front() {
+            if !seq_less_eq(oldest.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            let sent = self.in_flight.pop_front().expect("front exists");
+            bytes_acked += sent.packet.payload.len();
+            // Karn's algorithm: only unambiguous samples count.
+            if sent.transmissions == 1 {
+                rtt_sample = Some(sent.sent_at.elapsed());
+            }
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+        if bytes_acked > 0 {
+            self.duplicate_acks = 0;
+            self.apply_ledbat(packet.timestamp_diff, bytes_acked);
+        } else if packet.kind == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                // Fast retransmit.
+                self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+                self.retransmit_oldest().await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let deviation = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + deviation) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.rtt.expect("set above") + self.rtt_var * 4).max(MIN_RTO);
+    }
+
+    /// LEDBAT: grow the window while the delay our packets see stays under the target, and
+    /// shrink it as soon as we start adding queuing delay.
+    fn apply_ledbat(&mut self, delay: u32, bytes_acked: usize) {
+        if delay != 0 {
+            if self.delay_window_started.elapsed() > BASE_DELAY_WINDOW {
+                self.delay_minimums.push_back(u32::MAX);
+                if self.delay_minimums.len() > BASE_DELAY_HISTORY {
+                    self.delay_minimums.pop_front();
+                }
+                self.delay_window_started = Instant::now();
+            }
+            let current = self.delay_minimums.back_mut().expect("never empty");
+            *current = (*current).min(delay);
+        }
+        let base_delay = *self.delay_minimums.iter().min().expect("never empty");
+        let queuing_delay = if delay == 0 || base_delay == u32::MAX {
+            0
+        } else {
+            delay.wrapping_sub(base_delay) as i64
+        };
+        let off_target = (TARGET_DELAY - queuing_delay) as f64 / TARGET_DELAY as f64;
+        let change = GAIN * off_target * bytes_acked as f64 * MSS as f64 / self.cwnd as f64;
+        self.cwnd = ((self.cwnd as f64 + change) as usize).clamp(MIN_CWND, MAX_CWND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn bind() -> (UtpSocket, SocketAddr) {
+        let socket = UtpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = socket.shared.socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn data(seq_nr: u16, payload: &[u8]) -> Packet {
+        Packet {
+            kind: PacketType::Data,
+            connection_id: 7,
+            timestamp: now_micros(),
+            timestamp_diff: 0,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr,
+            ack_nr: 0,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let packet = Packet {
+            kind: PacketType::Fin,
+            connection_id: 0xbeef,
+            timestamp: 0x0102_0304,
+            timestamp_diff: 0x0506_0708,
+            wnd_size: 0x090a_0b0c,
+            seq_nr: 0xfffe,
+            ack_nr: 3,
+            payload: b"payload".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x11);
+        assert_eq!(bytes.len(), HEADER_LEN + 7);
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.kind, PacketType::Fin);
+        assert_eq!(decoded.connection_id, 0xbeef);
+        assert_eq!(decoded.timestamp, 0x0102_0304);
+        assert_eq!(decoded.timestamp_diff, 0x0506_0708);
+        assert_eq!(decoded.wnd_size, 0x090a_0b0c);
+        assert_eq!((decoded.seq_nr, decoded.ack_nr), (0xfffe, 3));
+        assert_eq!(decoded.payload, b"payload");
+
+        // An extension (selective ack) is skipped over, not taken for payload.
+        let mut extended = bytes[..HEADER_LEN].to_vec();
+        extended[1] = 1;
+        extended.extend([0, 4, 0xff, 0xff, 0xff, 0xff]);
+        extended.extend(b"payload");
+        assert_eq!(Packet::from_bytes(&extended).unwrap().payload, b"payload");
+
+        assert!(Packet::from_bytes(&bytes[..HEADER_LEN - 1]).is_none());
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 0x12;
+        assert!(Packet::from_bytes(&wrong_version).is_none());
+        let mut unknown_type = bytes;
+        unknown_type[0] = 0x51;
+        assert!(Packet::from_bytes(&unknown_type).is_none());
+    }
+
+    #[tokio::test]
+    async fn connects_and_transfers_both_ways() {
+        let (client, _) = bind().await;
+        let (server, server_addr) = bind().await;
+        let sent: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+        let accepted = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(b"thanks").await.unwrap();
+            stream.shutdown().await.unwrap();
+            received
+        });
+        let mut stream = client.connect(server_addr).await.unwrap();
+        stream.write_all(&sent).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+
+        assert_eq!(reply, b"thanks");
+        assert_eq!(accepted.await.unwrap(), sent);
+    }
+
+    #[tokio::test]
+    async fn socket_nobody_accepts_on_refuses_connections() {
+        let (client, _) = bind().await;
+        let (_server, server_addr) = bind().await;
+        let refused = client.connect(server_addr).await.err().unwrap();
+        assert!(refused.to_string().contains("refused"), "{refused}");
+    }
+
+    #[tokio::test]
+    async fn delivers_out_of_order_and_duplicate_packets_once_in_order() {
+        let (socket, _) = bind().await;
+        // Acknowledgements go here and are never read.
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut connection = Connection::new(
+            socket.shared.clone(),
+            peer.local_addr().unwrap(),
+            8,
+            7,
+            1,
+            10,
+        );
+        let (mut application, io) = tokio::io::duplex(STREAM_BUFFER);
+        let (_, mut deliver) = tokio::io::split(io);
+
+        for (seq_nr, payload) in [
+            (13, &b"d"[..]),
+            (12, b"c"),
+            (13, b"d"),
+            (11, b"a"),
+            // Already delivered: neither this nor the repeat above may show up twice.
+            (11, b"a"),
+            (12, b"c"),
+            // Too far ahead to buffer.
+            (13 + MAX_OUT_OF_ORDER + 1, b"x"),
+        ] {
+            connection
+                .handle(data(seq_nr, payload), &mut deliver)
+                .await
+                .unwrap();
+        }
+        let mut fin = data(14, b"");
+        fin.kind = PacketType::Fin;
+        connection.handle(fin, &mut deliver).await.unwrap();
+
+        assert_eq!(connection.ack_nr, 14);
+        assert!(connection.out_of_order.is_empty());
+        let mut received = Vec::new();
+        application.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"acd");
+    }
+}
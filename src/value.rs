@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::decoder::{self, DecodeError, Mode};
+
+/// JSON strings starting with this hold hex-encoded bytes. Byte strings that aren't UTF-8, or
+/// that start with the marker themselves, are written this way, so every byte string has
+/// exactly one JSON form and converting back gives the same bytes.
+pub const HEX_MARKER: &str = "hex:";
+
+/// A bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are kept sorted as raw bytes, which is the order canonical bencode wants.
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+/// Why a JSON value has no bencode equivalent.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FromJsonError {
+    #[error("{0} is not an integer; bencode only has integers")]
+    NotAnInteger(serde_json::Number),
+    #[error("bencode has no booleans")]
+    Bool,
+    #[error("bencode has no null")]
+    Null,
+    #[error("invalid hex in {0:?}")]
+    BadHex(String),
+}
+
+impl Value {
+    /// Decodes bencode; see `decoder::decode`.
+    pub fn decode(input: &[u8], mode: Mode) -> Result<Self, DecodeError> {
+        decoder::decode(input, mode)
+    }
+
+    /// Encodes the value as canonical bencode.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => {
+                out.push(b'i');
+                out.extend(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(values) => {
+                out.push(b'l');
+                for value in values {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// The value as JSON: integers become numbers, byte strings become strings (see
+    /// `HEX_MARKER`), lists become arrays and dictionaries become objects.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Int(n) => (*n).into(),
+            Value::Bytes(bytes) => bytes_to_json(bytes).into(),
+            Value::List(values) => values.iter().map(Value::to_json).collect(),
+            Value::Dict(dict) => dict
+                .iter()
+                .map(|(key, value)| (bytes_to_json(key), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    /// The inverse of `to_json`.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, FromJsonError> {
+        Ok(match json {
+            serde_json::Value::Number(n) => {
+                Value::Int(n.as_i64().ok_or(FromJsonError::NotAnInteger(n.clone()))?)
+            }
+            serde_json::Value::String(s) => Value::Bytes(bytes_from_json(s)?),
+            serde_json::Value::Array(values) => Value::List(
+                values
+                    .iter()
+                    .map(Value::from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            serde_json::Value::Object(dict) => Value::Dict(
+                dict.iter()
+                    .map(|(key, value)| Ok((bytes_from_json(key)?, Value::from_json(value)?)))
+                    .collect::<Result<_, FromJsonError>>()?,
+            ),
+            serde_json::Value::Bool(_) => return Err(FromJsonError::Bool),
+            serde_json::Value::Null => return Err(FromJsonError::Null),
+        })
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+fn bytes_to_json(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.starts_with(HEX_MARKER) => s.to_string(),
+        _ => format!("{HEX_MARKER}{}", hex::encode(bytes)),
+    }
+}
+
+fn bytes_from_json(s: &str) -> Result<Vec<u8>, FromJsonError> {
+    match s.strip_prefix(HEX_MARKER) {
+        Some(encoded) => hex::decode(encoded).map_err(|_| FromJsonError::BadHex(s.to_string())),
+        None => Ok(s.as_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_that_are_not_text_go_through_json_as_hex() {
+        let input = b"d4:name3:abc6:pieces2:\xff\x004:textl7:hex:abcee";
+        let value = Value::decode(input, Mode::Strict).unwrap();
+        let json = value.to_json();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "abc",
+                "pieces": "hex:ff00",
+                // Text that looks like the marker is hex-encoded too, so it comes back as text.
+                "text": ["hex:6865783a616263"],
+            })
+        );
+        assert_eq!(Value::from_json(&json).unwrap().encode(), input);
+        assert_eq!(
+            Value::from_json(&serde_json::json!("hex:zz")),
+            Err(FromJsonError::BadHex("hex:zz".into()))
+        );
+    }
+}
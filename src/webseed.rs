@@ -0,0 +1,397 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{header::RANGE, Client, StatusCode};
+use std::{sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::time;
+
+use crate::{
+    peers::WorkQueue,
+    storage::DownloadBuffer,
+    torrent::{urlencode, FileSlice, Info, Torrent},
+};
+
+/// Consecutive failed requests after which a web seed is given up on.
+const MAX_FAILURES: u32 = 5;
+/// Cap on how long an HTTP seed may ask us to stay away.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// An HTTP seed is busy and asked to be retried after the given delay.
+#[derive(Debug, Error)]
+#[error("server asked to retry in {0:?}")]
+pub struct RetryAfter(pub Duration);
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+pub fn percent_encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// GetRight-style (BEP 19, `url-list`): an HTTP server hosting the torrent's files as-is,
+    /// read with range requests.
+    GetRight,
+    /// Hoffman-style (BEP 17, `httpseeds`): a script that takes `info_hash`, `piece` and
+    /// `ranges` parameters and answers with piece data.
+    Hoffman,
+}
+
+/// A web seed, downloading pieces over HTTP.
+///
+/// It takes pieces off the same `WorkQueue` as connected peers, so it behaves like one more
+/// peer that is always unchoked.
+pub struct WebSeed {
+    url: String,
+    kind: WebSeedKind,
+    client: Client,
+    failures: u32,
+}
+
+impl WebSeed {
+    pub fn new(url: String, kind: WebSeedKind) -> Self {
+        Self {
+            url,
+            kind,
+            client: Client::new(),
+            failures: 0,
+        }
+    }
+
+    /// The URL of one file of the torrent on this server.
+    ///
+    /// For a single-file torrent a URL not ending in `/` is the file itself; otherwise the
+    /// file's path (starting with the torrent's name) is appended.
+    fn file_url(&self, info: &Info, file: &FileSlice) -> String {
+        if file.path.len() == 1 && !self.url.ends_with('/') && info.files().len() == 1 {
+            return self.url.clone();
+        }
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let path: Vec<String> = file.path.iter().map(|part| percent_encode(part)).collect();
+        url.push_str(&path.join("/"));
+        url
+    }
+
+    /// Downloads `length` bytes of the file at `url`, starting at `start`.
+    async fn fetch_range(&self, url: &str, start: usize, length: usize) -> Result<Vec<u8>> {
+        let mut response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, start + length - 1))
+            .send()
+            .await
+            .with_context(|| format!("request {url}"))?;
+        let mut skip = match response.status() {
+            StatusCode::PARTIAL_CONTENT => 0,
+            // The server ignored the range and is sending the whole file; read only as far as
+            // the end of the range rather than the whole thing.
+            StatusCode::OK => start,
+            status => bail!("{url} answered {status}"),
+        };
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let Some(chunk) = response
+                .chunk()
+                .await
+                .with_context(|| format!("read response from {url}"))?
+            else {
+                break;
+            };
+            let skipped = skip.min(chunk.len());
+            skip -= skipped;
+            let chunk = &chunk[skipped..];
+            data.extend_from_slice(&chunk[..chunk.len().min(length - data.len())]);
+        }
+        if data.len() != length {
+            bail!("{url} sent {} bytes, expected {length}", data.len());
+        }
+        Ok(data)
+    }
+
+    /// Asks a Hoffman-style seed for a whole piece.
+    async fn fetch_hoffman_piece(&self, torrent: &Torrent, piece_index: usize) -> Result<Vec<u8>> {
+        let piece_size = torrent.torrent_file.info.piece_size(piece_index);
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}info_hash={}&piece={}&ranges=0-{}",
+            self.url,
+            separator,
+            urlencode(&torrent.info_hash),
+            piece_index,
+            piece_size - 1
+        );
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("request {url}"))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .with_context(|| format!("read response from {url}"))?;
+        match status {
+            StatusCode::OK => {}
+            // The body of a 503 is the number of seconds to wait before asking again.
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let seconds = std::str::from_utf8(&body)
+                    .ok()
+                    .and_then(|body| body.trim().parse::<u64>().ok())
+                    .context("503 without a retry delay")?;
+                return Err(RetryAfter(Duration::from_secs(seconds).min(MAX_RETRY_AFTER)).into());
+            }
+            status => bail!("{url} answered {status}"),
+        }
+        if body.len() != piece_size {
+            bail!("{url} sent {} bytes, expected {piece_size}", body.len());
+        }
+        Ok(body.to_vec())
+    }
+
+    pub async fn fetch_piece(&self, torrent: &Torrent, piece_index: usize) -> Result<Vec<u8>> {
+        match self.kind {
+            WebSeedKind::GetRight => {
+                self.fetch_get_right_piece(&torrent.torrent_file.info, piece_index)
+                    .await
+            }
+            WebSeedKind::Hoffman => self.fetch_hoffman_piece(torrent, piece_index).await,
+        }
+    }
+
+    /// Fetches one piece from a GetRight-style seed; it may span several files.
+    async fn fetch_get_right_piece(&self, info: &Info, piece_index: usize) -> Result<Vec<u8>> {
+        let piece_start = piece_index * info.plength;
+        let piece_end = piece_start + info.piece_size(piece_index);
+        let mut piece = Vec::with_capacity(piece_end - piece_start);
+        for file in info.files() {
+            let file_end = file.offset + file.length;
+            if file_end <= piece_start || file.offset >= piece_end || file.length == 0 {
+                continue;
+            }
+            let start = piece_start.max(file.offset);
+            let end = piece_end.min(file_end);
+            // Servers don't have pad files; they're all zeros.
+            if file.attr.pad {
+                piece.resize(piece.len() + end - start, 0);
+                continue;
+            }
+            let url = self.file_url(info, &file);
+            piece.extend(
+                self.fetch_range(&url, start - file.offset, end - start)
+                    .await?,
+            );
+        }
+        Ok(piece)
+    }
+
+    /// Serves pieces from the work queue until it runs dry or the seed is disabled.
+    pub async fn start_downloading(
+        &mut self,
+        torrent: &Torrent,
+        work_queue: &WorkQueue,
+        buffer: Arc<tokio::sync::Mutex<DownloadBuffer>>,
+    ) {
+        let info = &torrent.torrent_file.info;
+        while let Some(piece_index) = work_queue.get_piece().await {
+            let piece = match self.fetch_piece(torrent, piece_index).await {
+                Ok(piece) => piece,
+                Err(e) if e.is::<RetryAfter>() => {
+                    work_queue.return_piece(piece_index).await;
+                    let RetryAfter(delay) = e.downcast().expect("checked above");
+                    log::info!("Web seed {} is busy, retrying in {delay:?}", self.url);
+                    time::sleep(delay).await;
+                    continue;
+                }
+                Err(e) => {
+                    work_queue.return_piece(piece_index).await;
+                    self.failures += 1;
+                    log::warn!("Web seed {} failed: {e:#}", self.url);
+                    if self.failures >= MAX_FAILURES {
+                        log::warn!("Disabling web seed {}", self.url);
+                        return;
+                    }
+                    time::sleep(Duration::from_secs(self.failures as u64)).await;
+                    continue;
+                }
+            };
+            self.failures = 0;
+
+            let disk = buffer.lock().await.disk.clone();
+            let verified = match disk.write_block(piece_index, 0, &piece).await {
+                Ok(()) => disk.verify_piece(piece_index, piece).await,
+                Err(e) => Err(e),
+            };
+            let verified = match verified {
+                Ok(verified) => verified,
+                Err(e) => {
+                    log::warn!("Could not store piece {piece_index}: {e:#}");
+                    work_queue.return_piece(piece_index).await;
+                    return;
+                }
+            };
+            if !verified {
+                // A server with the wrong file will keep sending wrong data.
+                log::warn!(
+                    "Piece {} from web seed {} failed hash check, disabling it",
+                    piece_index + 1,
+                    self.url
+                );
+                work_queue.return_piece(piece_index).await;
+                return;
+            }
+
+            buffer.lock().await.mark_have(piece_index);
+            log::info!(
+                "Successfully downloaded and verified piece {} : {} from {}",
+                piece_index + 1,
+                info.num_pieces(),
+                self.url
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves HTTP on a local port, answering every request with what `respond` returns for
+    /// its request line and headers. Connections are kept alive until the client closes them.
+    async fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let mut head = Vec::new();
+                        let mut byte = [0u8; 1];
+                        while !head.ends_with(b"\r\n\r\n") {
+                            if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            head.push(byte[0]);
+                        }
+                        let response = respond(&String::from_utf8_lossy(&head));
+                        if stream.write_all(&response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn file() -> Vec<u8> {
+        (0..=255).cycle().take(100_000).collect()
+    }
+
+    #[tokio::test]
+    async fn fetch_range_reads_partial_content() {
+        let url = serve(|head| {
+            assert!(head.to_ascii_lowercase().contains("range: bytes=1000-1999"));
+            let mut response =
+                b"HTTP/1.1 206 Partial Content\r\nContent-Length: 1000\r\n\r\n".to_vec();
+            response.extend_from_slice(&file()[1000..2000]);
+            response
+        })
+        .await;
+        let seed = WebSeed::new(url.clone(), WebSeedKind::GetRight);
+        let data = seed.fetch_range(&url, 1000, 1000).await.unwrap();
+        assert_eq!(data, file()[1000..2000]);
+    }
+
+    #[tokio::test]
+    async fn fetch_range_stops_reading_when_range_is_ignored() {
+        // Claims a far bigger file than it sends and then stalls, so reading to the end of
+        // the body would never finish.
+        let url = serve(|_| {
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 100000000\r\n\r\n".to_vec();
+            response.extend_from_slice(&file());
+            response
+        })
+        .await;
+        let seed = WebSeed::new(url.clone(), WebSeedKind::GetRight);
+        let data = time::timeout(Duration::from_secs(5), seed.fetch_range(&url, 50_000, 1000))
+            .await
+            .expect("read past the requested range")
+            .unwrap();
+        assert_eq!(data, file()[50_000..51_000]);
+    }
+
+    /// A torrent of one 40000-byte file in 16 KiB pieces, the last one 7232 bytes long.
+    fn hoffman_torrent(dir: &std::path::Path) -> Torrent {
+        let path = dir.join("data.bin");
+        std::fs::write(&path, &file()[..40_000]).unwrap();
+        let torrent_file = crate::TorrentBuilder::new(&path)
+            .piece_length(1 << 14)
+            .build()
+            .unwrap();
+        Torrent::new(torrent_file)
+    }
+
+    #[tokio::test]
+    async fn hoffman_seed_asks_for_the_whole_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = hoffman_torrent(dir.path());
+        let expected = format!(
+            "GET /seed?info_hash={}&piece=2&ranges=0-7231 ",
+            urlencode(&torrent.info_hash)
+        );
+        let url = serve(move |head| {
+            assert!(head.starts_with(&expected), "unexpected request {head}");
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 7232\r\n\r\n".to_vec();
+            response.extend_from_slice(&file()[2 << 14..40_000]);
+            response
+        })
+        .await;
+        let seed = WebSeed::new(format!("{url}/seed"), WebSeedKind::Hoffman);
+        let piece = seed.fetch_piece(&torrent, 2).await.unwrap();
+        assert_eq!(piece, file()[2 << 14..40_000]);
+    }
+
+    #[tokio::test]
+    async fn hoffman_seed_busy_means_retry_after() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = hoffman_torrent(dir.path());
+        let url = serve(|head| {
+            let seconds = if head.contains("piece=0&") {
+                "30"
+            } else {
+                "86400"
+            };
+            format!(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\n\r\n{seconds}",
+                seconds.len()
+            )
+            .into_bytes()
+        })
+        .await;
+        let seed = WebSeed::new(format!("{url}/seed?key=1"), WebSeedKind::Hoffman);
+
+        let e = seed.fetch_piece(&torrent, 0).await.unwrap_err();
+        let RetryAfter(delay) = e.downcast().unwrap();
+        assert_eq!(delay, Duration::from_secs(30));
+
+        let e = seed.fetch_piece(&torrent, 1).await.unwrap_err();
+        let RetryAfter(delay) = e.downcast().unwrap();
+        assert_eq!(delay, MAX_RETRY_AFTER);
+    }
+}